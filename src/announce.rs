@@ -0,0 +1,468 @@
+use std::{collections::HashSet, net::SocketAddr, sync::Arc};
+
+use getset::Getters;
+use tokio::{sync::Semaphore, task::JoinSet};
+
+use crate::{
+    udp_tracker, udp_tracker::UdpTrackerError, BencodeDecoder, Metainfo, TrackerError,
+    TrackerRequest, TrackerResponse,
+};
+
+#[derive(Debug, Clone)]
+pub struct AnnounceConfig {
+    /// Contacts at most this many trackers, in announce-list order
+    pub max_trackers: Option<usize>,
+    /// Returns as soon as this many distinct peers have been discovered
+    pub enough_peers: Option<usize>,
+    /// Keeps at most this many tracker requests in flight at once
+    pub max_concurrent: usize,
+    pub http_version: HttpVersion,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HttpVersion {
+    /// HTTP/1.1, or HTTP/2 when negotiated through TLS ALPN
+    #[default]
+    Negotiate,
+    /// HTTP/2 without negotiation, which also works over plain HTTP
+    Http2PriorKnowledge,
+}
+
+impl Default for AnnounceConfig {
+    fn default() -> Self {
+        Self {
+            max_trackers: None,
+            enough_peers: None,
+            max_concurrent: 10,
+            http_version: HttpVersion::default(),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AnnounceError {
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Tracker(#[from] TrackerError),
+    #[error(transparent)]
+    Udp(#[from] UdpTrackerError),
+}
+
+#[derive(Debug, Getters)]
+#[getset(get = "pub")]
+pub struct AnnounceReport {
+    /// Distinct peers, in the order the trackers returned them
+    peers: Vec<SocketAddr>,
+    /// Trackers that answered with an error or could not be reached
+    failures: Vec<(String, AnnounceError)>,
+}
+
+impl AnnounceReport {
+    pub fn into_peers(self) -> Vec<SocketAddr> {
+        self.peers
+    }
+}
+
+pub fn announce_client(http_version: HttpVersion) -> reqwest::Client {
+    let builder = reqwest::Client::builder();
+    let builder = match http_version {
+        HttpVersion::Negotiate => builder,
+        HttpVersion::Http2PriorKnowledge => builder.http2_prior_knowledge(),
+    };
+    builder.build().unwrap()
+}
+
+/// Sends a single announce to `url`, which already carries the query string.
+pub async fn announce(
+    client: &reqwest::Client,
+    url: &str,
+) -> Result<TrackerResponse, AnnounceError> {
+    let mut resp = client.get(url).send().await?;
+    // Decode as the body arrives and ignore anything after the first value
+    let mut decoder = BencodeDecoder::new();
+    while let Some(chunk) = resp.chunk().await? {
+        let values = decoder.feed(&chunk).map_err(|_| TrackerError::Malformed)?;
+        if let Some(value) = values.into_iter().next() {
+            return Ok(TrackerResponse::decode(value)?);
+        }
+    }
+    Err(TrackerError::Malformed.into())
+}
+
+/// Announces to the torrent's HTTP and UDP trackers one [tier](tiers) at a time and returns the
+/// distinct peers they know.
+///
+/// The trackers of a tier are contacted in parallel, at most `config.max_concurrent` at a time,
+/// and later tiers are only tried if every tracker of the tier before failed. Trackers that fail
+/// are skipped and reported. Trackers still pending are abandoned once `config.enough_peers`
+/// peers have been found.
+pub async fn announce_to_trackers(
+    metainfo: &Metainfo,
+    req: &TrackerRequest<'_>,
+    config: &AnnounceConfig,
+) -> AnnounceReport {
+    let client = announce_client(config.http_version);
+    let mut remaining = config.max_trackers.unwrap_or(usize::MAX);
+    let permits = Arc::new(Semaphore::new(config.max_concurrent.max(1)));
+    let mut seen = HashSet::new();
+    let mut peers = vec![];
+    let mut failures = vec![];
+    for tier in tiers(metainfo) {
+        let tier = &tier[..tier.len().min(remaining)];
+        if tier.is_empty() {
+            break;
+        }
+        remaining -= tier.len();
+        let mut announces = JoinSet::new();
+        for &tracker in tier {
+            let permits = Arc::clone(&permits);
+            let name = tracker.to_owned();
+            if tracker.starts_with("udp://") {
+                let announce = udp_tracker::announce(tracker, req);
+                announces.spawn(async move {
+                    let _permit = permits.acquire().await.unwrap();
+                    (name, announce.await.map_err(AnnounceError::from))
+                });
+                continue;
+            }
+            let client = client.clone();
+            let url = req.url_for(tracker, metainfo);
+            announces.spawn(async move {
+                let _permit = permits.acquire().await.unwrap();
+                (name, announce(&client, &url).await)
+            });
+        }
+
+        let mut answered = false;
+        while let Some(resp) = announces.join_next().await {
+            let resp = match resp {
+                Ok((_, Ok(resp))) => resp,
+                Ok((tracker, Err(e))) => {
+                    failures.push((tracker, e));
+                    continue;
+                }
+                Err(_) => continue,
+            };
+            answered = true;
+            for &peer in resp.peers() {
+                if seen.insert(peer) {
+                    peers.push(peer);
+                }
+            }
+            if config
+                .enough_peers
+                .is_some_and(|enough| peers.len() >= enough)
+            {
+                return AnnounceReport { peers, failures };
+            }
+        }
+        if answered {
+            break;
+        }
+    }
+    AnnounceReport { peers, failures }
+}
+
+/// Returns the tiers of trackers to announce to, in order: the announce list if there is one, in
+/// which case `announce` is ignored as BEP 12 asks, or else `announce` alone.
+///
+/// A tracker listed in several tiers only appears in the first of them.
+fn tiers(metainfo: &Metainfo) -> Vec<Vec<&str>> {
+    if metainfo.announce_list().is_empty() {
+        return vec![vec![metainfo.announce()]];
+    }
+    let mut seen = vec![];
+    let mut tiers = vec![];
+    for tier in metainfo.announce_list() {
+        let tier = tier
+            .iter()
+            .map(String::as_str)
+            .filter(|tracker| {
+                let new = !seen.contains(tracker);
+                seen.push(*tracker);
+                new
+            })
+            .collect::<Vec<_>>();
+        if !tier.is_empty() {
+            tiers.push(tier);
+        }
+    }
+    tiers
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        future,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::{
+        decode_bencoded_value_exact, download::tests::info_for, encode_bencoded_value,
+        udp_tracker::tests::udp_tracker, Value,
+    };
+
+    /// Counts the announces being served by every tracker sharing it.
+    #[derive(Debug, Default)]
+    struct InFlight {
+        current: AtomicUsize,
+        max: AtomicUsize,
+    }
+
+    /// Answers every announce with `peers`, or never answers if `peers` is `None`.
+    async fn tracker(peers: Option<Vec<u8>>) -> String {
+        counting_tracker(peers, Arc::default()).await
+    }
+
+    async fn counting_tracker(peers: Option<Vec<u8>>, in_flight: Arc<InFlight>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let Some(peers) = peers.clone() else {
+                    tokio::spawn(async move {
+                        let _stream = stream;
+                        future::pending::<()>().await;
+                    });
+                    continue;
+                };
+                let current = in_flight.current.fetch_add(1, Ordering::SeqCst) + 1;
+                in_flight.max.fetch_max(current, Ordering::SeqCst);
+                let mut request = vec![];
+                while !request.ends_with(b"\r\n\r\n") {
+                    request.push(stream.read_u8().await.unwrap());
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                in_flight.current.fetch_sub(1, Ordering::SeqCst);
+                let mut body = BTreeMap::new();
+                body.insert(b"interval".to_vec(), Value::Integer(60));
+                body.insert(b"peers".to_vec(), Value::Bytes(peers));
+                let body_buf = encode_bencoded_value(&Value::Dictionary(body));
+                let header = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body_buf.len()
+                );
+                stream.write_all(header.as_bytes()).await.unwrap();
+                stream.write_all(&body_buf).await.unwrap();
+            }
+        });
+        url
+    }
+
+    /// Answers a single announce over cleartext HTTP/2.
+    async fn h2_tracker(peers: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut connection = h2::server::handshake(stream).await.unwrap();
+            let (_request, mut respond) = connection.accept().await.unwrap().unwrap();
+            let mut body = BTreeMap::new();
+            body.insert(b"interval".to_vec(), Value::Integer(60));
+            body.insert(b"peers".to_vec(), Value::Bytes(peers));
+            let body = encode_bencoded_value(&Value::Dictionary(body));
+            let response = http::Response::new(());
+            let mut send = respond.send_response(response, false).unwrap();
+            send.send_data(body.into(), true).unwrap();
+            // Drive the connection until the client hangs up
+            while connection.accept().await.is_some() {}
+        });
+        url
+    }
+
+    /// Builds a torrent whose only tier lists `trackers`, the first of which is also `announce`.
+    fn metainfo_with_trackers(trackers: &[String]) -> Metainfo {
+        metainfo_with_tiers(&[trackers.to_vec()])
+    }
+
+    fn metainfo_with_tiers(tiers: &[Vec<String>]) -> Metainfo {
+        let mut map = BTreeMap::new();
+        map.insert("announce".into(), Value::Bytes(tiers[0][0].clone().into()));
+        let tiers = tiers
+            .iter()
+            .map(|tier| {
+                let tier = tier
+                    .iter()
+                    .map(|tracker| Value::Bytes(tracker.clone().into()));
+                Value::List(tier.collect())
+            })
+            .collect();
+        map.insert("announce-list".into(), Value::List(tiers));
+        map.insert("info".into(), info_for(&[0], 1));
+        Metainfo::decode(Value::Dictionary(map)).unwrap()
+    }
+
+    fn request(metainfo: &Metainfo) -> TrackerRequest<'_> {
+        TrackerRequest {
+            info_hash: metainfo.info().hash(),
+            peer_id: b"00112233445566778899",
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 1,
+            compact: true,
+            tracker_id: None,
+            corrupt: None,
+            redundant: None,
+            event: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_announce_to_trackers() {
+        let trackers = [
+            tracker(Some(vec![10, 0, 0, 1, 0x1a, 0xe1])).await,
+            tracker(Some(vec![10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe1])).await,
+            tracker(Some(vec![10, 0, 0, 3, 0x1a, 0xe1])).await,
+        ];
+        let metainfo = metainfo_with_trackers(&trackers);
+        let config = AnnounceConfig {
+            max_trackers: Some(2),
+            ..Default::default()
+        };
+        let mut peers = announce_to_trackers(&metainfo, &request(&metainfo), &config)
+            .await
+            .into_peers();
+        peers.sort();
+        assert_eq!(
+            peers,
+            &[
+                "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "10.0.0.2:6881".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_announce_list_replaces_announce() {
+        let encoded = concat!(
+            "d8:announce11:http://main",
+            "13:announce-listll8:http://a8:http://bel8:http://b8:http://ceee",
+        );
+        let mut map = decode_bencoded_value_exact(encoded.as_bytes())
+            .unwrap()
+            .into_dictionary()
+            .unwrap();
+        map.insert("info".into(), info_for(&[0], 1));
+        let metainfo = Metainfo::decode(Value::Dictionary(map)).unwrap();
+        assert_eq!(metainfo.trackers()[0], "http://main");
+        // The main tracker is left out, as is the second listing of a tracker
+        assert_eq!(
+            tiers(&metainfo),
+            &[vec!["http://a", "http://b"], vec!["http://c"]]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tiers() {
+        // Nothing listens on this port, so the first tier fails
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_tracker = format!("http://{}/announce", closed.local_addr().unwrap());
+        drop(closed);
+        let in_flight = Arc::new(InFlight::default());
+        let tiers = [
+            vec![closed_tracker.clone()],
+            vec![tracker(Some(vec![10, 0, 0, 1, 0x1a, 0xe1])).await],
+            vec![
+                counting_tracker(Some(vec![10, 0, 0, 2, 0x1a, 0xe1]), Arc::clone(&in_flight)).await,
+            ],
+        ];
+        let metainfo = metainfo_with_tiers(&tiers);
+        let report =
+            announce_to_trackers(&metainfo, &request(&metainfo), &AnnounceConfig::default()).await;
+        assert_eq!(
+            report.peers(),
+            &["10.0.0.1:6881".parse::<SocketAddr>().unwrap()]
+        );
+        assert_eq!(report.failures().len(), 1);
+        assert_eq!(report.failures()[0].0, closed_tracker);
+        // The last tier is not needed once the second answered
+        assert_eq!(in_flight.max.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_stop_once_enough_peers() {
+        let trackers = [
+            tracker(None).await,
+            tracker(Some(vec![10, 0, 0, 1, 0x1a, 0xe1])).await,
+        ];
+        let metainfo = metainfo_with_trackers(&trackers);
+        let config = AnnounceConfig {
+            enough_peers: Some(1),
+            ..Default::default()
+        };
+        let peers = tokio::time::timeout(
+            Duration::from_secs(5),
+            announce_to_trackers(&metainfo, &request(&metainfo), &config),
+        )
+        .await
+        .unwrap()
+        .into_peers();
+        assert_eq!(peers, &["10.0.0.1:6881".parse::<SocketAddr>().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_http2_prior_knowledge() {
+        let trackers = [h2_tracker(vec![10, 0, 0, 1, 0x1a, 0xe1]).await];
+        let metainfo = metainfo_with_trackers(&trackers);
+        let config = AnnounceConfig {
+            http_version: HttpVersion::Http2PriorKnowledge,
+            ..Default::default()
+        };
+        let peers = announce_to_trackers(&metainfo, &request(&metainfo), &config)
+            .await
+            .into_peers();
+        assert_eq!(peers, &["10.0.0.1:6881".parse::<SocketAddr>().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_udp_tracker() {
+        let trackers = [
+            tracker(Some(vec![10, 0, 0, 1, 0x1a, 0xe1])).await,
+            udp_tracker(vec![10, 0, 0, 2, 0x1a, 0xe1]).await,
+        ];
+        let metainfo = metainfo_with_trackers(&trackers);
+        let mut peers =
+            announce_to_trackers(&metainfo, &request(&metainfo), &AnnounceConfig::default())
+                .await
+                .into_peers();
+        peers.sort();
+        assert_eq!(
+            peers,
+            &[
+                "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "10.0.0.2:6881".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrent() {
+        let in_flight = Arc::new(InFlight::default());
+        let mut trackers = vec![];
+        for i in 1..=4 {
+            let peers = vec![10, 0, 0, i, 0x1a, 0xe1];
+            trackers.push(counting_tracker(Some(peers), Arc::clone(&in_flight)).await);
+        }
+        let metainfo = metainfo_with_trackers(&trackers);
+        let config = AnnounceConfig {
+            max_concurrent: 2,
+            ..Default::default()
+        };
+        let peers = announce_to_trackers(&metainfo, &request(&metainfo), &config)
+            .await
+            .into_peers();
+        assert_eq!(peers.len(), 4);
+        assert!(in_flight.max.load(Ordering::SeqCst) <= 2);
+    }
+}
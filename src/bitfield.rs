@@ -0,0 +1,199 @@
+use crate::{PeerMessageId, PeerMessageIn};
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum BitfieldError {
+    #[error("expected a bitfield message, got {0:?}")]
+    NotBitfield(PeerMessageId),
+    #[error("bitfield of {piece_count} pieces should be {expected} bytes, got {got}")]
+    WrongLength {
+        piece_count: u32,
+        expected: usize,
+        got: usize,
+    },
+    #[error("bitfield has spare bits set past piece {piece_count}")]
+    SpareBitsSet { piece_count: u32 },
+}
+
+/// The pieces a peer has, packed high bit first as in the `bitfield` message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    piece_count: u32,
+}
+
+impl Bitfield {
+    /// Creates a bitfield with none of the `piece_count` pieces set.
+    pub fn new(piece_count: u32) -> Self {
+        Self {
+            bytes: vec![0; (piece_count as usize + 7) / 8],
+            piece_count,
+        }
+    }
+
+    /// Takes the payload of a `bitfield` message, ignoring spare bits past `piece_count`.
+    pub fn from_bytes(bytes: &[u8], piece_count: u32) -> Self {
+        let mut bitfield = Self::new(piece_count);
+        let len = bitfield.bytes.len().min(bytes.len());
+        bitfield.bytes[..len].copy_from_slice(&bytes[..len]);
+        let spare_bits = bitfield.bytes.len() * 8 - piece_count as usize;
+        if let Some(last) = bitfield.bytes.last_mut() {
+            *last &= 0xff << spare_bits;
+        }
+        bitfield
+    }
+
+    /// Takes the payload of a `bitfield` message, which must be exactly as long as `piece_count`
+    /// needs and have its spare bits cleared.
+    pub fn try_from_bytes(bytes: &[u8], piece_count: u32) -> Result<Self, BitfieldError> {
+        let bitfield = Self::from_bytes(bytes, piece_count);
+        if bytes.len() != bitfield.bytes.len() {
+            return Err(BitfieldError::WrongLength {
+                piece_count,
+                expected: bitfield.bytes.len(),
+                got: bytes.len(),
+            });
+        }
+        if bytes != bitfield.bytes {
+            return Err(BitfieldError::SpareBitsSet { piece_count });
+        }
+        Ok(bitfield)
+    }
+
+    /// Validates the payload of a `bitfield` message for a torrent of `piece_count` pieces.
+    pub fn from_message(message: &PeerMessageIn, piece_count: u32) -> Result<Self, BitfieldError> {
+        match message.message_id() {
+            PeerMessageId::Bitfield => Self::try_from_bytes(message.payload(), piece_count),
+            got => Err(BitfieldError::NotBitfield(got)),
+        }
+    }
+
+    pub fn set(&mut self, index: u32) {
+        assert!(index < self.piece_count);
+        self.bytes[index as usize / 8] |= 0x80 >> (index % 8);
+    }
+
+    pub fn has_piece(&self, index: u32) -> bool {
+        index < self.piece_count && self.bytes[index as usize / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn piece_count(&self) -> u32 {
+        self.piece_count
+    }
+
+    pub fn count_set(&self) -> u32 {
+        self.bytes.iter().map(|byte| byte.count_ones()).sum()
+    }
+
+    /// Iterates over the indices of the pieces that are set.
+    pub fn iter_set(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.piece_count).filter(|&index| self.has_piece(index))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bitfield() {
+        let mut bitfield = Bitfield::new(10);
+        assert_eq!(bitfield.as_bytes(), &[0, 0]);
+        bitfield.set(0);
+        bitfield.set(9);
+        assert_eq!(bitfield.as_bytes(), &[0x80, 0x40]);
+        assert!(bitfield.has_piece(0));
+        assert!(!bitfield.has_piece(1));
+        assert!(bitfield.has_piece(9));
+        assert!(!bitfield.has_piece(10));
+        assert_eq!(bitfield.count_set(), 2);
+        assert_eq!(bitfield.iter_set().collect::<Vec<_>>(), &[0, 9]);
+
+        // Spare bits are cleared
+        let bitfield = Bitfield::from_bytes(&[0xff, 0xff], 10);
+        assert_eq!(bitfield.as_bytes(), &[0xff, 0xc0]);
+        assert_eq!(bitfield.count_set(), 10);
+    }
+
+    #[test]
+    fn test_boundary_pieces() {
+        for piece_count in [1, 7, 8, 9, 16] {
+            let mut bitfield = Bitfield::new(piece_count);
+            bitfield.set(0);
+            bitfield.set(piece_count - 1);
+            assert!(bitfield.has_piece(0));
+            assert!(bitfield.has_piece(piece_count - 1));
+            assert!(!bitfield.has_piece(piece_count));
+            assert!(!bitfield.has_piece(u32::MAX));
+            let expected: Vec<_> = if piece_count == 1 {
+                vec![0]
+            } else {
+                vec![0, piece_count - 1]
+            };
+            assert_eq!(bitfield.iter_set().collect::<Vec<_>>(), expected);
+            assert_eq!(
+                Bitfield::try_from_bytes(bitfield.as_bytes(), piece_count),
+                Ok(bitfield)
+            );
+        }
+    }
+
+    #[test]
+    fn test_try_from_bytes() {
+        assert_eq!(
+            Bitfield::try_from_bytes(&[0xff, 0xc0], 10)
+                .unwrap()
+                .count_set(),
+            10
+        );
+        assert_eq!(
+            Bitfield::try_from_bytes(&[0xff, 0xe0], 10),
+            Err(BitfieldError::SpareBitsSet { piece_count: 10 })
+        );
+        assert_eq!(
+            Bitfield::try_from_bytes(&[0x01], 7),
+            Err(BitfieldError::SpareBitsSet { piece_count: 7 })
+        );
+        assert_eq!(
+            Bitfield::try_from_bytes(&[0xff], 10),
+            Err(BitfieldError::WrongLength {
+                piece_count: 10,
+                expected: 2,
+                got: 1
+            })
+        );
+        assert_eq!(
+            Bitfield::try_from_bytes(&[0xff, 0xc0, 0], 10),
+            Err(BitfieldError::WrongLength {
+                piece_count: 10,
+                expected: 2,
+                got: 3
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_from_message() {
+        let mut encoded: &[u8] = &[0, 0, 0, 3, 5, 0xa0, 0x80, 0, 0, 0, 1, 2];
+        let message = PeerMessageIn::decode(&mut encoded).await.unwrap().unwrap();
+        let bitfield = Bitfield::from_message(&message, 9).unwrap();
+        assert_eq!(bitfield.iter_set().collect::<Vec<_>>(), &[0, 2, 8]);
+        assert_eq!(
+            Bitfield::from_message(&message, 8),
+            Err(BitfieldError::WrongLength {
+                piece_count: 8,
+                expected: 1,
+                got: 2
+            })
+        );
+
+        let message = PeerMessageIn::decode(&mut encoded).await.unwrap().unwrap();
+        assert_eq!(
+            Bitfield::from_message(&message, 9),
+            Err(BitfieldError::NotBitfield(PeerMessageId::Interested))
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use crate::{verify::PieceVerifier, Metainfo, Value};
+
+/// A common piece length for torrents of a few hundred megabytes
+pub const DEFAULT_PIECE_LENGTH: u32 = 1 << 18;
+
+/// Builds a torrent announcing to `announce` for the file at `path`, or for every file under it
+/// if it is a directory, hashing the content in pieces of `piece_length` bytes.
+///
+/// Files in a directory are listed in path order, and the torrent is named after the last
+/// component of `path`.
+pub fn create_torrent(path: &Path, announce: &str, piece_length: u32) -> io::Result<Metainfo> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    if piece_length == 0 {
+        return Err(invalid("piece length must not be zero".into()));
+    }
+    let name = path
+        .file_name()
+        .ok_or_else(|| invalid(format!("{} has no file name", path.display())))?
+        .to_string_lossy()
+        .into_owned();
+    let mut hasher = PieceHasher::new(piece_length);
+    let mut info = BTreeMap::new();
+    if path.is_dir() {
+        let mut files = vec![];
+        list_files(path, &mut files)?;
+        if files.is_empty() {
+            return Err(invalid(format!("{} contains no files", path.display())));
+        }
+        let mut entries = vec![];
+        for file in files {
+            let length = hasher.hash_file(&file)?;
+            let components = file
+                .strip_prefix(path)
+                .unwrap()
+                .iter()
+                .map(|component| Value::Bytes(component.to_string_lossy().as_bytes().to_vec()))
+                .collect();
+            let mut entry = BTreeMap::new();
+            entry.insert(b"length".to_vec(), Value::Integer(length as i64));
+            entry.insert(b"path".to_vec(), Value::List(components));
+            entries.push(Value::Dictionary(entry));
+        }
+        info.insert(b"files".to_vec(), Value::List(entries));
+    } else {
+        let length = hasher.hash_file(path)?;
+        info.insert(b"length".to_vec(), Value::Integer(length as i64));
+    }
+    info.insert(b"name".to_vec(), Value::Bytes(name.into_bytes()));
+    info.insert(
+        b"piece length".to_vec(),
+        Value::Integer(piece_length.into()),
+    );
+    info.insert(b"pieces".to_vec(), Value::Bytes(hasher.finish()));
+
+    let mut metainfo = BTreeMap::new();
+    metainfo.insert(
+        b"announce".to_vec(),
+        Value::Bytes(announce.as_bytes().to_vec()),
+    );
+    metainfo.insert(b"info".to_vec(), Value::Dictionary(info));
+    Metainfo::decode(Value::Dictionary(metainfo))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Collects the files under `dir`, depth first in path order.
+fn list_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() {
+            list_files(&entry, files)?;
+        } else {
+            files.push(entry);
+        }
+    }
+    Ok(())
+}
+
+/// Hashes content that runs on from one file into the next in pieces of a fixed length.
+struct PieceHasher {
+    piece_length: u64,
+    verifier: PieceVerifier,
+    /// Bytes hashed into the current piece
+    filled: u64,
+    pieces: Vec<u8>,
+}
+
+impl PieceHasher {
+    fn new(piece_length: u32) -> Self {
+        Self {
+            piece_length: piece_length.into(),
+            verifier: PieceVerifier::new(),
+            filled: 0,
+            pieces: vec![],
+        }
+    }
+
+    /// Hashes the whole file, returning its length.
+    fn hash_file(&mut self, path: &Path) -> io::Result<u64> {
+        let mut file = File::open(path)?;
+        let mut buf = vec![0; 64 * 1024];
+        let mut length = 0;
+        loop {
+            let read = file.read(&mut buf)?;
+            if read == 0 {
+                return Ok(length);
+            }
+            self.update(&buf[..read]);
+            length += read as u64;
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let take = (self.piece_length - self.filled).min(data.len() as u64) as usize;
+            self.verifier.update(&data[..take]);
+            self.filled += take as u64;
+            data = &data[take..];
+            if self.filled == self.piece_length {
+                let verifier = std::mem::take(&mut self.verifier);
+                self.pieces.extend(verifier.finalize());
+                self.filled = 0;
+            }
+        }
+    }
+
+    /// Returns the concatenated piece hashes, the last piece being short unless the content
+    /// fills it exactly.
+    fn finish(mut self) -> Vec<u8> {
+        if self.filled > 0 {
+            self.pieces.extend(self.verifier.finalize());
+        }
+        self.pieces
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FileEntry, MetainfoMode};
+
+    fn piece_hashes(content: &[u8], piece_length: usize) -> Vec<[u8; 20]> {
+        use sha1::Digest;
+        content
+            .chunks(piece_length)
+            .map(|piece| sha1::Sha1::digest(piece).into())
+            .collect()
+    }
+
+    #[test]
+    fn test_create_single_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("content.bin");
+        let content = (0..1000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        std::fs::write(&path, &content).unwrap();
+
+        let metainfo = create_torrent(&path, "http://tracker/announce", 256).unwrap();
+        assert_eq!(metainfo.announce(), "http://tracker/announce");
+        let info = metainfo.info();
+        assert_eq!(info.name(), "content.bin");
+        assert_eq!(info.length(), 1000);
+        assert_eq!(info.piece_length(), 256);
+        assert_eq!(
+            info.piece_hashes().collect::<Vec<_>>(),
+            piece_hashes(&content, 256)
+        );
+        // The info hash survives writing the torrent out
+        let decoded = Metainfo::decode_bytes(&metainfo.encode()).unwrap();
+        assert_eq!(decoded.info().hash(), info.hash());
+
+        assert_eq!(
+            create_torrent(&path, "http://tracker/announce", 0)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn test_create_multi_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("album");
+        std::fs::create_dir_all(root.join("disc 1")).unwrap();
+        std::fs::write(root.join("b.txt"), b"0123456789").unwrap();
+        std::fs::write(root.join("a.txt"), b"abc").unwrap();
+        std::fs::write(root.join("disc 1").join("c.txt"), b"xyz").unwrap();
+
+        let metainfo = create_torrent(&root, "http://tracker/announce", 4).unwrap();
+        let info = metainfo.info();
+        assert_eq!(info.name(), "album");
+        assert_eq!(
+            info.mode(),
+            &MetainfoMode::MultiFile {
+                files: vec![
+                    FileEntry {
+                        length: 3,
+                        path: vec!["a.txt".into()],
+                    },
+                    FileEntry {
+                        length: 10,
+                        path: vec!["b.txt".into()],
+                    },
+                    FileEntry {
+                        length: 3,
+                        path: vec!["disc 1".into(), "c.txt".into()],
+                    },
+                ],
+            }
+        );
+        assert_eq!(
+            info.piece_hashes().collect::<Vec<_>>(),
+            piece_hashes(b"abc0123456789xyz", 4)
+        );
+
+        std::fs::create_dir(dir.path().join("empty")).unwrap();
+        assert!(create_torrent(&dir.path().join("empty"), "http://tracker/announce", 4).is_err());
+    }
+}
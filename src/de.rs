@@ -0,0 +1,374 @@
+use std::{collections::btree_map, fmt, vec};
+
+use serde::{
+    de::{self, DeserializeOwned, DeserializeSeed, Visitor},
+    forward_to_deserialize_any, Deserialize,
+};
+
+use crate::{decode_bencoded_value_exact, BencodeError, Value};
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum DeError {
+    #[error(transparent)]
+    Bencode(#[from] BencodeError),
+    #[error("{0}")]
+    Message(String),
+}
+
+impl de::Error for DeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self::Message(msg.to_string())
+    }
+}
+
+/// Deserializes `T` from a single bencoded value spanning all of `encoded_value`.
+pub fn from_bytes<T: DeserializeOwned>(encoded_value: &[u8]) -> Result<T, DeError> {
+    from_value(decode_bencoded_value_exact(encoded_value)?)
+}
+
+/// Deserializes `T` from an already decoded value.
+///
+/// Dictionaries map to structs and maps, byte strings to `String`, `Vec<u8>` or
+/// `serde_bytes::ByteBuf`, and integers to any integer type they fit in. Unit enum variants are
+/// read from byte strings and the others from single-entry dictionaries.
+pub fn from_value<T: DeserializeOwned>(value: Value) -> Result<T, DeError> {
+    T::deserialize(Deserializer::new(value))
+}
+
+pub struct Deserializer {
+    value: Value,
+}
+
+impl Deserializer {
+    pub fn new(value: Value) -> Self {
+        Self { value }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self.value {
+            Value::Bytes(bytes) => visitor.visit_byte_buf(bytes),
+            Value::Integer(integer) => visitor.visit_i64(integer),
+            Value::List(list) => visitor.visit_seq(SeqAccess(list.into_iter())),
+            Value::Dictionary(dictionary) => visitor.visit_map(MapAccess {
+                entries: dictionary.into_iter(),
+                value: None,
+            }),
+        }
+    }
+
+    /// Bencode has no booleans, so they are written as `i0e` and `i1e`.
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self.value {
+            Value::Integer(0) => visitor.visit_bool(false),
+            Value::Integer(1) => visitor.visit_bool(true),
+            value => Self::new(value).deserialize_any(visitor),
+        }
+    }
+
+    /// Lets `Vec<u8>` be read from a byte string.
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self.value {
+            Value::Bytes(bytes) => {
+                let bytes = bytes.into_iter().map(|byte| Value::Integer(byte.into()));
+                visitor.visit_seq(SeqAccess(bytes.collect::<Vec<_>>().into_iter()))
+            }
+            value => Self::new(value).deserialize_any(visitor),
+        }
+    }
+
+    /// A value that is present is always `Some`; missing struct fields become `None`.
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        match self.value {
+            Value::Bytes(variant) => visitor.visit_enum(EnumAccess {
+                variant,
+                value: None,
+            }),
+            Value::Dictionary(dictionary) if dictionary.len() == 1 => {
+                let (variant, value) = dictionary.into_iter().next().unwrap();
+                visitor.visit_enum(EnumAccess {
+                    variant,
+                    value: Some(value),
+                })
+            }
+            _ => Err(de::Error::custom(
+                "expected a byte string or a single-entry dictionary for an enum",
+            )),
+        }
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_unit()
+    }
+
+    forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf unit
+        unit_struct tuple tuple_struct map struct identifier
+    }
+}
+
+struct SeqAccess(vec::IntoIter<Value>);
+
+impl<'de> de::SeqAccess<'de> for SeqAccess {
+    type Error = DeError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, DeError> {
+        self.0
+            .next()
+            .map(|value| seed.deserialize(Deserializer::new(value)))
+            .transpose()
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+struct MapAccess {
+    entries: btree_map::IntoIter<Vec<u8>, Value>,
+    /// The value of the key last handed out
+    value: Option<Value>,
+}
+
+impl<'de> de::MapAccess<'de> for MapAccess {
+    type Error = DeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, DeError> {
+        let Some((key, value)) = self.entries.next() else {
+            return Ok(None);
+        };
+        self.value = Some(value);
+        seed.deserialize(Deserializer::new(Value::Bytes(key)))
+            .map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, DeError> {
+        let value = self.value.take().expect("value requested before its key");
+        seed.deserialize(Deserializer::new(value))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+struct EnumAccess {
+    variant: Vec<u8>,
+    /// Absent for unit variants written as a plain byte string
+    value: Option<Value>,
+}
+
+impl<'de> de::EnumAccess<'de> for EnumAccess {
+    type Error = DeError;
+    type Variant = VariantAccess;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, VariantAccess), DeError> {
+        let variant = seed.deserialize(Deserializer::new(Value::Bytes(self.variant)))?;
+        Ok((variant, VariantAccess(self.value)))
+    }
+}
+
+struct VariantAccess(Option<Value>);
+
+impl VariantAccess {
+    fn into_value(self) -> Result<Value, DeError> {
+        self.0
+            .ok_or_else(|| de::Error::custom("expected a variant with data"))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for VariantAccess {
+    type Error = DeError;
+
+    fn unit_variant(self) -> Result<(), DeError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, DeError> {
+        seed.deserialize(Deserializer::new(self.into_value()?))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, DeError> {
+        de::Deserializer::deserialize_seq(Deserializer::new(self.into_value()?), visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        de::Deserializer::deserialize_map(Deserializer::new(self.into_value()?), visitor)
+    }
+}
+
+/// Reads a value back from any self-describing format, so that fields can be left undecoded
+/// when deserializing with [`from_value`].
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a bencode value")
+    }
+
+    fn visit_i64<E: de::Error>(self, integer: i64) -> Result<Value, E> {
+        Ok(Value::Integer(integer))
+    }
+
+    fn visit_u64<E: de::Error>(self, integer: u64) -> Result<Value, E> {
+        i64::try_from(integer)
+            .map(Value::Integer)
+            .map_err(|_| E::custom("integer out of range"))
+    }
+
+    fn visit_str<E: de::Error>(self, string: &str) -> Result<Value, E> {
+        Ok(Value::Bytes(string.as_bytes().to_vec()))
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Value, E> {
+        Ok(Value::Bytes(bytes.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Value, E> {
+        Ok(Value::Bytes(bytes))
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut list = vec![];
+        while let Some(element) = seq.next_element()? {
+            list.push(element);
+        }
+        Ok(Value::List(list))
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut dictionary = std::collections::BTreeMap::new();
+        while let Some((key, value)) = map.next_entry::<serde_bytes::ByteBuf, _>()? {
+            dictionary.insert(key.into_vec(), value);
+        }
+        Ok(Value::Dictionary(dictionary))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde_bytes::ByteBuf;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct Torrent {
+        announce: String,
+        #[serde(rename = "created by")]
+        created_by: Option<String>,
+        info: Info,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Info {
+        length: u64,
+        name: String,
+        #[serde(rename = "piece length")]
+        piece_length: u32,
+        pieces: ByteBuf,
+    }
+
+    #[test]
+    fn test_sample_torrent() {
+        let buf = std::fs::read("sample.torrent").unwrap();
+        let torrent: Torrent = from_bytes(&buf).unwrap();
+        let metainfo = crate::Metainfo::decode_bytes(&buf).unwrap();
+        assert_eq!(&torrent.announce, metainfo.announce());
+        assert!(torrent.created_by.is_some());
+        assert_eq!(torrent.info.length, metainfo.info().length());
+        assert_eq!(&torrent.info.name, metainfo.info().name());
+        assert_eq!(torrent.info.piece_length, metainfo.info().piece_length());
+        assert_eq!(
+            torrent.info.pieces.chunks(20).collect::<Vec<_>>(),
+            metainfo.info().piece_hashes().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_types() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        enum Kind {
+            Plain,
+            Sized(u8),
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Sample {
+            bytes: Vec<u8>,
+            flag: bool,
+            kinds: Vec<Kind>,
+            map: BTreeMap<String, i64>,
+            missing: Option<u32>,
+            raw: Value,
+        }
+
+        let sample: Sample = from_bytes(
+            b"d5:bytes2:ab4:flagi1e5:kindsl5:Plaind5:Sizedi3eee3:mapd1:ai-1ee3:rawli1eee",
+        )
+        .unwrap();
+        assert_eq!(
+            sample,
+            Sample {
+                bytes: b"ab".to_vec(),
+                flag: true,
+                kinds: vec![Kind::Plain, Kind::Sized(3)],
+                map: BTreeMap::from([("a".to_owned(), -1)]),
+                missing: None,
+                raw: Value::List(vec![Value::Integer(1)]),
+            }
+        );
+
+        assert!(from_bytes::<u8>(b"i256e").is_err());
+        assert!(from_bytes::<String>(b"1:\xff").is_err());
+        assert_eq!(
+            from_bytes::<u32>(b"i1eGARBAGE"),
+            Err(DeError::Bencode(BencodeError::TrailingGarbage {
+                consumed: 3,
+                total: 10
+            }))
+        );
+    }
+}
@@ -0,0 +1,522 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::{net::UdpSocket, time::Instant};
+
+use crate::{
+    decode_bencoded_value_exact, encode_bencoded_value, parse_compact_peers,
+    udp_tracker::random_u64, BencodeError, InfoHash, Value,
+};
+
+/// How long a node may take to answer a query
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// How many queries are kept in flight at once
+const PARALLEL_QUERIES: usize = 8;
+/// How many nodes a lookup queries before giving up
+const MAX_QUERIES: usize = 64;
+/// A lookup stops once it has collected this many peers
+const WANTED_PEERS: usize = 50;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DhtError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Bencode(#[from] BencodeError),
+    #[error("malformed KRPC message")]
+    Malformed,
+}
+
+/// A DHT node as found in the compact `nodes` of a response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Node {
+    pub id: [u8; 20],
+    pub addr: SocketAddr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Ping,
+    FindNode { target: [u8; 20] },
+    GetPeers { info_hash: InfoHash },
+}
+
+/// The arguments of a response; which are set depends on the query it answers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub id: [u8; 20],
+    /// Closer nodes, of which only IPv4 ones are encoded
+    pub nodes: Vec<Node>,
+    /// Peers of the torrent asked for with `get_peers`
+    pub values: Vec<SocketAddr>,
+    /// Lets us announce to the node after `get_peers`
+    pub token: Option<Vec<u8>>,
+}
+
+/// A KRPC message (BEP 5), exchanged as a bencoded dictionary over UDP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KrpcMessage {
+    Query {
+        transaction_id: Vec<u8>,
+        node_id: [u8; 20],
+        query: Query,
+    },
+    Response {
+        transaction_id: Vec<u8>,
+        response: Response,
+    },
+    Error {
+        transaction_id: Vec<u8>,
+        code: i64,
+        message: String,
+    },
+}
+
+impl KrpcMessage {
+    pub fn transaction_id(&self) -> &[u8] {
+        match self {
+            Self::Query { transaction_id, .. }
+            | Self::Response { transaction_id, .. }
+            | Self::Error { transaction_id, .. } => transaction_id,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let bytes = |bytes: &[u8]| Value::Bytes(bytes.to_vec());
+        let mut message = BTreeMap::new();
+        message.insert(b"t".to_vec(), bytes(self.transaction_id()));
+        match self {
+            Self::Query { node_id, query, .. } => {
+                let mut args = BTreeMap::new();
+                args.insert(b"id".to_vec(), bytes(node_id));
+                let method: &[u8] = match query {
+                    Query::Ping => b"ping",
+                    Query::FindNode { target } => {
+                        args.insert(b"target".to_vec(), bytes(target));
+                        b"find_node"
+                    }
+                    Query::GetPeers { info_hash } => {
+                        args.insert(b"info_hash".to_vec(), bytes(info_hash.as_bytes()));
+                        b"get_peers"
+                    }
+                };
+                message.insert(b"y".to_vec(), bytes(b"q"));
+                message.insert(b"q".to_vec(), bytes(method));
+                message.insert(b"a".to_vec(), Value::Dictionary(args));
+            }
+            Self::Response { response, .. } => {
+                let mut args = BTreeMap::new();
+                args.insert(b"id".to_vec(), bytes(&response.id));
+                if !response.nodes.is_empty() {
+                    let mut nodes = vec![];
+                    for node in &response.nodes {
+                        if let Some(addr) = compact_addr(node.addr) {
+                            nodes.extend(node.id);
+                            nodes.extend(addr);
+                        }
+                    }
+                    args.insert(b"nodes".to_vec(), Value::Bytes(nodes));
+                }
+                if !response.values.is_empty() {
+                    let values = response
+                        .values
+                        .iter()
+                        .filter_map(|&peer| compact_addr(peer))
+                        .map(|peer| bytes(&peer))
+                        .collect();
+                    args.insert(b"values".to_vec(), Value::List(values));
+                }
+                if let Some(token) = &response.token {
+                    args.insert(b"token".to_vec(), bytes(token));
+                }
+                message.insert(b"y".to_vec(), bytes(b"r"));
+                message.insert(b"r".to_vec(), Value::Dictionary(args));
+            }
+            Self::Error {
+                code,
+                message: text,
+                ..
+            } => {
+                let error = vec![Value::Integer(*code), bytes(text.as_bytes())];
+                message.insert(b"y".to_vec(), bytes(b"e"));
+                message.insert(b"e".to_vec(), Value::List(error));
+            }
+        }
+        encode_bencoded_value(&Value::Dictionary(message))
+    }
+
+    /// Decodes a message, ignoring keys it does not know about.
+    ///
+    /// Malformed `nodes` and `values` entries are skipped rather than failing the message.
+    pub fn decode(buf: &[u8]) -> Result<Self, DhtError> {
+        let mut message = decode_bencoded_value_exact(buf)?
+            .into_dictionary()
+            .ok_or(DhtError::Malformed)?;
+        let mut take_bytes = |key: &[u8]| {
+            message
+                .remove(key)
+                .and_then(|value| value.into_bytes())
+                .ok_or(DhtError::Malformed)
+        };
+        let transaction_id = take_bytes(b"t")?;
+        let kind = take_bytes(b"y")?;
+        match kind.as_slice() {
+            b"q" => {
+                let method = take_bytes(b"q")?;
+                let mut args = message
+                    .remove(b"a".as_slice())
+                    .and_then(|args| args.into_dictionary())
+                    .ok_or(DhtError::Malformed)?;
+                let mut take_id = |key: &[u8]| {
+                    args.remove(key)
+                        .and_then(|value| value.into_bytes())
+                        .and_then(|id| <[u8; 20]>::try_from(id).ok())
+                        .ok_or(DhtError::Malformed)
+                };
+                let node_id = take_id(b"id")?;
+                let query = match method.as_slice() {
+                    b"ping" => Query::Ping,
+                    b"find_node" => Query::FindNode {
+                        target: take_id(b"target")?,
+                    },
+                    b"get_peers" => Query::GetPeers {
+                        info_hash: InfoHash::new(take_id(b"info_hash")?),
+                    },
+                    _ => return Err(DhtError::Malformed),
+                };
+                Ok(Self::Query {
+                    transaction_id,
+                    node_id,
+                    query,
+                })
+            }
+            b"r" => {
+                let mut args = message
+                    .remove(b"r".as_slice())
+                    .and_then(|args| args.into_dictionary())
+                    .ok_or(DhtError::Malformed)?;
+                let id = args
+                    .remove(b"id".as_slice())
+                    .and_then(|id| id.into_bytes())
+                    .and_then(|id| <[u8; 20]>::try_from(id).ok())
+                    .ok_or(DhtError::Malformed)?;
+                let nodes = args
+                    .remove(b"nodes".as_slice())
+                    .and_then(|nodes| nodes.into_bytes())
+                    .unwrap_or_default()
+                    .chunks_exact(26)
+                    .map(|node| Node {
+                        id: node[..20].try_into().unwrap(),
+                        addr: parse_compact_peers(&node[20..]).unwrap()[0],
+                    })
+                    .collect();
+                let values = args
+                    .remove(b"values".as_slice())
+                    .and_then(|values| values.into_list())
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|peer| peer.into_bytes())
+                    .filter_map(|peer| parse_compact_peers(&peer).ok())
+                    .flatten()
+                    .collect();
+                let token = args
+                    .remove(b"token".as_slice())
+                    .and_then(|token| token.into_bytes());
+                Ok(Self::Response {
+                    transaction_id,
+                    response: Response {
+                        id,
+                        nodes,
+                        values,
+                        token,
+                    },
+                })
+            }
+            b"e" => {
+                let mut error = message
+                    .remove(b"e".as_slice())
+                    .and_then(|error| error.into_list())
+                    .filter(|error| error.len() == 2)
+                    .ok_or(DhtError::Malformed)?
+                    .into_iter();
+                let code = error
+                    .next()
+                    .and_then(|code| code.into_integer())
+                    .ok_or(DhtError::Malformed)?;
+                let message = error
+                    .next()
+                    .and_then(|message| message.into_bytes())
+                    .ok_or(DhtError::Malformed)?;
+                Ok(Self::Error {
+                    transaction_id,
+                    code,
+                    message: String::from_utf8_lossy(&message).into_owned(),
+                })
+            }
+            _ => Err(DhtError::Malformed),
+        }
+    }
+}
+
+/// XOR distance between two ids, which compares as a big-endian number.
+pub fn distance(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    let mut distance = [0; 20];
+    for (distance, (a, b)) in distance.iter_mut().zip(a.iter().zip(b)) {
+        *distance = a ^ b;
+    }
+    distance
+}
+
+/// Looks up peers of the torrent with `get_peers` queries, starting at `bootstrap` and moving
+/// on to the nodes closest to the info hash that the answers name.
+///
+/// Nodes that time out or answer with an error are skipped. The lookup ends once enough peers
+/// have been collected, no unqueried nodes are left, or too many nodes have been queried.
+pub async fn find_peers(
+    info_hash: &InfoHash,
+    bootstrap: SocketAddr,
+) -> Result<Vec<SocketAddr>, DhtError> {
+    let local: SocketAddr = match bootstrap {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0_u16; 8], 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    let mut node_id = [0; 20];
+    for chunk in node_id.chunks_mut(8) {
+        chunk.copy_from_slice(&random_u64().to_be_bytes()[..chunk.len()]);
+    }
+    let target = info_hash.as_bytes();
+
+    // The bootstrap node's id is unknown, so it sorts first
+    let mut candidates = BTreeMap::from([([0; 20], bootstrap)]);
+    let mut seen = HashSet::from([bootstrap]);
+    let mut in_flight: HashMap<Vec<u8>, (SocketAddr, Instant)> = HashMap::new();
+    let mut queried = 0;
+    let mut next_transaction_id = random_u64() as u16;
+    let mut peers = vec![];
+    let mut buf = vec![0; 64 * 1024];
+    while peers.len() < WANTED_PEERS {
+        while in_flight.len() < PARALLEL_QUERIES && queried < MAX_QUERIES {
+            let Some((_, addr)) = candidates.pop_first() else {
+                break;
+            };
+            let transaction_id = next_transaction_id.to_be_bytes().to_vec();
+            next_transaction_id = next_transaction_id.wrapping_add(1);
+            let query = KrpcMessage::Query {
+                transaction_id: transaction_id.clone(),
+                node_id,
+                query: Query::GetPeers {
+                    info_hash: *info_hash,
+                },
+            };
+            queried += 1;
+            // Unreachable nodes are as good as ones that never answer
+            if socket.send_to(&query.encode(), addr).await.is_ok() {
+                in_flight.insert(transaction_id, (addr, Instant::now() + QUERY_TIMEOUT));
+            }
+        }
+        let Some(deadline) = in_flight.values().map(|&(_, deadline)| deadline).min() else {
+            break;
+        };
+        let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await
+        else {
+            let now = Instant::now();
+            in_flight.retain(|_, &mut (_, deadline)| deadline > now);
+            continue;
+        };
+        let (read, from) = received?;
+        let Ok(message) = KrpcMessage::decode(&buf[..read]) else {
+            continue;
+        };
+        match in_flight.get(message.transaction_id()) {
+            Some(&(addr, _)) if addr == from => {
+                in_flight.remove(message.transaction_id());
+            }
+            _ => continue,
+        }
+        let KrpcMessage::Response { response, .. } = message else {
+            continue;
+        };
+        for peer in response.values {
+            if !peers.contains(&peer) {
+                peers.push(peer);
+            }
+        }
+        for node in response.nodes {
+            if !is_routable(node.addr.ip()) || !seen.insert(node.addr) {
+                continue;
+            }
+            candidates.insert(distance(&node.id, target), node.addr);
+        }
+    }
+    Ok(peers)
+}
+
+fn compact_addr(addr: SocketAddr) -> Option<[u8; 6]> {
+    let SocketAddr::V4(addr) = addr else {
+        return None;
+    };
+    let mut compact = [0; 6];
+    compact[..4].copy_from_slice(&addr.ip().octets());
+    compact[4..].copy_from_slice(&addr.port().to_be_bytes());
+    Some(compact)
+}
+
+/// Nodes sometimes hand out placeholder addresses that cannot be queried.
+fn is_routable(ip: IpAddr) -> bool {
+    !ip.is_unspecified() && !ip.is_multicast()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO_HASH: InfoHash = InfoHash::new([0xab; 20]);
+
+    fn response(transaction_id: Vec<u8>, nodes: Vec<Node>, values: Vec<SocketAddr>) -> Vec<u8> {
+        KrpcMessage::Response {
+            transaction_id,
+            response: Response {
+                id: [0; 20],
+                nodes,
+                values,
+                token: Some(b"token".to_vec()),
+            },
+        }
+        .encode()
+    }
+
+    /// Answers one `get_peers` query for [`INFO_HASH`] with `nodes` and `values`.
+    async fn dht_node(socket: UdpSocket, nodes: Vec<Node>, values: Vec<SocketAddr>) {
+        let mut buf = [0; 1024];
+        let (read, client) = socket.recv_from(&mut buf).await.unwrap();
+        let KrpcMessage::Query {
+            transaction_id,
+            query,
+            ..
+        } = KrpcMessage::decode(&buf[..read]).unwrap()
+        else {
+            panic!("expected a query");
+        };
+        assert_eq!(
+            query,
+            Query::GetPeers {
+                info_hash: INFO_HASH
+            }
+        );
+        let resp = response(transaction_id, nodes, values);
+        socket.send_to(&resp, client).await.unwrap();
+    }
+
+    #[test]
+    fn test_codec() {
+        let messages = [
+            KrpcMessage::Query {
+                transaction_id: b"aa".to_vec(),
+                node_id: [1; 20],
+                query: Query::Ping,
+            },
+            KrpcMessage::Query {
+                transaction_id: b"ab".to_vec(),
+                node_id: [1; 20],
+                query: Query::FindNode { target: [2; 20] },
+            },
+            KrpcMessage::Query {
+                transaction_id: b"ac".to_vec(),
+                node_id: [1; 20],
+                query: Query::GetPeers {
+                    info_hash: INFO_HASH,
+                },
+            },
+            KrpcMessage::Response {
+                transaction_id: b"ac".to_vec(),
+                response: Response {
+                    id: [3; 20],
+                    nodes: vec![Node {
+                        id: [4; 20],
+                        addr: "10.0.0.1:6881".parse().unwrap(),
+                    }],
+                    values: vec!["10.0.0.2:51413".parse().unwrap()],
+                    token: Some(b"tok".to_vec()),
+                },
+            },
+            KrpcMessage::Error {
+                transaction_id: b"ad".to_vec(),
+                code: 201,
+                message: "A Generic Error Ocurred".into(),
+            },
+        ];
+        for message in messages {
+            assert_eq!(KrpcMessage::decode(&message.encode()).unwrap(), message);
+        }
+
+        // The ping example from BEP 5
+        let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        assert_eq!(
+            KrpcMessage::decode(ping).unwrap(),
+            KrpcMessage::Query {
+                transaction_id: b"aa".to_vec(),
+                node_id: *b"abcdefghij0123456789",
+                query: Query::Ping,
+            }
+        );
+        assert_eq!(
+            KrpcMessage::Query {
+                transaction_id: b"aa".to_vec(),
+                node_id: *b"abcdefghij0123456789",
+                query: Query::Ping,
+            }
+            .encode(),
+            ping
+        );
+        assert!(matches!(
+            KrpcMessage::decode(b"d1:t2:aa1:y1:xe"),
+            Err(DhtError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn test_distance() {
+        let mut a = [0; 20];
+        a[19] = 0b0110;
+        let mut b = [0; 20];
+        b[19] = 0b0011;
+        let mut expected = [0; 20];
+        expected[19] = 0b0101;
+        assert_eq!(distance(&a, &b), expected);
+        assert_eq!(distance(&a, &a), [0; 20]);
+    }
+
+    #[tokio::test]
+    async fn test_find_peers() {
+        let bootstrap = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let bootstrap_addr = bootstrap.local_addr().unwrap();
+        let close = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let far = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peers: Vec<SocketAddr> = vec![
+            "10.0.0.1:6881".parse().unwrap(),
+            "10.0.0.2:6881".parse().unwrap(),
+        ];
+        let nodes = vec![
+            Node {
+                id: [0; 20],
+                addr: far.local_addr().unwrap(),
+            },
+            Node {
+                id: [0xaa; 20],
+                addr: close.local_addr().unwrap(),
+            },
+        ];
+        tokio::spawn(dht_node(bootstrap, nodes, vec![]));
+        tokio::spawn(dht_node(close, vec![], peers.clone()));
+        tokio::spawn(dht_node(far, vec![], vec![peers[1]]));
+
+        let mut found = find_peers(&INFO_HASH, bootstrap_addr).await.unwrap();
+        found.sort();
+        assert_eq!(found, peers);
+    }
+}
@@ -0,0 +1,1360 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    future, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use getset::{CopyGetters, Getters};
+use tokio::{
+    io::{AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+    sync::{broadcast, mpsc, Notify},
+    task::JoinSet,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    bitfield::Bitfield,
+    files::FileMapper,
+    peer::{ConnectOptions, PeerConnection, PeerError},
+    pool::PeerPool,
+    progress::Progress,
+    rate::RateLimiter,
+    selector::PieceSelector,
+    verify::scan_existing,
+    Metainfo, MetainfoInfo, MetainfoMode,
+};
+
+#[derive(Debug, Clone)]
+pub struct DownloadConfig {
+    pub concurrency: usize,
+    /// Download into `<output>.part` and only rename it to the output path once every piece
+    /// has been verified
+    pub use_part_file: bool,
+    /// Stops requesting pieces once this many bytes have been downloaded across all peers
+    pub max_total_bytes: Option<u64>,
+    pub connect_options: ConnectOptions,
+    /// Requests pieces strictly in index order instead of preferring suggested and allowed-fast
+    /// pieces
+    pub sequential: bool,
+    /// Requests the pieces held by the fewest connected peers first, unless `sequential` is set
+    pub rarest_first: bool,
+    /// Once every piece has been requested, lets idle peers also request the pieces still in
+    /// flight elsewhere; whichever copy arrives first wins and the others are cancelled
+    pub endgame: bool,
+    /// Caps the combined download rate of all peers in bytes per second; zero means unlimited
+    pub max_download_rate: Option<u64>,
+    /// Keeps the verified pieces of an earlier, interrupted download to the same path and only
+    /// fetches the rest
+    pub resume: bool,
+    /// Counts the verified pieces as they are written, starting with those kept by `resume`
+    pub progress: Option<Arc<Progress>>,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: 5,
+            use_part_file: true,
+            max_total_bytes: None,
+            connect_options: ConnectOptions::default(),
+            sequential: false,
+            rarest_first: false,
+            endgame: true,
+            max_download_rate: None,
+            resume: false,
+            progress: None,
+        }
+    }
+}
+
+#[derive(Debug, Getters, CopyGetters)]
+pub struct DownloadReport {
+    #[getset(get = "pub")]
+    completed_pieces: Vec<u32>,
+    #[getset(get_copy = "pub")]
+    total_pieces: u32,
+    #[getset(get_copy = "pub")]
+    cancelled: bool,
+    #[getset(get_copy = "pub")]
+    budget_exhausted: bool,
+}
+
+impl DownloadReport {
+    pub fn is_complete(&self) -> bool {
+        self.completed_pieces.len() == self.total_pieces as usize
+    }
+}
+
+/// Downloads every piece from up to `config.concurrency` peers at a time into `output_file_path`.
+///
+/// Duplicate peers are dialed once, and a peer that fails is replaced by one not tried yet.
+///
+/// Cancelling `cancel` stops all peer tasks and returns the pieces completed so far; the output
+/// file is flushed and closed before this function returns in either case.
+/// An incomplete download is left in the `.part` file if `config.use_part_file` is set, where
+/// `config.resume` picks it up again.
+///
+/// The files of a multi-file torrent are written into the directory at `output_file_path`
+/// directly; part files and resuming only apply to single-file torrents.
+///
+/// Once `config.max_total_bytes` would be exceeded no further pieces are requested, and the
+/// pieces already in flight are written before returning.
+pub async fn download_all(
+    metainfo: &Metainfo,
+    peers: &[SocketAddr],
+    peer_id: &[u8; 20],
+    config: &DownloadConfig,
+    output_file_path: impl AsRef<Path>,
+    cancel: CancellationToken,
+) -> io::Result<DownloadReport> {
+    let output_file_path = output_file_path.as_ref();
+    if let MetainfoMode::MultiFile { files } = metainfo.info().mode() {
+        let output = Output::Files(FileMapper::create(output_file_path, files).await?);
+        return download_to(metainfo, peers, peer_id, config, output, None, cancel).await;
+    }
+    if !config.use_part_file {
+        let (output, existing) = Output::file(output_file_path, metainfo.info(), config).await?;
+        return download_to(metainfo, peers, peer_id, config, output, existing, cancel).await;
+    }
+
+    let part_file_path = part_file_path(output_file_path);
+    let (output, existing) = Output::file(&part_file_path, metainfo.info(), config).await?;
+    let report = match download_to(metainfo, peers, peer_id, config, output, existing, cancel).await
+    {
+        Ok(report) => report,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&part_file_path).await;
+            return Err(e);
+        }
+    };
+    if report.is_complete() {
+        tokio::fs::rename(&part_file_path, output_file_path).await?;
+    }
+    Ok(report)
+}
+
+/// Downloads every piece like [`download_all`] but streams the content to `writer` in order.
+///
+/// Pieces are requested sequentially; any that arrive ahead of the next one to write are held in
+/// memory until the gap is filled. On cancellation `writer` holds the longest complete prefix.
+pub async fn download_to_writer(
+    metainfo: &Metainfo,
+    peers: &[SocketAddr],
+    peer_id: &[u8; 20],
+    config: &DownloadConfig,
+    writer: &mut (dyn AsyncWrite + Unpin),
+    cancel: CancellationToken,
+) -> io::Result<DownloadReport> {
+    let config = DownloadConfig {
+        sequential: true,
+        ..config.clone()
+    };
+    let output = Output::Stream {
+        writer,
+        next_piece: 0,
+        pending: BTreeMap::new(),
+    };
+    download_to(metainfo, peers, peer_id, &config, output, None, cancel).await
+}
+
+/// Returns `<path>.part`.
+pub fn part_file_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_owned();
+    file_name.push(".part");
+    path.with_file_name(file_name)
+}
+
+/// Creates or truncates `path` for writing, creating its parent directories as needed.
+pub async fn create_output_file(path: &Path) -> io::Result<tokio::fs::File> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::File::options()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .await
+}
+
+/// Opens `path` for writing without truncating it, creating it and its parent directories as
+/// needed.
+async fn open_output_file(path: &Path) -> io::Result<tokio::fs::File> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::File::options()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .await
+}
+
+async fn download_to(
+    metainfo: &Metainfo,
+    peers: &[SocketAddr],
+    peer_id: &[u8; 20],
+    config: &DownloadConfig,
+    mut output: Output<'_>,
+    existing: Option<Bitfield>,
+    cancel: CancellationToken,
+) -> io::Result<DownloadReport> {
+    let info = Arc::new(metainfo.info().clone());
+    let total_pieces = u32::try_from(info.piece_hashes().count()).unwrap();
+    let existing = existing.unwrap_or_else(|| Bitfield::new(total_pieces));
+    let queue = (0..total_pieces).filter(|&index| !existing.has_piece(index));
+    let queue = Arc::new(Mutex::new(queue.collect::<VecDeque<_>>()));
+
+    let budget = Arc::new(ByteBudget {
+        max: config.max_total_bytes,
+        used: AtomicU64::new(0),
+        exhausted: AtomicBool::new(false),
+    });
+
+    let mut pool = PeerPool::new();
+    pool.add_peers(peers.iter().copied());
+
+    // Stops the workers once every piece is in without cancelling the caller's token
+    let workers_cancel = cancel.child_token();
+    let (piece_tx, mut piece_rx) = mpsc::channel(config.concurrency.max(1));
+    // Tells the workers which pieces are done so they can cancel redundant requests
+    let (completed_tx, _) = broadcast::channel(total_pieces.max(1) as usize);
+    let worker = Worker {
+        info: Arc::clone(&info),
+        peer_id: *peer_id,
+        connect_options: ConnectOptions {
+            download_limiter: config
+                .max_download_rate
+                .map(|rate| Arc::new(RateLimiter::new(rate)))
+                .or_else(|| config.connect_options.download_limiter.clone()),
+            piece_count: Some(total_pieces),
+            ..config.connect_options.clone()
+        },
+        queue: Arc::clone(&queue),
+        piece_tx,
+        completed_tx: completed_tx.clone(),
+        budget: Arc::clone(&budget),
+        sequential: config.sequential,
+        selector: (config.rarest_first && !config.sequential).then(|| {
+            let mut selector = PieceSelector::new(total_pieces);
+            existing.iter_set().for_each(|index| selector.take(index));
+            Arc::new(Mutex::new(selector))
+        }),
+        requeued: Arc::new(Notify::new()),
+        in_flight: Arc::new(Mutex::new(BTreeMap::new())),
+        endgame: config.endgame,
+        cancel: workers_cancel.clone(),
+    };
+    let mut workers = JoinSet::new();
+
+    let mut completed_pieces = existing.iter_set().collect::<Vec<_>>();
+    let mut completed = (0..total_pieces)
+        .map(|index| existing.has_piece(index))
+        .collect::<Vec<_>>();
+    if let Some(progress) = &config.progress {
+        for &index in &completed_pieces {
+            progress.record_piece(info.piece_len(index));
+        }
+    }
+    let mut cancelled = false;
+    while completed_pieces.len() < total_pieces as usize {
+        // Replace the workers that gave up with peers we have not dialed yet
+        while workers.len() < config.concurrency
+            && !queue.lock().unwrap().is_empty()
+            && !budget.is_exhausted()
+        {
+            let Some(peer) = pool.next_to_dial() else {
+                break;
+            };
+            let haves = pool.subscribe_haves(peer);
+            workers.spawn(worker.clone().run(peer, haves));
+        }
+        if workers.is_empty() {
+            break;
+        }
+
+        let (piece_index, piece) = tokio::select! {
+            // Cancelling also stops the workers, so check it before they exit
+            biased;
+            _ = cancel.cancelled() => {
+                cancelled = true;
+                break;
+            }
+            Some(piece) = piece_rx.recv() => piece,
+            Some(worker) = workers.join_next() => {
+                match worker {
+                    Ok((
+                        peer,
+                        Err(
+                            PeerError::ConnectionClosed
+                            | PeerError::UnchokeTimeout(_)
+                            | PeerError::Timeout(_),
+                        ),
+                    )) => pool.mark_dead(peer),
+                    Ok((peer, _)) => pool.mark_disconnected(peer),
+                    // A worker that panicked gave its piece back as it unwound
+                    Err(_) => {}
+                }
+                continue;
+            }
+        };
+        if completed[piece_index as usize] {
+            continue;
+        }
+        output
+            .write_piece(piece_index, info.piece_length(), piece)
+            .await?;
+        completed[piece_index as usize] = true;
+        completed_pieces.push(piece_index);
+        if let Some(progress) = &config.progress {
+            progress.record_piece(info.piece_len(piece_index));
+        }
+        let _ = completed_tx.send(piece_index);
+        pool.broadcast_have(piece_index);
+    }
+
+    workers_cancel.cancel();
+    while workers.join_next().await.is_some() {}
+    output.finish().await?;
+
+    completed_pieces.sort_unstable();
+    Ok(DownloadReport {
+        completed_pieces,
+        total_pieces,
+        cancelled,
+        budget_exhausted: budget.is_exhausted(),
+    })
+}
+
+#[derive(Debug, Clone)]
+struct Worker {
+    info: Arc<MetainfoInfo>,
+    peer_id: [u8; 20],
+    connect_options: ConnectOptions,
+    queue: Arc<Mutex<VecDeque<u32>>>,
+    piece_tx: mpsc::Sender<(u32, Vec<u8>)>,
+    completed_tx: broadcast::Sender<u32>,
+    budget: Arc<ByteBudget>,
+    sequential: bool,
+    /// Shared by all workers when downloading rarest first; pieces taken from `queue` are also
+    /// taken here
+    selector: Option<Arc<Mutex<PieceSelector>>>,
+    /// Wakes the workers waiting on an empty queue when a piece is put back
+    requeued: Arc<Notify>,
+    /// How many workers are downloading each piece taken from `queue`
+    in_flight: Arc<Mutex<BTreeMap<u32, usize>>>,
+    endgame: bool,
+    cancel: CancellationToken,
+}
+
+impl Worker {
+    /// Downloads pieces from `peer` until every piece is in, the budget runs out, the peer fails or
+    /// the worker is cancelled, and returns the peer with the reason it stopped.
+    async fn run(
+        self,
+        peer: SocketAddr,
+        haves: mpsc::UnboundedReceiver<u32>,
+    ) -> (SocketAddr, Result<(), PeerError>) {
+        let res = tokio::select! {
+            // Lets a piece completed elsewhere be cancelled with the peer before stopping
+            biased;
+            res = self.download_from_peer(peer, haves) => res,
+            _ = self.cancel.cancelled() => Ok(()),
+        };
+        (peer, res)
+    }
+
+    /// Pieces completed by any worker are announced to `peer` with `Have` between pieces.
+    async fn download_from_peer(
+        &self,
+        peer: SocketAddr,
+        mut haves: mpsc::UnboundedReceiver<u32>,
+    ) -> Result<(), PeerError> {
+        let info = &self.info;
+        let mut conn =
+            PeerConnection::connect_with(peer, info.hash(), &self.peer_id, &self.connect_options)
+                .await?;
+        conn.declare_interest().await?;
+        let mut availability = self.selector.clone().map(|selector| Availability {
+            selector,
+            bitfield: Bitfield::new(0),
+        });
+
+        let mut completed_rx = self.completed_tx.subscribe();
+        loop {
+            while let Ok(index) = haves.try_recv() {
+                conn.send_have(index).await?;
+            }
+            if let (Some(availability), Some(bitfield)) = (&mut availability, conn.bitfield()) {
+                availability.update(bitfield);
+            }
+            // Created before checking the queue so that a piece put back meanwhile is not missed
+            let requeued = self.requeued.notified();
+            let piece_index = if self.queue.lock().unwrap().is_empty() {
+                if self.budget.is_exhausted() {
+                    return Ok(());
+                }
+                match self.endgame_piece(&conn) {
+                    Some(piece_index) => Some(piece_index),
+                    None => {
+                        // Pieces in flight elsewhere come back if their peer fails
+                        requeued.await;
+                        continue;
+                    }
+                }
+            } else {
+                let mut queue = self.queue.lock().unwrap();
+                // Only pieces the peer announced are requested from it
+                let has = |index| conn.has_piece(index);
+                let piece_index = if let Some(selector) = &self.selector {
+                    let mut selector = selector.lock().unwrap();
+                    let piece_index = if conn.choked() {
+                        pick_piece(&mut queue, conn.allowed_fast(), has)
+                    } else {
+                        let rarest = conn.bitfield().and_then(|have| selector.next_piece(have));
+                        rarest
+                            .and_then(|index| pick_piece(&mut queue, [&index], has))
+                            .or_else(|| pop_available(&mut queue, has))
+                    };
+                    if let Some(index) = piece_index {
+                        selector.take(index);
+                    }
+                    piece_index
+                } else if self.sequential {
+                    if conn.choked() {
+                        None
+                    } else {
+                        pop_available(&mut queue, has)
+                    }
+                } else if conn.choked() {
+                    pick_piece(&mut queue, conn.allowed_fast(), has)
+                } else {
+                    let preferred = conn.allowed_fast().iter().chain(conn.suggested());
+                    pick_piece(&mut queue, preferred, has)
+                        .or_else(|| pop_available(&mut queue, has))
+                };
+                if let Some(index) = piece_index {
+                    *self.in_flight.lock().unwrap().entry(index).or_default() += 1;
+                }
+                piece_index
+            };
+            let Some(piece_index) = piece_index else {
+                if conn.choked() {
+                    conn.wait_for_permission().await?;
+                } else {
+                    // The peer has none of the queued pieces yet
+                    tokio::select! {
+                        res = conn.wait_for_have() => res?,
+                        () = requeued => {}
+                    }
+                }
+                continue;
+            };
+            let _claim = Claim {
+                worker: self,
+                piece_index,
+            };
+            let piece_length = info.piece_len(piece_index);
+            if !self.budget.try_reserve(piece_length.into()) {
+                return Ok(());
+            }
+            let piece_hash = info.piece_hashes().nth(piece_index as usize).unwrap();
+            let piece_hash = piece_hash
+                .try_into()
+                .map_err(|_| PeerError::MalformedPieceHash {
+                    index: piece_index,
+                    length: piece_hash.len(),
+                })?;
+            let piece = conn
+                .download_piece_until(
+                    piece_index,
+                    piece_length as usize,
+                    piece_hash,
+                    completed_elsewhere(&mut completed_rx, piece_index),
+                )
+                .await;
+            match piece {
+                Ok(None) => continue,
+                Ok(Some(piece)) => {
+                    // Duplicates still downloading are cancelled once the piece is written
+                    self.in_flight.lock().unwrap().remove(&piece_index);
+                    if self.piece_tx.send((piece_index, piece)).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Picks the piece in flight with the fewest workers on it that `conn` has, once nothing is
+    /// left in the queue.
+    fn endgame_piece(&self, conn: &PeerConnection) -> Option<u32> {
+        if !self.endgame {
+            return None;
+        }
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let (&piece_index, workers) = in_flight
+            .iter_mut()
+            .filter(|(&index, _)| conn.has_piece(index))
+            .min_by_key(|(_, workers)| **workers)?;
+        *workers += 1;
+        Some(piece_index)
+    }
+
+    /// Counts this worker off the piece, returning whether nobody else is downloading it and it
+    /// has not been completed, so that it needs to be put back in the queue.
+    fn stop_downloading(&self, piece_index: u32) -> bool {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let Some(workers) = in_flight.get_mut(&piece_index) else {
+            return false;
+        };
+        *workers -= 1;
+        if *workers > 0 {
+            return false;
+        }
+        in_flight.remove(&piece_index);
+        true
+    }
+
+    /// Counts this worker off the piece and puts it back in the queue unless someone else is
+    /// still downloading it or it has been completed.
+    fn give_back(&self, piece_index: u32) {
+        if !self.stop_downloading(piece_index) {
+            return;
+        }
+        let mut queue = self.queue.lock().unwrap();
+        if self.sequential {
+            let position = queue.partition_point(|&queued| queued < piece_index);
+            queue.insert(position, piece_index);
+        } else {
+            queue.push_back(piece_index);
+        }
+        drop(queue);
+        self.release(piece_index);
+    }
+
+    /// Lets the selector and idle workers pick up a piece again after it was put back in the
+    /// queue.
+    fn release(&self, piece_index: u32) {
+        if let Some(selector) = &self.selector {
+            selector.lock().unwrap().mark_needed(piece_index);
+        }
+        self.requeued.notify_waiters();
+    }
+}
+
+/// A piece a worker took, given back with [`Worker::give_back`] once the worker is done with it
+/// however it stops, be it by failing, being cancelled or panicking.
+struct Claim<'a> {
+    worker: &'a Worker,
+    piece_index: u32,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.worker.give_back(self.piece_index);
+    }
+}
+
+/// Counts a connected peer's pieces towards their availability until it is dropped.
+struct Availability {
+    selector: Arc<Mutex<PieceSelector>>,
+    bitfield: Bitfield,
+}
+
+impl Availability {
+    /// Replaces the pieces counted for the peer if it announced more since.
+    fn update(&mut self, bitfield: &Bitfield) {
+        if *bitfield == self.bitfield {
+            return;
+        }
+        let mut selector = self.selector.lock().unwrap();
+        selector.remove_peer(&self.bitfield);
+        selector.add_peer(bitfield);
+        self.bitfield = bitfield.clone();
+    }
+}
+
+impl Drop for Availability {
+    fn drop(&mut self) {
+        self.selector.lock().unwrap().remove_peer(&self.bitfield);
+    }
+}
+
+/// Where verified pieces are written.
+enum Output<'a> {
+    File(tokio::fs::File),
+    /// Splits pieces across the files of a multi-file torrent
+    Files(FileMapper),
+    /// Writes pieces in index order, holding back the ones that arrive early
+    Stream {
+        writer: &'a mut (dyn AsyncWrite + Unpin),
+        next_piece: u32,
+        pending: BTreeMap<u32, Vec<u8>>,
+    },
+}
+
+impl Output<'_> {
+    /// Opens the file at `path` along with the pieces it already has if resuming.
+    async fn file(
+        path: &Path,
+        info: &MetainfoInfo,
+        config: &DownloadConfig,
+    ) -> io::Result<(Self, Option<Bitfield>)> {
+        let (file, existing) = if config.resume {
+            let existing = {
+                let info = info.clone();
+                let path = path.to_owned();
+                tokio::task::spawn_blocking(move || scan_existing(&info, path))
+                    .await
+                    .unwrap()?
+            };
+            (open_output_file(path).await?, Some(existing))
+        } else {
+            (create_output_file(path).await?, None)
+        };
+        file.set_len(info.length()).await?;
+        Ok((Self::File(file), existing))
+    }
+
+    async fn write_piece(
+        &mut self,
+        piece_index: u32,
+        piece_length: u32,
+        piece: Vec<u8>,
+    ) -> io::Result<()> {
+        match self {
+            Self::File(file) => {
+                let offset = u64::from(piece_index) * u64::from(piece_length);
+                file.seek(io::SeekFrom::Start(offset)).await?;
+                file.write_all(&piece).await
+            }
+            Self::Files(mapper) => {
+                let offset = u64::from(piece_index) * u64::from(piece_length);
+                mapper.write_at(offset, &piece).await
+            }
+            Self::Stream {
+                writer,
+                next_piece,
+                pending,
+            } => {
+                pending.insert(piece_index, piece);
+                while let Some(piece) = pending.remove(next_piece) {
+                    writer.write_all(&piece).await?;
+                    *next_piece += 1;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    async fn finish(self) -> io::Result<()> {
+        match self {
+            Self::File(mut file) => {
+                file.flush().await?;
+                file.sync_all().await
+            }
+            Self::Files(mapper) => mapper.finish().await,
+            Self::Stream { writer, .. } => writer.flush().await,
+        }
+    }
+}
+
+/// Takes the first of the `preferred` pieces that is still queued and that the peer `has`.
+fn pick_piece<'a>(
+    queue: &mut VecDeque<u32>,
+    preferred: impl IntoIterator<Item = &'a u32>,
+    has: impl Fn(u32) -> bool,
+) -> Option<u32> {
+    preferred
+        .into_iter()
+        .filter(|&&index| has(index))
+        .find_map(|index| {
+            let position = queue.iter().position(|queued| queued == index)?;
+            queue.remove(position)
+        })
+}
+
+/// Takes the first queued piece that the peer `has`.
+fn pop_available(queue: &mut VecDeque<u32>, has: impl Fn(u32) -> bool) -> Option<u32> {
+    let position = queue.iter().position(|&index| has(index))?;
+    queue.remove(position)
+}
+
+/// Caps the bytes requested across all workers.
+#[derive(Debug)]
+struct ByteBudget {
+    max: Option<u64>,
+    used: AtomicU64,
+    exhausted: AtomicBool,
+}
+
+impl ByteBudget {
+    /// Claims `bytes` for a piece about to be requested, or marks the budget exhausted if that
+    /// would go over it.
+    fn try_reserve(&self, bytes: u64) -> bool {
+        let Some(max) = self.max else {
+            return true;
+        };
+        let reserved = self
+            .used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                Some(used + bytes).filter(|&used| used <= max)
+            })
+            .is_ok();
+        if !reserved {
+            self.exhausted.store(true, Ordering::SeqCst);
+        }
+        reserved
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.exhausted.load(Ordering::SeqCst)
+    }
+}
+
+/// Resolves once another worker has completed the piece.
+async fn completed_elsewhere(completed_rx: &mut broadcast::Receiver<u32>, piece_index: u32) {
+    loop {
+        match completed_rx.recv().await {
+            Ok(index) if index == piece_index => return,
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => future::pending().await,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::{
+        decode_bencoded_value,
+        peer::tests::{full_bitfield, handshaking_peer, serve_blocks, serving_peer},
+        PeerMessageId, Value,
+    };
+
+    fn sample_metainfo() -> Metainfo {
+        let buf = std::fs::read("sample.torrent").unwrap();
+        let (value, _) = decode_bencoded_value(&buf);
+        Metainfo::decode(value).unwrap()
+    }
+
+    /// The info dictionary of a single file holding `content`.
+    pub(crate) fn info_for(content: &[u8], piece_length: u32) -> Value {
+        use sha1::Digest;
+        let pieces = content
+            .chunks(piece_length as usize)
+            .flat_map(|piece| <[u8; 20]>::from(sha1::Sha1::digest(piece)))
+            .collect();
+        let mut info = std::collections::BTreeMap::new();
+        info.insert("length".into(), Value::Integer(content.len() as i64));
+        info.insert("name".into(), Value::Bytes(b"content.bin".into()));
+        info.insert("piece length".into(), Value::Integer(piece_length.into()));
+        info.insert("pieces".into(), Value::Bytes(pieces));
+        Value::Dictionary(info)
+    }
+
+    pub(crate) fn metainfo_for(content: &[u8], piece_length: u32) -> Metainfo {
+        let mut map = std::collections::BTreeMap::new();
+        map.insert("announce".into(), Value::Bytes(b"http://tracker".into()));
+        map.insert("info".into(), info_for(content, piece_length));
+        Metainfo::decode(Value::Dictionary(map)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_download_all() {
+        let piece_length = 1 << 15;
+        let content = (0..piece_length * 3 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let metainfo = metainfo_for(&content, piece_length);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(serving_peer(listener, content.clone(), piece_length));
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_file_path = output_dir.path().join("content.bin");
+
+        // The duplicate is only dialed once
+        let report = download_all(
+            &metainfo,
+            &[peer, peer],
+            b"00112233445566778899",
+            &DownloadConfig::default(),
+            &output_file_path,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert!(report.is_complete());
+        assert!(!report.cancelled());
+        assert_eq!(report.completed_pieces(), &[0, 1, 2, 3]);
+        assert_eq!(std::fs::read(&output_file_path).unwrap(), content);
+        assert!(!part_file_path(&output_file_path).exists());
+    }
+
+    #[tokio::test]
+    async fn test_download_progress() {
+        let piece_length = 1 << 14;
+        let content = (0..piece_length * 3 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let metainfo = metainfo_for(&content, piece_length);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(serving_peer(listener, content.clone(), piece_length));
+
+        let progress = Arc::new(Progress::new());
+        let changed = progress.subscribe();
+        let config = DownloadConfig {
+            progress: Some(Arc::clone(&progress)),
+            ..Default::default()
+        };
+        let mut output = vec![];
+        let report = download_to_writer(
+            &metainfo,
+            &[peer],
+            b"00112233445566778899",
+            &config,
+            &mut output,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert!(report.is_complete());
+        assert!(changed.has_changed().unwrap());
+        assert_eq!(progress.downloaded_bytes(), content.len() as u64);
+        assert_eq!(progress.completed_pieces(), 4);
+        assert_eq!(progress.uploaded_bytes(), 0);
+        assert_eq!(progress.percent_complete(content.len() as u64), 100.0);
+    }
+
+    #[tokio::test]
+    async fn test_download_multi_file() {
+        let piece_length = 1 << 14;
+        let content = (0..piece_length * 2 + 5)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        // Piece 1 starts in the first file and ends in the second
+        let split = piece_length as usize + 100;
+        let encoded = metainfo_for(&content, piece_length).encode();
+        let Value::Dictionary(mut map) = decode_bencoded_value(&encoded).0 else {
+            unreachable!();
+        };
+        let Some(Value::Dictionary(info)) = map.get_mut(b"info".as_slice()) else {
+            unreachable!();
+        };
+        info.remove(b"length".as_slice());
+        let file = |length: usize, path: &[&str]| {
+            let mut file = std::collections::BTreeMap::new();
+            file.insert(b"length".to_vec(), Value::Integer(length as i64));
+            let path = path.iter().map(|c| Value::Bytes(c.as_bytes().into()));
+            file.insert(b"path".to_vec(), Value::List(path.collect()));
+            Value::Dictionary(file)
+        };
+        let files = vec![
+            file(split, &["a.bin"]),
+            file(content.len() - split, &["sub", "b.bin"]),
+        ];
+        info.insert(b"files".to_vec(), Value::List(files));
+        let metainfo = Metainfo::decode(Value::Dictionary(map)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(serving_peer(listener, content.clone(), piece_length));
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_path = output_dir.path().join("content");
+
+        let report = download_all(
+            &metainfo,
+            &[peer],
+            b"00112233445566778899",
+            &DownloadConfig::default(),
+            &output_path,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert!(report.is_complete());
+        assert_eq!(
+            std::fs::read(output_path.join("a.bin")).unwrap(),
+            &content[..split]
+        );
+        assert_eq!(
+            std::fs::read(output_path.join("sub").join("b.bin")).unwrap(),
+            &content[split..]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resume() {
+        let piece_length = 1 << 14;
+        let content = (0..piece_length * 4 + 9)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let metainfo = metainfo_for(&content, piece_length);
+        // The peer only has good copies of the pieces missing locally, so fetching any of the
+        // others again would fail verification
+        let mut served = content.clone();
+        served[0] ^= 0xff;
+        served[piece_length as usize * 2] ^= 0xff;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(serving_peer(listener, served, piece_length));
+
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_file_path = output_dir.path().join("content.bin");
+        let mut partial = content.clone();
+        partial[piece_length as usize..piece_length as usize * 2].fill(0);
+        partial.truncate(piece_length as usize * 3);
+        std::fs::write(part_file_path(&output_file_path), &partial).unwrap();
+
+        let config = DownloadConfig {
+            resume: true,
+            ..Default::default()
+        };
+        let report = download_all(
+            &metainfo,
+            &[peer],
+            b"00112233445566778899",
+            &config,
+            &output_file_path,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert!(report.is_complete());
+        assert_eq!(report.completed_pieces(), &[0, 1, 2, 3, 4]);
+        assert_eq!(std::fs::read(&output_file_path).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn test_max_download_rate() {
+        let piece_length = 1 << 14;
+        let content = (0..160 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let metainfo = metainfo_for(&content, piece_length);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(serving_peer(listener, content.clone(), piece_length));
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_file_path = output_dir.path().join("content.bin");
+
+        let config = DownloadConfig {
+            max_download_rate: Some(100 * 1024),
+            ..Default::default()
+        };
+        let start = std::time::Instant::now();
+        let report = download_all(
+            &metainfo,
+            &[peer],
+            b"00112233445566778899",
+            &config,
+            &output_file_path,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        // The first second's worth comes in a burst and the remaining 60 KiB take 0.6s
+        let elapsed = start.elapsed();
+        assert!(report.is_complete());
+        assert!(elapsed >= Duration::from_millis(550), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(3), "{elapsed:?}");
+        assert_eq!(std::fs::read(&output_file_path).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn test_download_to_writer() {
+        let piece_length = 1 << 14;
+        let content = (0..piece_length * 5 + 7)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let metainfo = metainfo_for(&content, piece_length);
+        let mut peers = vec![];
+        for _ in 0..3 {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            peers.push(listener.local_addr().unwrap());
+            tokio::spawn(serving_peer(listener, content.clone(), piece_length));
+        }
+
+        let mut output = vec![];
+        let report = download_to_writer(
+            &metainfo,
+            &peers,
+            b"00112233445566778899",
+            &DownloadConfig::default(),
+            &mut output,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert!(report.is_complete());
+        assert_eq!(output, content);
+    }
+
+    #[tokio::test]
+    async fn test_rarest_first() {
+        let piece_length = 1 << 14;
+        let content = (0..piece_length * 6 + 3)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let metainfo = metainfo_for(&content, piece_length);
+        let mut peers = vec![];
+        for _ in 0..2 {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            peers.push(listener.local_addr().unwrap());
+            tokio::spawn(serving_peer(listener, content.clone(), piece_length));
+        }
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_file_path = output_dir.path().join("content.bin");
+
+        let config = DownloadConfig {
+            rarest_first: true,
+            ..Default::default()
+        };
+        let report = download_all(
+            &metainfo,
+            &peers,
+            b"00112233445566778899",
+            &config,
+            &output_file_path,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert!(report.is_complete());
+        assert_eq!(std::fs::read(&output_file_path).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn test_request_announced_pieces_only() {
+        let piece_length = 1 << 14;
+        let content = (0..piece_length * 2)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let metainfo = metainfo_for(&content, piece_length);
+        let mut peers = vec![];
+        // Each peer has one piece and serves garbage for the other, which would get it dropped
+        for (index, bitfield) in [0x80, 0x40].into_iter().enumerate() {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            peers.push(listener.local_addr().unwrap());
+            let mut served = vec![0; content.len()];
+            let piece = index * piece_length as usize..(index + 1) * piece_length as usize;
+            served[piece.clone()].copy_from_slice(&content[piece]);
+            tokio::spawn(async move {
+                let mut stream = handshaking_peer(&listener).await;
+                stream.write_all(&[0, 0, 0, 2, 5, bitfield]).await.unwrap();
+                let mut interested = [0; 5];
+                stream.read_exact(&mut interested).await.unwrap();
+                stream.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+                serve_blocks(stream, served, piece_length).await;
+            });
+        }
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_file_path = output_dir.path().join("content.bin");
+
+        let report = download_all(
+            &metainfo,
+            &peers,
+            b"00112233445566778899",
+            &DownloadConfig::default(),
+            &output_file_path,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert!(report.is_complete());
+        assert_eq!(std::fs::read(&output_file_path).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn test_retry_corrupt_piece() {
+        let piece_length = 1 << 14;
+        let content = (0..piece_length * 2)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let metainfo = metainfo_for(&content, piece_length);
+        let mut corrupt = content.clone();
+        corrupt[0] ^= 0xff;
+        corrupt[piece_length as usize] ^= 0xff;
+        let corrupt_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let corrupt_peer = corrupt_listener.local_addr().unwrap();
+        tokio::spawn(serving_peer(corrupt_listener, corrupt, piece_length));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(serving_peer(listener, content.clone(), piece_length));
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_file_path = output_dir.path().join("content.bin");
+
+        // The corrupt peer is dropped and its pieces are fetched from the other one
+        let report = download_all(
+            &metainfo,
+            &[corrupt_peer, peer],
+            b"00112233445566778899",
+            &DownloadConfig::default(),
+            &output_file_path,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert!(report.is_complete());
+        assert_eq!(std::fs::read(&output_file_path).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn test_max_total_bytes() {
+        let piece_length = 1 << 15;
+        let content = (0..piece_length * 4)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let metainfo = metainfo_for(&content, piece_length);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(serving_peer(listener, content.clone(), piece_length));
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_file_path = output_dir.path().join("content.bin");
+
+        let config = DownloadConfig {
+            max_total_bytes: Some(u64::from(piece_length) * 2 + 100),
+            ..Default::default()
+        };
+        let report = download_all(
+            &metainfo,
+            &[peer],
+            b"00112233445566778899",
+            &config,
+            &output_file_path,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert!(report.budget_exhausted());
+        assert!(!report.cancelled());
+        assert_eq!(report.completed_pieces(), &[0, 1]);
+        let part = std::fs::read(part_file_path(&output_file_path)).unwrap();
+        assert_eq!(
+            part[..piece_length as usize * 2],
+            content[..piece_length as usize * 2]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_skip_peer_closed_after_handshake() {
+        let piece_length = 1 << 15;
+        let content = (0..piece_length * 2)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let metainfo = metainfo_for(&content, piece_length);
+        let closing = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closing_peer = closing.local_addr().unwrap();
+        tokio::spawn(async move {
+            handshaking_peer(&closing).await;
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(serving_peer(listener, content.clone(), piece_length));
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_file_path = output_dir.path().join("content.bin");
+
+        let report = download_all(
+            &metainfo,
+            &[closing_peer, peer],
+            b"00112233445566778899",
+            &DownloadConfig::default(),
+            &output_file_path,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert!(report.is_complete());
+        assert_eq!(std::fs::read(&output_file_path).unwrap(), content);
+    }
+
+    /// Unchokes us but never answers a request, and returns everything sent after `Interested`.
+    async fn stalling_peer(listener: TcpListener, piece_count: u32) -> Vec<u8> {
+        let mut stream = handshaking_peer(&listener).await;
+        stream.write_all(&full_bitfield(piece_count)).await.unwrap();
+        let mut interested = [0; 5];
+        stream.read_exact(&mut interested).await.unwrap();
+        stream.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+        let mut sink = vec![];
+        let _ = stream.read_to_end(&mut sink).await;
+        sink
+    }
+
+    #[tokio::test]
+    async fn test_endgame() {
+        let piece_length = 1 << 14;
+        let content = (0..piece_length)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let metainfo = metainfo_for(&content, piece_length);
+        let stalling_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stalling = stalling_listener.local_addr().unwrap();
+        let stalled = tokio::spawn(stalling_peer(stalling_listener, 1));
+        // Answers late so that the only piece is first requested from the stalling peer
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            serving_peer(listener, content, piece_length).await;
+        });
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_file_path = output_dir.path().join("content.bin");
+
+        let report = download_all(
+            &metainfo,
+            &[stalling, peer],
+            b"00112233445566778899",
+            &DownloadConfig::default(),
+            &output_file_path,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert!(report.is_complete());
+
+        // The request to the stalling peer was cancelled once the other peer sent the piece
+        let stalled = stalled.await.unwrap();
+        let mut messages = vec![];
+        let mut rest = stalled.as_slice();
+        while !rest.is_empty() {
+            let length = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            messages.push(&rest[4..4 + length]);
+            rest = &rest[4 + length..];
+        }
+        let request = messages[0];
+        assert_eq!(request[0], PeerMessageId::Request.code());
+        let cancel = messages
+            .iter()
+            .find(|message| message[0] == PeerMessageId::Cancel.code())
+            .unwrap();
+        assert_eq!(request[1..], cancel[1..]);
+    }
+
+    #[tokio::test]
+    async fn test_drop_silent_peer() {
+        let piece_length = 1 << 14;
+        let content = (0..piece_length * 4)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let metainfo = metainfo_for(&content, piece_length);
+        let stalling_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stalling = stalling_listener.local_addr().unwrap();
+        tokio::spawn(stalling_peer(stalling_listener, 4));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(serving_peer(listener, content.clone(), piece_length));
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_file_path = output_dir.path().join("content.bin");
+
+        // The silent peer times out and its piece is fetched from the other one
+        let config = DownloadConfig {
+            connect_options: ConnectOptions {
+                read_timeout: Duration::from_millis(200),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let report = tokio::time::timeout(
+            Duration::from_secs(5),
+            download_all(
+                &metainfo,
+                &[stalling, peer],
+                b"00112233445566778899",
+                &config,
+                &output_file_path,
+                CancellationToken::new(),
+            ),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(report.is_complete());
+        assert_eq!(std::fs::read(&output_file_path).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_download_all() {
+        let metainfo = sample_metainfo();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        let piece_count = metainfo.info().piece_hashes().count() as u32;
+        tokio::spawn(stalling_peer(listener, piece_count));
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_file_path = output_dir.path().join("sample.txt");
+
+        let cancel = CancellationToken::new();
+        tokio::spawn({
+            let cancel = cancel.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                cancel.cancel();
+            }
+        });
+        let report = tokio::time::timeout(
+            Duration::from_secs(5),
+            download_all(
+                &metainfo,
+                &[peer],
+                b"00112233445566778899",
+                &DownloadConfig::default(),
+                &output_file_path,
+                cancel,
+            ),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(report.cancelled());
+        assert!(!report.is_complete());
+        assert!(report.completed_pieces().is_empty());
+        assert!(!output_file_path.exists());
+        assert!(part_file_path(&output_file_path).exists());
+    }
+
+    #[tokio::test]
+    async fn test_create_output_file() {
+        let output_dir = tempfile::tempdir().unwrap();
+        let path = output_dir.path().join("downloads/nested/content.bin");
+        create_output_file(&path).await.unwrap();
+        assert!(path.exists());
+
+        // A file where a directory should be is reported rather than panicking
+        let res = create_output_file(&path.join("content.bin")).await;
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_pick_piece() {
+        let mut queue = VecDeque::from([0, 1, 2, 3]);
+        assert_eq!(pick_piece(&mut queue, &[7, 2, 1], |_| true), Some(2));
+        assert_eq!(pick_piece(&mut queue, &[7], |_| true), None);
+        assert_eq!(queue, [0, 1, 3]);
+        // Pieces the peer does not have are skipped
+        assert_eq!(pick_piece(&mut queue, &[1, 3], |index| index == 3), Some(3));
+        assert_eq!(pop_available(&mut queue, |index| index == 1), Some(1));
+        assert_eq!(pop_available(&mut queue, |index| index == 7), None);
+        assert_eq!(queue, [0]);
+    }
+
+    #[test]
+    fn test_part_file_path() {
+        assert_eq!(
+            part_file_path(Path::new("/tmp/sample.txt")),
+            Path::new("/tmp/sample.txt.part")
+        );
+        assert_eq!(part_file_path(Path::new("out")), Path::new("out.part"));
+    }
+}
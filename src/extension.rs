@@ -0,0 +1,136 @@
+use std::collections::BTreeMap;
+
+use crate::{encode_bencoded_value, peer::MAX_PIPELINE_DEPTH, try_decode_bencoded_value, Value};
+
+/// Extended message id of the handshake itself (BEP 10).
+pub const HANDSHAKE_ID: u8 = 0;
+
+pub const CLIENT_VERSION: &str = concat!("bittorrent-starter-rust/", env!("CARGO_PKG_VERSION"));
+
+/// The dictionary exchanged in the BEP 10 extended handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtendedHandshake {
+    /// Maps extension names to the message ids the sender wants to receive them with
+    pub extensions: BTreeMap<String, u8>,
+    pub client: Option<String>,
+    /// How many outstanding requests the sender accepts
+    pub reqq: Option<u32>,
+    /// The sender's listening port
+    pub port: Option<u16>,
+    /// Size of the info dictionary the sender can serve through `ut_metadata` (BEP 9)
+    pub metadata_size: Option<u32>,
+}
+
+impl ExtendedHandshake {
+    /// Our own handshake, advertising `extensions` and listening on `port`.
+    pub fn ours(extensions: BTreeMap<String, u8>, port: u16) -> Self {
+        Self {
+            extensions,
+            client: Some(CLIENT_VERSION.to_owned()),
+            reqq: Some(MAX_PIPELINE_DEPTH as u32),
+            port: Some(port),
+            metadata_size: None,
+        }
+    }
+
+    /// Encodes the payload of the extended message, starting with [`HANDSHAKE_ID`].
+    pub fn encode(&self) -> Vec<u8> {
+        let extensions = self
+            .extensions
+            .iter()
+            .map(|(name, &id)| (name.clone().into_bytes(), Value::Integer(id.into())))
+            .collect();
+        let mut map = BTreeMap::new();
+        map.insert(b"m".to_vec(), Value::Dictionary(extensions));
+        if let Some(client) = &self.client {
+            map.insert(b"v".to_vec(), Value::Bytes(client.clone().into_bytes()));
+        }
+        if let Some(reqq) = self.reqq {
+            map.insert(b"reqq".to_vec(), Value::Integer(reqq.into()));
+        }
+        if let Some(port) = self.port {
+            map.insert(b"p".to_vec(), Value::Integer(port.into()));
+        }
+        if let Some(metadata_size) = self.metadata_size {
+            map.insert(
+                b"metadata_size".to_vec(),
+                Value::Integer(metadata_size.into()),
+            );
+        }
+
+        let mut payload = vec![HANDSHAKE_ID];
+        payload.extend(encode_bencoded_value(&Value::Dictionary(map)));
+        payload
+    }
+
+    /// Decodes the bencoded dictionary following the extended message id, ignoring keys that are
+    /// missing, malformed or out of range.
+    pub fn decode(dict: &[u8]) -> Self {
+        let mut map = try_decode_bencoded_value(dict)
+            .ok()
+            .and_then(|(value, _)| value.into_dictionary())
+            .unwrap_or_default();
+        let extensions = map
+            .remove(b"m".as_slice())
+            .and_then(|extensions| extensions.into_dictionary())
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(name, id)| {
+                let name = String::from_utf8(name).ok()?;
+                let id = u8::try_from(id.into_integer()?).ok()?;
+                Some((name, id))
+            })
+            .collect();
+        let client = map
+            .remove(b"v".as_slice())
+            .and_then(|client| client.into_bytes())
+            .map(|client| String::from_utf8_lossy(&client).into_owned());
+        let reqq = map
+            .remove(b"reqq".as_slice())
+            .and_then(|reqq| reqq.into_integer())
+            .and_then(|reqq| u32::try_from(reqq).ok());
+        let port = map
+            .remove(b"p".as_slice())
+            .and_then(|port| port.into_integer())
+            .and_then(|port| u16::try_from(port).ok());
+        let metadata_size = map
+            .remove(b"metadata_size".as_slice())
+            .and_then(|metadata_size| metadata_size.into_integer())
+            .and_then(|metadata_size| u32::try_from(metadata_size).ok());
+        Self {
+            extensions,
+            client,
+            reqq,
+            port,
+            metadata_size,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extended_handshake() {
+        let mut extensions = BTreeMap::new();
+        extensions.insert("ut_metadata".to_owned(), 1);
+        let handshake = ExtendedHandshake::ours(extensions, 6881);
+        let payload = handshake.encode();
+        assert_eq!(payload[0], HANDSHAKE_ID);
+        let expected = format!(
+            "d1:md11:ut_metadatai1ee1:pi6881e4:reqqi{MAX_PIPELINE_DEPTH}e1:v{}:{CLIENT_VERSION}e",
+            CLIENT_VERSION.len()
+        );
+        assert_eq!(&payload[1..], expected.as_bytes());
+        assert_eq!(ExtendedHandshake::decode(&payload[1..]), handshake);
+
+        let handshake = ExtendedHandshake {
+            metadata_size: Some(31235),
+            ..handshake
+        };
+        let payload = handshake.encode();
+        assert_eq!(ExtendedHandshake::decode(&payload[1..]), handshake);
+        assert_eq!(ExtendedHandshake::decode(b"garbage").extensions.len(), 0);
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+};
+
+use tokio::{
+    fs::File,
+    io::{AsyncSeekExt, AsyncWriteExt},
+};
+
+use crate::FileEntry;
+
+/// Spreads the content of a multi-file torrent over its files, which are laid out back to back.
+#[derive(Debug)]
+pub struct FileMapper {
+    files: Vec<MappedFile>,
+}
+
+#[derive(Debug)]
+struct MappedFile {
+    /// Offset of the file's first byte in the content
+    start: u64,
+    length: u64,
+    file: File,
+}
+
+impl FileMapper {
+    /// Creates every file of `files` under `dir` at its full length, along with the directories
+    /// in its path.
+    ///
+    /// Paths that are absolute or climb out of `dir` are rejected.
+    pub async fn create(dir: &Path, files: &[FileEntry]) -> io::Result<Self> {
+        let mut mapped = vec![];
+        let mut start = 0;
+        for entry in files {
+            let path = file_path(dir, &entry.path)?;
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            let file = File::options()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&path)
+                .await?;
+            let length = entry.length as u64;
+            file.set_len(length).await?;
+            mapped.push(MappedFile {
+                start,
+                length,
+                file,
+            });
+            start += length;
+        }
+        Ok(Self { files: mapped })
+    }
+
+    /// Writes `data` at `offset` into the content, splitting it across the files it spans.
+    pub async fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let end = offset + data.len() as u64;
+        for mapped in &mut self.files {
+            let file_end = mapped.start + mapped.length;
+            if file_end <= offset || end <= mapped.start {
+                continue;
+            }
+            let from = offset.max(mapped.start);
+            let to = end.min(file_end);
+            let data = &data[(from - offset) as usize..(to - offset) as usize];
+            mapped
+                .file
+                .seek(io::SeekFrom::Start(from - mapped.start))
+                .await?;
+            mapped.file.write_all(data).await?;
+        }
+        Ok(())
+    }
+
+    /// Flushes every file to disk.
+    pub async fn finish(self) -> io::Result<()> {
+        for mut mapped in self.files {
+            mapped.file.flush().await?;
+            mapped.file.sync_all().await?;
+        }
+        Ok(())
+    }
+}
+
+fn file_path(dir: &Path, components: &[String]) -> io::Result<PathBuf> {
+    let relative = components.iter().collect::<PathBuf>();
+    let is_plain = !components.is_empty()
+        && components.len() == relative.components().count()
+        && relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    if !is_plain {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsafe file path in torrent: {components:?}"),
+        ));
+    }
+    Ok(dir.join(relative))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(length: usize, path: &[&str]) -> FileEntry {
+        FileEntry {
+            length,
+            path: path.iter().map(|&component| component.into()).collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_across_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = [
+            entry(5, &["a.txt"]),
+            entry(0, &["empty"]),
+            entry(7, &["sub", "b.txt"]),
+        ];
+        let mut mapper = FileMapper::create(dir.path(), &files).await.unwrap();
+        mapper.write_at(3, b"defghij").await.unwrap();
+        mapper.write_at(0, b"abc").await.unwrap();
+        mapper.write_at(10, b"kl").await.unwrap();
+        mapper.finish().await.unwrap();
+
+        assert_eq!(std::fs::read(dir.path().join("a.txt")).unwrap(), b"abcde");
+        assert_eq!(std::fs::read(dir.path().join("empty")).unwrap(), b"");
+        assert_eq!(
+            std::fs::read(dir.path().join("sub").join("b.txt")).unwrap(),
+            b"fghijkl"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reject_unsafe_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        for path in [&["..", "escape"][..], &["/etc", "passwd"], &["a/b"], &[]] {
+            let res = FileMapper::create(dir.path(), &[entry(1, path)]).await;
+            assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
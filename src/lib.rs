@@ -1,127 +1,541 @@
 use std::{
     collections::BTreeMap,
-    fmt, io,
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    fmt,
+    net::{IpAddr, SocketAddr},
+    ops::{Range, RangeInclusive},
 };
 
-use byteorder::BigEndian;
 use getset::{CopyGetters, Getters};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncRead, AsyncWrite};
 
+use crate::{bitfield::Bitfield, de::DeError, peer::PeerError};
+
+pub mod announce;
+pub mod bitfield;
+pub mod create;
+pub mod de;
+pub mod dht;
+pub mod download;
+pub mod extension;
+pub mod files;
+pub mod magnet;
+pub mod metadata;
+pub mod peer;
+pub mod pool;
+pub mod probe;
+pub mod progress;
+pub mod rate;
+pub mod scrape;
+pub mod seed;
+pub mod selector;
+pub mod socks;
+pub mod udp_tracker;
+pub mod verify;
+
+/// Decodes the value at the start of `encoded_value`, returning it with the number of bytes read.
+///
+/// Panics on malformed input; see [`try_decode_bencoded_value`].
 pub fn decode_bencoded_value(encoded_value: &[u8]) -> (Value, usize) {
+    try_decode_bencoded_value(encoded_value).unwrap()
+}
+
+/// Lists and dictionaries nested deeper than this are rejected rather than overflowing the stack
+pub const DEFAULT_MAX_DEPTH: usize = 1000;
+
+/// Decodes the value at the start of `encoded_value`, returning it with the number of bytes read.
+pub fn try_decode_bencoded_value(encoded_value: &[u8]) -> Result<(Value, usize), BencodeError> {
+    try_decode_bencoded_value_with_max_depth(encoded_value, DEFAULT_MAX_DEPTH)
+}
+
+/// Like [`try_decode_bencoded_value`], but fails with [`BencodeError::DepthExceeded`] once lists
+/// and dictionaries are nested more than `max_depth` levels deep.
+pub fn try_decode_bencoded_value_with_max_depth(
+    encoded_value: &[u8],
+    max_depth: usize,
+) -> Result<(Value, usize), BencodeError> {
+    decode_bencoded_value_with(
+        encoded_value,
+        &mut vec![],
+        &mut None::<fn(&[Vec<u8>], Value)>,
+        max_depth,
+    )
+}
+
+/// Decodes a single value that must span all of `encoded_value`.
+pub fn decode_bencoded_value_exact(encoded_value: &[u8]) -> Result<Value, BencodeError> {
+    let (value, consumed) = try_decode_bencoded_value(encoded_value)?;
+    if consumed != encoded_value.len() {
+        return Err(BencodeError::TrailingGarbage {
+            consumed,
+            total: encoded_value.len(),
+        });
+    }
+    Ok(value)
+}
+
+/// Decodes like [`decode_bencoded_value`], but hands each list element to `visitor` as soon as it
+/// is parsed instead of collecting it, along with the dictionary keys leading to that list.
+///
+/// Visited lists are left empty in the returned value.
+pub fn decode_with_visitor<F>(encoded_value: &[u8], visitor: F) -> (Value, usize)
+where
+    F: FnMut(&[Vec<u8>], Value),
+{
+    decode_bencoded_value_with(
+        encoded_value,
+        &mut vec![],
+        &mut Some(visitor),
+        DEFAULT_MAX_DEPTH,
+    )
+    .unwrap()
+}
+
+/// Finds the bytes of the value stored under `key` in the dictionary at the start of
+/// `encoded_value`, exactly as they appear in the input.
+pub fn dictionary_value_span(
+    encoded_value: &[u8],
+    key: &[u8],
+) -> Result<Option<Range<usize>>, BencodeError> {
+    if encoded_value.first() != Some(&b'd') {
+        return Ok(None);
+    }
+    let mut pos = 1;
+    loop {
+        let remaining = &encoded_value[pos..];
+        if remaining.first() == Some(&b'e') {
+            return Ok(None);
+        }
+        let (entry_key, read) = try_decode_bencoded_value(remaining)?;
+        pos += read;
+        let (_, read) = try_decode_bencoded_value(&encoded_value[pos..])?;
+        if entry_key == Value::Bytes(key.to_vec()) {
+            return Ok(Some(pos..pos + read));
+        }
+        pos += read;
+    }
+}
+
+/// Decodes a stream of concatenated values fed in chunks of any size, such as a response body
+/// read off the network, without waiting for the whole input.
+///
+/// Bytes are scanned once to find where each top-level value ends, and only then decoded.
+#[derive(Debug, Clone, Default)]
+pub struct BencodeDecoder {
+    buf: Vec<u8>,
+    /// How much of `buf` has been scanned
+    pos: usize,
+    /// Lists and dictionaries opened but not yet closed
+    depth: usize,
+    state: ScanState,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+enum ScanState {
+    /// Expecting the first byte of a value, or the `e` closing a list or dictionary
+    #[default]
+    Value,
+    /// Inside an integer, up to its `e`
+    Integer,
+    /// Reading the length of a string
+    Length(usize),
+    /// Skipping this many bytes of a string
+    Skip(usize),
+}
+
+impl BencodeDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Buffers `chunk` and returns the top-level values it completed.
+    ///
+    /// After an error the decoder must not be fed again.
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<Vec<Value>, BencodeError> {
+        self.buf.extend_from_slice(chunk);
+        let mut values = vec![];
+        while self.pos < self.buf.len() {
+            if self.scan()? {
+                let value = decode_bencoded_value_exact(&self.buf[..self.pos])?;
+                values.push(value);
+                self.buf.drain(..self.pos);
+                self.pos = 0;
+            }
+        }
+        Ok(values)
+    }
+
+    /// Whether a value has been started but not completed.
+    pub fn is_partial(&self) -> bool {
+        !self.buf.is_empty()
+    }
+
+    /// Advances over the buffered bytes, returning `true` as soon as a top-level value ends at
+    /// `self.pos`.
+    fn scan(&mut self) -> Result<bool, BencodeError> {
+        let byte = self.buf[self.pos];
+        let value_ended = match self.state {
+            ScanState::Value => {
+                self.pos += 1;
+                match byte {
+                    b'i' => {
+                        self.state = ScanState::Integer;
+                        false
+                    }
+                    b'l' | b'd' => {
+                        self.depth += 1;
+                        false
+                    }
+                    b'e' if self.depth > 0 => {
+                        self.depth -= 1;
+                        true
+                    }
+                    b'0'..=b'9' => {
+                        self.state = ScanState::Length((byte - b'0').into());
+                        false
+                    }
+                    byte => return Err(BencodeError::UnexpectedByte { byte }),
+                }
+            }
+            ScanState::Integer => match self.buf[self.pos..].iter().position(|&b| b == b'e') {
+                Some(e_index) => {
+                    self.pos += e_index + 1;
+                    self.state = ScanState::Value;
+                    true
+                }
+                None => {
+                    self.pos = self.buf.len();
+                    false
+                }
+            },
+            ScanState::Length(length) => {
+                self.pos += 1;
+                match byte {
+                    b'0'..=b'9' => {
+                        let length = length
+                            .checked_mul(10)
+                            .and_then(|length| length.checked_add((byte - b'0').into()))
+                            .ok_or(BencodeError::InvalidLengthPrefix)?;
+                        self.state = ScanState::Length(length);
+                        false
+                    }
+                    b':' if length == 0 => {
+                        self.state = ScanState::Value;
+                        true
+                    }
+                    b':' => {
+                        self.state = ScanState::Skip(length);
+                        false
+                    }
+                    _ => return Err(BencodeError::MissingColon),
+                }
+            }
+            ScanState::Skip(length) => {
+                let skipped = length.min(self.buf.len() - self.pos);
+                self.pos += skipped;
+                if skipped == length {
+                    self.state = ScanState::Value;
+                    true
+                } else {
+                    self.state = ScanState::Skip(length - skipped);
+                    false
+                }
+            }
+        };
+        Ok(value_ended && self.depth == 0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum BencodeError {
+    #[error("unexpected end of input")]
+    UnexpectedEof,
+    #[error("invalid integer")]
+    InvalidInteger,
+    #[error("missing colon after the string length")]
+    MissingColon,
+    #[error("invalid string length")]
+    InvalidLengthPrefix,
+    #[error("dictionary key is not a string")]
+    NonStringKey,
+    #[error("unexpected byte {byte:#04x} at the start of a value")]
+    UnexpectedByte { byte: u8 },
+    #[error("lists and dictionaries are nested too deeply")]
+    DepthExceeded,
+    #[error("only {consumed} of {total} bytes were decoded")]
+    TrailingGarbage { consumed: usize, total: usize },
+}
+
+/// A list or dictionary whose closing `e` has not been reached yet.
+enum Container {
+    List(Vec<Value>),
+    /// The key of the value being decoded is on top of the path
+    Dictionary {
+        map: BTreeMap<Vec<u8>, Value>,
+        awaiting_value: bool,
+    },
+}
+
+/// Decodes with an explicit stack of open containers instead of recursing, so deeply nested input
+/// cannot overflow the call stack.
+///
+/// Elements of lists reached from the top through dictionary values only are handed to `visitor`
+/// along with the keys leading to them, instead of being collected.
+fn decode_bencoded_value_with<F>(
+    encoded_value: &[u8],
+    path: &mut Vec<Vec<u8>>,
+    visitor: &mut Option<F>,
+    max_depth: usize,
+) -> Result<(Value, usize), BencodeError>
+where
+    F: FnMut(&[Vec<u8>], Value),
+{
+    // Each container remembers whether its list elements are visited
+    let mut stack: Vec<(Container, bool)> = vec![];
+    let mut pos = 0;
+    loop {
+        let remaining = &encoded_value[pos..];
+        let first = *remaining.first().ok_or(BencodeError::UnexpectedEof)?;
+        let visited = match stack.last() {
+            None => visitor.is_some(),
+            Some((Container::Dictionary { awaiting_value, .. }, visited)) => {
+                *awaiting_value && *visited
+            }
+            Some((Container::List(_), _)) => false,
+        };
+        let awaiting_key = matches!(
+            stack.last(),
+            Some((
+                Container::Dictionary {
+                    awaiting_value: false,
+                    ..
+                },
+                _
+            ))
+        );
+        let at_boundary = awaiting_key || matches!(stack.last(), Some((Container::List(_), _)));
+
+        let value = match first {
+            b'e' if at_boundary => {
+                pos += 1;
+                match stack.pop().unwrap().0 {
+                    Container::List(elements) => Value::List(elements),
+                    Container::Dictionary { map, .. } => Value::Dictionary(map),
+                }
+            }
+            b'l' | b'd' if awaiting_key => return Err(BencodeError::NonStringKey),
+            b'l' | b'd' => {
+                if stack.len() == max_depth {
+                    return Err(BencodeError::DepthExceeded);
+                }
+                pos += 1;
+                let container = if first == b'l' {
+                    // Example: "l5:helloi52ee" -> ["hello", 52]
+                    Container::List(vec![])
+                } else {
+                    // Example: "d3:foo3:bar5:helloi52ee" -> {"hello": 52, "foo":"bar"}
+                    Container::Dictionary {
+                        map: BTreeMap::new(),
+                        awaiting_value: false,
+                    }
+                };
+                stack.push((container, visited));
+                continue;
+            }
+            _ => {
+                let (value, read) = decode_scalar(remaining)?;
+                pos += read;
+                value
+            }
+        };
+
+        // Hand the completed value to the container it belongs to
+        let Some((container, visited)) = stack.last_mut() else {
+            return Ok((value, pos));
+        };
+        match container {
+            // Elements of a visited list are handed over whole
+            Container::List(elements) => match visitor {
+                Some(visitor) if *visited => visitor(path, value),
+                _ => elements.push(value),
+            },
+            Container::Dictionary {
+                map,
+                awaiting_value,
+            } => {
+                if *awaiting_value {
+                    map.insert(path.pop().unwrap(), value);
+                } else {
+                    let Value::Bytes(key) = value else {
+                        return Err(BencodeError::NonStringKey);
+                    };
+                    path.push(key);
+                }
+                *awaiting_value = !*awaiting_value;
+            }
+        }
+    }
+}
+
+/// Decodes the string or integer at the start of `encoded_value`.
+fn decode_scalar(encoded_value: &[u8]) -> Result<(Value, usize), BencodeError> {
+    let first = *encoded_value.first().ok_or(BencodeError::UnexpectedEof)?;
+
     // If encoded_value starts with a digit, it's a number
-    if encoded_value[0].is_ascii_digit() {
+    if first.is_ascii_digit() {
         // Example: "5:hello" -> "hello"
-        let colon_index = encoded_value.iter().position(|v| *v == b':').unwrap();
-        let number_string = &encoded_value[..colon_index];
-        let number = String::from_utf8_lossy(number_string)
-            .parse::<i64>()
-            .unwrap();
-        let read = colon_index + 1 + number as usize;
-        let string = &encoded_value[colon_index + 1..read];
-        return (Value::Bytes(string.to_owned()), read);
+        let colon_index = encoded_value
+            .iter()
+            .position(|v| *v == b':')
+            .ok_or(BencodeError::MissingColon)?;
+        let number = std::str::from_utf8(&encoded_value[..colon_index])
+            .ok()
+            .and_then(|number_string| number_string.parse::<usize>().ok())
+            .ok_or(BencodeError::InvalidLengthPrefix)?;
+        let read = (colon_index + 1)
+            .checked_add(number)
+            .ok_or(BencodeError::InvalidLengthPrefix)?;
+        let string = encoded_value
+            .get(colon_index + 1..read)
+            .ok_or(BencodeError::UnexpectedEof)?;
+        return Ok((Value::Bytes(string.to_owned()), read));
     }
 
     // If encoded_value starts with 'i', it's an integer
-    if encoded_value[0] == b'i' {
+    if first == b'i' {
         // Example: "i52e" -> 52
         // Example: "i-52e" -> -52
-        let e_index = encoded_value.iter().position(|v| *v == b'e').unwrap();
+        let e_index = encoded_value
+            .iter()
+            .position(|v| *v == b'e')
+            .ok_or(BencodeError::UnexpectedEof)?;
         let integer_string = &encoded_value[1..e_index];
-        let integer = String::from_utf8_lossy(integer_string)
-            .parse::<i64>()
-            .unwrap();
-        return (Value::Integer(integer), e_index + 1);
-    }
-
-    // If encoded_value starts with 'l', it's a list
-    if encoded_value[0] == b'l' {
-        // Example: "l5:helloi52ee" -> ["hello", 52]
-        let mut elements = vec![];
-        let mut pos = 1;
-        loop {
-            let remaining = encoded_value.get(pos..).unwrap();
-            if remaining[0] == b'e' {
-                return (Value::List(elements), pos);
-            }
-            let (element, read) = decode_bencoded_value(remaining);
-            elements.push(element);
-            pos += read;
+        if !is_canonical_integer(integer_string) {
+            return Err(BencodeError::InvalidInteger);
         }
+        let integer = std::str::from_utf8(integer_string)
+            .ok()
+            .and_then(|integer_string| integer_string.parse::<i64>().ok())
+            .ok_or(BencodeError::InvalidInteger)?;
+        return Ok((Value::Integer(integer), e_index + 1));
     }
 
-    // If encoded_value starts with 'd', it's a dictionary
-    if encoded_value[0] == b'd' {
-        // Example: "d3:foo3:bar5:helloi52ee" -> {"hello": 52, "foo":"bar"}
-        let mut map: BTreeMap<String, Value> = Default::default();
-        let mut pos = 1;
-        loop {
-            let remaining = encoded_value.get(pos..).unwrap();
-            if remaining[0] == b'e' {
-                return (Value::Dictionary(map), pos);
-            }
-            let (key, read) = decode_bencoded_value(remaining);
-            let key = match key {
-                Value::Bytes(string) => String::from_utf8(string).unwrap(),
-                _ => panic!(),
-            };
-            pos += read;
-
-            let remaining = encoded_value.get(pos..).unwrap();
-            let (value, read) = decode_bencoded_value(remaining);
-            pos += read;
+    Err(BencodeError::UnexpectedByte { byte: first })
+}
 
-            map.insert(key, value);
-        }
+/// Whether `integer_string` is written without a leading `+`, leading zeros or a negative zero.
+fn is_canonical_integer(integer_string: &[u8]) -> bool {
+    let digits = integer_string.strip_prefix(b"-").unwrap_or(integer_string);
+    match digits {
+        [] => false,
+        [b'0'] => digits.len() == integer_string.len(),
+        [b'0', ..] => false,
+        _ => digits.iter().all(u8::is_ascii_digit),
     }
-
-    panic!(
-        "Unhandled encoded value: {}",
-        String::from_utf8_lossy(encoded_value)
-    )
 }
 
 pub fn encode_bencoded_value(decoded_value: &Value) -> Vec<u8> {
     let mut encoded_value = vec![];
+    encode_bencoded_value_into(decoded_value, &mut encoded_value);
+    encoded_value
+}
+
+/// Appends the encoding of `decoded_value` to `out`, without allocating for nested values.
+pub fn encode_bencoded_value_into(decoded_value: &Value, out: &mut Vec<u8>) {
     match decoded_value {
-        Value::Bytes(bytes) => {
-            let length = bytes.len().to_string();
-            encoded_value.extend(length.bytes());
-            encoded_value.push(b':');
-            encoded_value.extend(bytes);
-        }
+        Value::Bytes(bytes) => encode_bytes_into(bytes, out),
         Value::Integer(integer) => {
-            encoded_value.push(b'i');
-            let integer = integer.to_string();
-            encoded_value.extend(integer.bytes());
-            encoded_value.push(b'e');
+            use std::io::Write;
+            write!(out, "i{integer}e").unwrap();
         }
         Value::List(list) => {
-            encoded_value.push(b'l');
+            out.push(b'l');
             for item in list {
-                let item = encode_bencoded_value(item);
-                encoded_value.extend(item);
+                encode_bencoded_value_into(item, out);
             }
-            encoded_value.push(b'e');
+            out.push(b'e');
         }
         Value::Dictionary(dictionary) => {
-            encoded_value.push(b'd');
+            out.push(b'd');
             for (key, value) in dictionary {
-                let key = encode_bencoded_value(&Value::Bytes(key.as_bytes().into()));
-                encoded_value.extend(key);
-                let value = encode_bencoded_value(value);
-                encoded_value.extend(value);
+                encode_bytes_into(key, out);
+                encode_bencoded_value_into(value, out);
             }
-            encoded_value.push(b'e');
+            out.push(b'e');
         }
     }
-    encoded_value
+}
+
+fn encode_bytes_into(bytes: &[u8], out: &mut Vec<u8>) {
+    use std::io::Write;
+    write!(out, "{}:", bytes.len()).unwrap();
+    out.extend(bytes);
+}
+
+/// Streams the encoding of `decoded_value` to `writer` piece by piece, so wrap unbuffered
+/// writers such as sockets in a [`tokio::io::BufWriter`].
+pub async fn encode_bencoded_value_async<W>(
+    decoded_value: &Value,
+    writer: &mut W,
+) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    use std::io::Write;
+    use tokio::io::AsyncWriteExt;
+
+    /// What is left to write, innermost last, as async functions cannot recurse without boxing
+    enum Pending<'a> {
+        Value(&'a Value),
+        Key(&'a [u8]),
+        End,
+    }
+
+    let mut stack = vec![Pending::Value(decoded_value)];
+    let mut header = vec![];
+    while let Some(pending) = stack.pop() {
+        header.clear();
+        let bytes: &[u8] = match pending {
+            Pending::Value(Value::Bytes(bytes)) => bytes,
+            Pending::Key(key) => key,
+            Pending::Value(value @ Value::Integer(_)) => {
+                encode_bencoded_value_into(value, &mut header);
+                writer.write_all(&header).await?;
+                continue;
+            }
+            Pending::Value(Value::List(list)) => {
+                writer.write_all(b"l").await?;
+                stack.push(Pending::End);
+                stack.extend(list.iter().rev().map(Pending::Value));
+                continue;
+            }
+            Pending::Value(Value::Dictionary(dictionary)) => {
+                writer.write_all(b"d").await?;
+                stack.push(Pending::End);
+                for (key, value) in dictionary.iter().rev() {
+                    stack.push(Pending::Value(value));
+                    stack.push(Pending::Key(key));
+                }
+                continue;
+            }
+            Pending::End => {
+                writer.write_all(b"e").await?;
+                continue;
+            }
+        };
+        write!(header, "{}:", bytes.len()).unwrap();
+        writer.write_all(&header).await?;
+        writer.write_all(bytes).await?;
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use std::io::Read;
+    use std::{
+        io::{self, Read},
+        net::Ipv6Addr,
+    };
 
     use super::*;
 
@@ -146,6 +560,111 @@ mod tests {
         assert_eq!(encoded_value, &encode_bencoded_value(&value)[..]);
     }
 
+    #[test]
+    fn test_non_canonical_number() {
+        for encoded_value in [b"i03e".as_slice(), b"i-0e", b"i00e", b"i-e", b"ie", b"i+5e"] {
+            assert_eq!(
+                try_decode_bencoded_value(encoded_value),
+                Err(BencodeError::InvalidInteger)
+            );
+        }
+        for (encoded_value, integer) in [(b"i0e".as_slice(), 0), (b"i-52e", -52), (b"i10e", 10)] {
+            let (value, _) = try_decode_bencoded_value(encoded_value).unwrap();
+            assert_eq!(value, Value::Integer(integer));
+            assert_eq!(encoded_value, &encode_bencoded_value(&value)[..]);
+        }
+    }
+
+    #[test]
+    fn test_bencode_decoder() {
+        let inputs = [
+            b"i52e".as_slice(),
+            b"0:",
+            b"5:hello",
+            b"le",
+            b"de",
+            b"l5:helloi52ee",
+            b"d3:foo3:bar5:hellod3:fool0:i-7eeee",
+            b"d8:intervali1800e5:peers12:\x01\x02\x03\x04\x1a\xe1\x7f\x00\x00\x01\x1a\xe2e",
+        ];
+        let stream = inputs.concat();
+        let expected = inputs
+            .iter()
+            .map(|input| decode_bencoded_value(input).0)
+            .collect::<Vec<_>>();
+        for chunk_size in 1..=stream.len() {
+            let mut decoder = BencodeDecoder::new();
+            let mut values = vec![];
+            for chunk in stream.chunks(chunk_size) {
+                values.extend(decoder.feed(chunk).unwrap());
+            }
+            assert_eq!(values, expected, "chunk size {chunk_size}");
+            assert!(!decoder.is_partial());
+        }
+        // Split once at every boundary
+        for split in 0..=stream.len() {
+            let mut decoder = BencodeDecoder::new();
+            let mut values = decoder.feed(&stream[..split]).unwrap();
+            values.extend(decoder.feed(&stream[split..]).unwrap());
+            assert_eq!(values, expected, "split at {split}");
+        }
+
+        let mut decoder = BencodeDecoder::new();
+        assert_eq!(decoder.feed(b"l5:hel").unwrap(), vec![]);
+        assert!(decoder.is_partial());
+        assert_eq!(
+            BencodeDecoder::new().feed(b"i52ex"),
+            Err(BencodeError::UnexpectedByte { byte: b'x' })
+        );
+        assert_eq!(
+            BencodeDecoder::new().feed(b"i03e"),
+            Err(BencodeError::InvalidInteger)
+        );
+        assert_eq!(
+            BencodeDecoder::new().feed(b"e"),
+            Err(BencodeError::UnexpectedByte { byte: b'e' })
+        );
+        assert_eq!(
+            BencodeDecoder::new().feed(b"5x"),
+            Err(BencodeError::MissingColon)
+        );
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let nested = |depth: usize| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+        let (value, read) = try_decode_bencoded_value(&nested(DEFAULT_MAX_DEPTH)).unwrap();
+        assert_eq!(read, 2 * DEFAULT_MAX_DEPTH);
+        assert!(matches!(value, Value::List(_)));
+        assert_eq!(
+            try_decode_bencoded_value(&nested(DEFAULT_MAX_DEPTH + 1)),
+            Err(BencodeError::DepthExceeded)
+        );
+        // Fails fast on input that would otherwise overflow the stack
+        assert_eq!(
+            try_decode_bencoded_value(&vec![b'l'; 100_000]),
+            Err(BencodeError::DepthExceeded)
+        );
+        assert_eq!(
+            try_decode_bencoded_value_with_max_depth(b"d1:ad1:ad1:ai1eeee", 2),
+            Err(BencodeError::DepthExceeded)
+        );
+        assert!(try_decode_bencoded_value_with_max_depth(b"d1:ad1:ad1:ai1eeee", 3).is_ok());
+        assert!(try_decode_bencoded_value_with_max_depth(b"i1e", 0).is_ok());
+    }
+
+    #[test]
+    fn test_decode_exact() {
+        assert_eq!(
+            decode_bencoded_value_exact(b"i52eGARBAGE"),
+            Err(BencodeError::TrailingGarbage {
+                consumed: 4,
+                total: 11
+            })
+        );
+        assert_eq!(decode_bencoded_value_exact(b"i52e"), Ok(Value::Integer(52)));
+    }
+
     #[test]
     fn test_list() {
         let encoded_value = b"l5:helloi52ee";
@@ -171,6 +690,96 @@ mod tests {
         assert_eq!(encoded_value, &encode_bencoded_value(&value)[..]);
     }
 
+    #[test]
+    fn test_nested() {
+        let encoded_value = b"d4:dictd3:foo3:bare4:listlli1eei2eee";
+        let (value, read) = decode_bencoded_value(encoded_value);
+        assert_eq!(read, encoded_value.len());
+        let mut dict = BTreeMap::new();
+        dict.insert("foo".into(), Value::Bytes(b"bar".into()));
+        let mut map = BTreeMap::new();
+        map.insert(
+            "list".into(),
+            Value::List(vec![
+                Value::List(vec![Value::Integer(1)]),
+                Value::Integer(2),
+            ]),
+        );
+        map.insert("dict".into(), Value::Dictionary(dict));
+        assert_eq!(value, Value::Dictionary(map));
+        assert_eq!(encoded_value, &encode_bencoded_value(&value)[..]);
+    }
+
+    #[test]
+    fn test_encode_into() {
+        let buf = std::fs::read("sample.torrent").unwrap();
+        let (sample, _) = decode_bencoded_value(&buf);
+        let values = [
+            sample,
+            Value::Bytes(vec![]),
+            Value::Integer(-42),
+            Value::List(vec![]),
+            Value::Dictionary(BTreeMap::new()),
+        ];
+        for value in values {
+            let mut out = b"prefix".to_vec();
+            encode_bencoded_value_into(&value, &mut out);
+            assert_eq!(out[..6], *b"prefix");
+            assert_eq!(out[6..], encode_bencoded_value(&value));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_encode_async() {
+        let buf = std::fs::read("sample.torrent").unwrap();
+        let (sample, _) = decode_bencoded_value(&buf);
+        let (nested, _) = decode_bencoded_value(b"d4:dictd3:foo3:bare4:listlli1eei-2eeee");
+        for value in [sample, nested, Value::Bytes(vec![]), Value::List(vec![])] {
+            let mut out = vec![];
+            encode_bencoded_value_async(&value, &mut out).await.unwrap();
+            assert_eq!(out, encode_bencoded_value(&value));
+        }
+    }
+
+    #[test]
+    fn test_try_decode_malformed() {
+        let cases: [(&[u8], BencodeError); 9] = [
+            (b"", BencodeError::UnexpectedEof),
+            (b"5:hel", BencodeError::UnexpectedEof),
+            (b"5hello", BencodeError::MissingColon),
+            (b"5x:hello", BencodeError::InvalidLengthPrefix),
+            (b"i52", BencodeError::UnexpectedEof),
+            (b"i5x2e", BencodeError::InvalidInteger),
+            (b"l5:hello", BencodeError::UnexpectedEof),
+            (b"di1ei2ee", BencodeError::NonStringKey),
+            (b"x", BencodeError::UnexpectedByte { byte: b'x' }),
+        ];
+        for (encoded_value, expected) in cases {
+            assert_eq!(try_decode_bencoded_value(encoded_value), Err(expected));
+        }
+        assert_eq!(
+            try_decode_bencoded_value(b"l5:helloe"),
+            Ok((Value::List(vec![Value::Bytes(b"hello".into())]), 9))
+        );
+    }
+
+    #[test]
+    fn test_visitor() {
+        let encoded_value = b"d8:intervali60e5:peersld2:ip3:a.b4:porti1eed2:ip3:c.d4:porti2eeee";
+        let mut ips = vec![];
+        let (value, read) = decode_with_visitor(encoded_value, |path, element| {
+            assert_eq!(path, [b"peers"]);
+            let mut peer = element.into_dictionary().unwrap();
+            ips.push(peer.remove(b"ip".as_slice()).unwrap().into_bytes().unwrap());
+        });
+        assert_eq!(read, encoded_value.len());
+        assert_eq!(ips, [b"a.b", b"c.d"]);
+        let mut map = BTreeMap::new();
+        map.insert("interval".into(), Value::Integer(60));
+        map.insert("peers".into(), Value::List(vec![]));
+        assert_eq!(value, Value::Dictionary(map));
+    }
+
     #[test]
     fn test_metainfo() {
         let file = "sample.torrent";
@@ -179,6 +788,1042 @@ mod tests {
         file.read_to_end(&mut buf).unwrap();
         let (_value, _) = decode_bencoded_value(&buf);
     }
+
+    #[test]
+    fn test_info_hash() {
+        let buf = std::fs::read("sample.torrent").unwrap();
+        let (value, _) = decode_bencoded_value(&buf);
+        let metainfo = Metainfo::decode(value).unwrap();
+        assert_eq!(
+            metainfo.info().hash().to_string(),
+            "d69f91e6b2ae4c542468d1073a71d4ea13879a7f"
+        );
+    }
+
+    #[test]
+    fn test_info_hash_round_trip() {
+        let hex = "d69f91e6b2ae4c542468d1073a71d4ea13879a7f";
+        let base32 = "22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7";
+        let info_hash: InfoHash = hex.parse().unwrap();
+        assert_eq!(info_hash.as_bytes()[..2], [0xd6, 0x9f]);
+        assert_eq!(info_hash.to_string(), hex);
+        assert_eq!(hex.to_uppercase().parse(), Ok(info_hash));
+        assert_eq!(info_hash.to_base32(), base32);
+        assert_eq!(InfoHash::from_base32(base32), Ok(info_hash));
+        assert_eq!(InfoHash::from_base32(&base32.to_lowercase()), Ok(info_hash));
+
+        for bytes in [[0; 20], [0xff; 20]] {
+            let info_hash = InfoHash::new(bytes);
+            assert_eq!(info_hash.to_string().parse(), Ok(info_hash));
+            assert_eq!(InfoHash::from_base32(&info_hash.to_base32()), Ok(info_hash));
+        }
+
+        assert!(matches!(
+            "d69f".parse::<InfoHash>(),
+            Err(InfoHashError::InvalidHex(_))
+        ));
+        assert!(matches!(
+            InfoHash::from_base32("22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT1"),
+            Err(InfoHashError::InvalidBase32(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_info_hash() {
+        let hex = parse_info_hash("d69f91e6b2ae4c542468d1073a71d4ea13879a7f").unwrap();
+        let base32 = parse_info_hash("22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7").unwrap();
+        assert_eq!(hex, base32);
+        assert_eq!(hex.as_bytes()[..2], [0xd6, 0x9f]);
+        assert_eq!(
+            parse_info_hash("22pzdzvsvzgfijdi2edtu4ou5ijypgt7"),
+            Ok(base32)
+        );
+        assert_eq!(
+            parse_info_hash("D69F91E6B2AE4C542468D1073A71D4EA13879A7F"),
+            Ok(hex)
+        );
+        assert_eq!(
+            parse_info_hash("d69f"),
+            Err(InfoHashError::InvalidLength("d69f".to_owned()))
+        );
+        assert!(matches!(
+            parse_info_hash("z69f91e6b2ae4c542468d1073a71d4ea13879a7f"),
+            Err(InfoHashError::InvalidHex(_))
+        ));
+    }
+
+    fn tracker_response(peers: &[u8]) -> Value {
+        let mut map = BTreeMap::new();
+        map.insert("interval".into(), Value::Integer(60));
+        map.insert("peers".into(), Value::Bytes(peers.into()));
+        Value::Dictionary(map)
+    }
+
+    #[test]
+    fn test_recompute_info_hash() {
+        let buf = std::fs::read("sample.torrent").unwrap();
+        let (value, _) = decode_bencoded_value(&buf);
+        let mut metainfo = Metainfo::decode(value).unwrap();
+        let hash = *metainfo.info().hash();
+        assert_eq!(metainfo.encode(), buf);
+
+        // The announce URL is outside the info dictionary
+        metainfo.set_announce("http://other-tracker/announce".into());
+        metainfo.recompute_info_hash();
+        assert_eq!(metainfo.info().hash(), &hash);
+
+        metainfo.info_mut().set_name("renamed.txt".into());
+        metainfo.recompute_info_hash();
+        assert_ne!(metainfo.info().hash(), &hash);
+        let (value, _) = decode_bencoded_value(&metainfo.encode());
+        let decoded = Metainfo::decode(value).unwrap();
+        assert_eq!(decoded.announce(), "http://other-tracker/announce");
+        assert_eq!(decoded.info().name(), "renamed.txt");
+        assert_eq!(decoded.info().hash(), metainfo.info().hash());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_extension_bit() {
+        let mut buf = vec![];
+        HandshakeRequest {
+            info_hash: &InfoHash::new([1; 20]),
+            peer_id: b"00112233445566778899",
+        }
+        .encode(&mut buf)
+        .await
+        .unwrap();
+        assert_eq!(buf.len(), 68);
+        assert_eq!(buf[20..28], [0, 0, 0, 0, 0, 0x10, 0, 0x05]);
+
+        let handshake = HandshakeResponse::decode(&mut buf.as_slice())
+            .await
+            .unwrap();
+        assert!(handshake.supports_extensions());
+        assert!(handshake.supports_fast_extension());
+        assert!(handshake.supports_dht());
+        assert_eq!(handshake.info_hash(), &InfoHash::new([1; 20]));
+        buf[25] = 0;
+        buf[27] = 0x01;
+        let handshake = HandshakeResponse::decode(&mut buf.as_slice())
+            .await
+            .unwrap();
+        assert!(!handshake.supports_extensions());
+        assert!(!handshake.supports_fast_extension());
+        assert!(handshake.supports_dht());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_verify() {
+        let mut buf = vec![];
+        HandshakeRequest {
+            info_hash: &InfoHash::new([1; 20]),
+            peer_id: b"00112233445566778899",
+        }
+        .encode(&mut buf)
+        .await
+        .unwrap();
+        let handshake = HandshakeResponse::decode(&mut buf.as_slice())
+            .await
+            .unwrap();
+        assert!(handshake.verify(&InfoHash::new([1; 20])).is_ok());
+        assert!(matches!(
+            handshake.verify(&InfoHash::new([2; 20])),
+            Err(HandshakeError::InfoHashMismatch { expected, got })
+                if expected == InfoHash::new([2; 20]) && got == InfoHash::new([1; 20])
+        ));
+
+        buf[1] = b'b';
+        assert!(matches!(
+            HandshakeResponse::decode(&mut buf.as_slice()).await,
+            Err(HandshakeError::BadProtocolString(protocol)) if protocol == b"bitTorrent protocol"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_truncated() {
+        let mut buf = vec![];
+        HandshakeRequest {
+            info_hash: &InfoHash::new([1; 20]),
+            peer_id: b"00112233445566778899",
+        }
+        .encode(&mut buf)
+        .await
+        .unwrap();
+        for len in [0, 1, 10, 30, 67] {
+            assert!(matches!(
+                HandshakeResponse::decode(&mut &buf[..len]).await,
+                Err(HandshakeError::UnexpectedEof)
+            ));
+        }
+        buf[0] = 20;
+        assert!(matches!(
+            HandshakeResponse::decode(&mut buf.as_slice()).await,
+            Err(HandshakeError::BadProtocolLength(20))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_encode_write_error() {
+        // A writer that fills up stands in for a peer that reset the connection
+        let mut buf = [0; 10];
+        let res = HandshakeRequest {
+            info_hash: &InfoHash::new([1; 20]),
+            peer_id: b"00112233445566778899",
+        }
+        .encode(&mut io::Cursor::new(&mut buf[..]))
+        .await;
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::WriteZero);
+        let res = PeerMessageOut {
+            message_id: PeerMessageId::Bitfield,
+            payload: &[0xff; 20],
+        }
+        .encode(&mut io::Cursor::new(&mut buf[..]))
+        .await;
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::WriteZero);
+    }
+
+    #[tokio::test]
+    async fn test_into_typed() {
+        async fn typed(id: u8, payload: &[u8]) -> Result<TypedPeerMessage, PeerError> {
+            let mut buf = u32::try_from(payload.len() + 1)
+                .unwrap()
+                .to_be_bytes()
+                .to_vec();
+            buf.push(id);
+            buf.extend(payload);
+            PeerMessageIn::decode(&mut buf.as_slice())
+                .await
+                .unwrap()
+                .unwrap()
+                .into_typed()
+        }
+        let request = PeerMessageRequest {
+            index: 1,
+            begin: 2,
+            length: 3,
+        };
+        let request_payload = [0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3];
+        let mut bitfield = Bitfield::new(16);
+        bitfield.set(0);
+        bitfield.set(9);
+        let cases = [
+            (0, &[][..], TypedPeerMessage::Choke),
+            (1, &[], TypedPeerMessage::Unchoke),
+            (2, &[], TypedPeerMessage::Interested),
+            (3, &[], TypedPeerMessage::NotInterested),
+            (4, &[0, 0, 1, 0], TypedPeerMessage::Have(256)),
+            (5, &[0x80, 0x40], TypedPeerMessage::Bitfield(bitfield)),
+            (6, &request_payload, TypedPeerMessage::Request(request)),
+            (
+                7,
+                &[0, 0, 0, 1, 0, 0, 0, 2, 9, 9],
+                TypedPeerMessage::Piece(PeerMessageResponse {
+                    index: 1,
+                    begin: 2,
+                    block: vec![9, 9],
+                }),
+            ),
+            (8, &request_payload, TypedPeerMessage::Cancel(request)),
+            (9, &[0x1a, 0xe1], TypedPeerMessage::Port(6881)),
+            (13, &[0, 0, 0, 5], TypedPeerMessage::Suggest(5)),
+            (17, &[0, 0, 0, 6], TypedPeerMessage::AllowedFast(6)),
+            (14, &[], TypedPeerMessage::HaveAll),
+            (15, &[], TypedPeerMessage::HaveNone),
+            (16, &request_payload, TypedPeerMessage::Reject(request)),
+            (
+                20,
+                &[0, b'd', b'e'],
+                TypedPeerMessage::Extended(b"\0de".to_vec()),
+            ),
+        ];
+        for (id, payload, expected) in cases {
+            assert_eq!(typed(id, payload).await.unwrap(), expected, "{id}");
+        }
+
+        assert!(matches!(
+            typed(4, &[0, 0, 1]).await,
+            Err(PeerError::MalformedMessage {
+                message_id: PeerMessageId::Have,
+                length: 3
+            })
+        ));
+        assert!(matches!(
+            typed(1, &[0]).await,
+            Err(PeerError::MalformedMessage { .. })
+        ));
+        assert!(matches!(
+            typed(7, &[0, 0, 0, 1]).await,
+            Err(PeerError::MalformedMessage { .. })
+        ));
+        assert!(matches!(
+            typed(99, &[1, 2]).await,
+            Err(PeerError::UnknownMessage(99))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_peer_message_truncated() {
+        let buf = [0, 0, 0, 5, 4, 0, 0, 0, 7];
+        let message = PeerMessageIn::decode(&mut &buf[..]).await.unwrap().unwrap();
+        assert_eq!(message.message_id(), PeerMessageId::Have);
+        assert_eq!(message.payload(), &[0, 0, 0, 7]);
+        assert!(PeerMessageIn::decode(&mut &[0, 0, 0, 0][..])
+            .await
+            .unwrap()
+            .is_none());
+        for len in [0, 3, 4, 8] {
+            assert!(matches!(
+                PeerMessageIn::decode(&mut &buf[..len]).await,
+                Err(PeerError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof
+            ));
+        }
+        assert!(matches!(
+            PeerMessageIn::decode(&mut &[0xff, 0xff, 0xff, 0xff][..]).await,
+            Err(PeerError::MessageTooLarge {
+                length: u32::MAX,
+                max: peer::MAX_MESSAGE_LEN
+            })
+        ));
+        assert!(PeerMessageIn::decode_with_limit(&mut &buf[..], 5)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(matches!(
+            PeerMessageIn::decode_with_limit(&mut &buf[..], 4).await,
+            Err(PeerError::MessageTooLarge { length: 5, max: 4 })
+        ));
+        assert!(PeerMessageResponse::decode(&mut &[0, 0, 0, 1, 0][..], 5)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_tracker_response_edge_ports() {
+        let resp = TrackerResponse::decode(tracker_response(&[
+            127, 0, 0, 1, 0xff, 0xff, //
+            10, 0, 0, 2, 0, 0, //
+            10, 0, 0, 3, 0x1a, 0xe1,
+        ]))
+        .unwrap();
+        assert_eq!(
+            resp.peers(),
+            &[
+                "127.0.0.1:65535".parse::<SocketAddr>().unwrap(),
+                "10.0.0.2:0".parse().unwrap(),
+                "10.0.0.3:6881".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tracker_response_missing_interval() {
+        let resp = TrackerResponse::decode(tracker_response(&[])).unwrap();
+        assert_eq!(resp.interval(), 60);
+        assert!(resp.interval_provided());
+
+        let mut map = tracker_response(&[]).into_dictionary().unwrap();
+        map.remove(b"interval".as_slice());
+        let resp = TrackerResponse::decode(Value::Dictionary(map)).unwrap();
+        assert_eq!(resp.interval(), DEFAULT_ANNOUNCE_INTERVAL);
+        assert!(!resp.interval_provided());
+    }
+
+    #[test]
+    fn test_tracker_response_dictionary_peers() {
+        let peer = |ip: &str, port, peer_id: Option<&[u8]>| {
+            let mut peer = BTreeMap::new();
+            peer.insert(b"ip".to_vec(), Value::Bytes(ip.into()));
+            peer.insert(b"port".to_vec(), Value::Integer(port));
+            if let Some(peer_id) = peer_id {
+                peer.insert(b"peer id".to_vec(), Value::Bytes(peer_id.into()));
+            }
+            Value::Dictionary(peer)
+        };
+        let mut map = tracker_response(&[]).into_dictionary().unwrap();
+        let peers = vec![
+            peer("10.0.0.1", 6881, Some(b"00112233445566778899")),
+            peer("::1", 6882, None),
+            peer("tracker.example", 6883, None),
+        ];
+        map.insert(b"peers".to_vec(), Value::List(peers));
+        let resp = TrackerResponse::decode(Value::Dictionary(map)).unwrap();
+        assert_eq!(
+            resp.peers(),
+            &[
+                "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "[::1]:6882".parse().unwrap(),
+            ]
+        );
+        assert_eq!(resp.peer_ids(), &[Some(*b"00112233445566778899"), None]);
+
+        // Compact peers come without ids
+        let resp = TrackerResponse::decode(tracker_response(&[10, 0, 0, 1, 0x1a, 0xe1])).unwrap();
+        assert_eq!(
+            resp.peers(),
+            &["10.0.0.1:6881".parse::<SocketAddr>().unwrap()]
+        );
+        assert_eq!(resp.peer_ids(), &[None]);
+    }
+
+    #[test]
+    fn test_tracker_response_peers6() {
+        let mut map = tracker_response(&[10, 0, 0, 1, 0x1a, 0xe1])
+            .into_dictionary()
+            .unwrap();
+        let mut peers6 = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        peers6.extend(6882_u16.to_be_bytes());
+        map.insert(b"peers6".to_vec(), Value::Bytes(peers6));
+        let resp = TrackerResponse::decode(Value::Dictionary(map)).unwrap();
+        assert_eq!(
+            resp.peers(),
+            &[
+                "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "[2001:db8::1]:6882".parse().unwrap(),
+            ]
+        );
+        assert_eq!(resp.peer_ids().len(), 2);
+
+        let mut map = tracker_response(&[]).into_dictionary().unwrap();
+        map.insert(b"peers6".to_vec(), Value::Bytes(vec![0; 17]));
+        let resp = TrackerResponse::decode(Value::Dictionary(map));
+        assert!(matches!(
+            resp,
+            Err(TrackerError::InvalidCompactPeers(
+                CompactPeersError::InvalidLength {
+                    length: 17,
+                    entry_length: 18
+                }
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_tracker_response_failure() {
+        let (value, _) = decode_bencoded_value(b"d14:failure reason17:torrent not founde");
+        let resp = TrackerResponse::decode(value);
+        assert!(
+            matches!(resp, Err(TrackerError::Failure(reason)) if reason == "torrent not found")
+        );
+
+        let mut map = tracker_response(&[]).into_dictionary().unwrap();
+        map.remove(b"peers".as_slice());
+        let resp = TrackerResponse::decode(Value::Dictionary(map));
+        assert!(matches!(resp, Err(TrackerError::Malformed)));
+        let resp = TrackerResponse::decode(Value::Integer(1));
+        assert!(matches!(resp, Err(TrackerError::Malformed)));
+    }
+
+    #[test]
+    fn test_tracker_response_partial_peer() {
+        let resp = TrackerResponse::decode(tracker_response(&[127, 0, 0, 1, 0x1a, 0xe1, 127]));
+        assert!(matches!(
+            resp,
+            Err(TrackerError::InvalidCompactPeersLength { length: 7 })
+        ));
+        let resp = TrackerResponse::decode(tracker_response(&[])).unwrap();
+        assert!(resp.peers().is_empty());
+    }
+
+    #[test]
+    fn test_parse_compact_peers() {
+        assert_eq!(
+            parse_compact_peers(&[10, 0, 0, 1, 0x1a, 0xe1]).unwrap(),
+            &["10.0.0.1:6881".parse::<SocketAddr>().unwrap()]
+        );
+        // Every octet differs, so a byte order mistake would show
+        assert_eq!(
+            parse_compact_peers(&[1, 2, 3, 4, 0, 5]).unwrap(),
+            &["1.2.3.4:5".parse::<SocketAddr>().unwrap()]
+        );
+        let mut v6 = vec![0; 15];
+        v6.extend([1, 0x1a, 0xe1]);
+        assert_eq!(
+            parse_compact_peers_v6(&v6).unwrap(),
+            &["[::1]:6881".parse::<SocketAddr>().unwrap()]
+        );
+        assert!(matches!(
+            parse_compact_peers_v6(&v6[..6]),
+            Err(CompactPeersError::InvalidLength {
+                length: 6,
+                entry_length: 18
+            })
+        ));
+    }
+
+    #[test]
+    fn test_tracker_request_extended_stats() {
+        let metainfo = metainfo(1, 1, 20);
+        let mut req = TrackerRequest {
+            info_hash: metainfo.info().hash(),
+            peer_id: b"00112233445566778899",
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 1,
+            compact: true,
+            tracker_id: None,
+            corrupt: None,
+            redundant: None,
+            event: None,
+        };
+        assert!(req.url(&metainfo).ends_with("&left=1&compact=1"));
+
+        req.tracker_id = Some("a b");
+        req.corrupt = Some(3);
+        req.redundant = Some(4);
+        assert!(req
+            .url(&metainfo)
+            .ends_with("&left=1&compact=1&trackerid=a%20b&corrupt=3&redundant=4"));
+    }
+
+    #[test]
+    fn test_tracker_request_builder() {
+        let metainfo = metainfo(3, 1, 60);
+        let manual = TrackerRequest {
+            info_hash: metainfo.info().hash(),
+            peer_id: b"00112233445566778899",
+            port: 6881,
+            uploaded: 0,
+            downloaded: 1,
+            left: 2,
+            compact: true,
+            tracker_id: Some("x"),
+            corrupt: None,
+            redundant: None,
+            event: Some(TrackerEvent::Started),
+        };
+        let built = TrackerRequest::builder(metainfo.info(), b"00112233445566778899")
+            .downloaded(1)
+            .left(metainfo.info().bytes_left(1))
+            .tracker_id("x")
+            .event(TrackerEvent::Started)
+            .build();
+        assert_eq!(built.url(&metainfo), manual.url(&metainfo));
+
+        // Nothing is downloaded yet unless told otherwise
+        let built = TrackerRequest::builder(metainfo.info(), b"00112233445566778899")
+            .port(1)
+            .compact(false)
+            .build();
+        assert!(built
+            .url(&metainfo)
+            .ends_with("&port=1&uploaded=0&downloaded=0&left=3&compact=0"));
+        assert_eq!(metainfo.info().bytes_left(5), 0);
+    }
+
+    #[test]
+    fn test_tracker_request_existing_query() {
+        let metainfo = metainfo(1, 1, 20);
+        let req = TrackerRequest {
+            info_hash: &InfoHash::new([0xab; 20]),
+            peer_id: b"00112233445566778899",
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 1,
+            compact: true,
+            tracker_id: None,
+            corrupt: None,
+            redundant: None,
+            event: None,
+        };
+        let query = "info_hash=%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB\
+            &peer_id=00112233445566778899&port=6881&uploaded=0&downloaded=0&left=1&compact=1";
+        for (tracker, expected) in [
+            (
+                "https://tracker/announce",
+                format!("https://tracker/announce?{query}"),
+            ),
+            (
+                "https://tracker/announce?passkey=x",
+                format!("https://tracker/announce?passkey=x&{query}"),
+            ),
+            (
+                "https://tracker/announce?passkey=x&",
+                format!("https://tracker/announce?passkey=x&{query}"),
+            ),
+            (
+                "https://tracker/announce?",
+                format!("https://tracker/announce?{query}"),
+            ),
+        ] {
+            assert_eq!(req.url_to(tracker), expected);
+        }
+        assert!(req
+            .url_for("https://tracker/announce?passkey=x", &metainfo)
+            .starts_with("https://tracker/announce?passkey=x&info_hash="));
+    }
+
+    #[test]
+    fn test_tracker_request_event() {
+        let metainfo = metainfo(1, 1, 20);
+        let mut req = TrackerRequest {
+            info_hash: metainfo.info().hash(),
+            peer_id: b"00112233445566778899",
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 1,
+            compact: true,
+            tracker_id: None,
+            corrupt: None,
+            redundant: None,
+            event: None,
+        };
+        assert!(!req.url(&metainfo).contains("event="));
+        for (event, query) in [
+            (TrackerEvent::Started, "&left=1&compact=1&event=started"),
+            (TrackerEvent::Stopped, "&left=1&compact=1&event=stopped"),
+            (TrackerEvent::Completed, "&left=1&compact=1&event=completed"),
+        ] {
+            req.event = Some(event);
+            assert!(req.url(&metainfo).ends_with(query));
+        }
+    }
+
+    #[test]
+    fn test_tracker_response_tracker_id() {
+        let resp = TrackerResponse::decode(tracker_response(&[])).unwrap();
+        assert_eq!(resp.tracker_id(), &None);
+
+        let mut value = tracker_response(&[]).into_dictionary().unwrap();
+        value.insert("tracker id".into(), Value::Bytes(b"abc".into()));
+        let resp = TrackerResponse::decode(Value::Dictionary(value)).unwrap();
+        assert_eq!(resp.tracker_id().as_deref(), Some("abc"));
+    }
+
+    fn metainfo(length: i64, piece_length: i64, pieces: usize) -> Metainfo {
+        Metainfo::decode(metainfo_value(length, piece_length, pieces)).unwrap()
+    }
+
+    fn metainfo_value(length: i64, piece_length: i64, pieces: usize) -> Value {
+        let mut info = BTreeMap::new();
+        info.insert("length".into(), Value::Integer(length));
+        info.insert("name".into(), Value::Bytes(b"sample.txt".into()));
+        info.insert("piece length".into(), Value::Integer(piece_length));
+        info.insert("pieces".into(), Value::Bytes(vec![0; pieces]));
+        let mut map = BTreeMap::new();
+        map.insert("announce".into(), Value::Bytes(b"http://tracker".into()));
+        map.insert("info".into(), Value::Dictionary(info));
+        Value::Dictionary(map)
+    }
+
+    fn metainfo_with_name(name: &[u8], name_utf8: Option<&str>, encoding: &str) -> Metainfo {
+        let mut info = BTreeMap::new();
+        info.insert("length".into(), Value::Integer(1));
+        info.insert("name".into(), Value::Bytes(name.into()));
+        if let Some(name_utf8) = name_utf8 {
+            info.insert("name.utf-8".into(), Value::Bytes(name_utf8.into()));
+        }
+        info.insert("piece length".into(), Value::Integer(1));
+        info.insert("pieces".into(), Value::Bytes(vec![0; 20]));
+        let mut map = BTreeMap::new();
+        map.insert("announce".into(), Value::Bytes(b"http://tracker".into()));
+        map.insert("encoding".into(), Value::Bytes(encoding.into()));
+        map.insert("info".into(), Value::Dictionary(info));
+        Metainfo::decode(Value::Dictionary(map)).unwrap()
+    }
+
+    #[test]
+    fn test_decode_malformed() {
+        // Each case replaces one info key, or removes it if there is no value
+        let with_info = |key: &[u8], value: Option<Value>| {
+            let mut map = metainfo_value(1, 1, 20).into_dictionary().unwrap();
+            let Some(Value::Dictionary(info)) = map.get_mut(b"info".as_slice()) else {
+                unreachable!();
+            };
+            match value {
+                Some(value) => info.insert(key.to_vec(), value),
+                None => info.remove(key),
+            };
+            Metainfo::decode(Value::Dictionary(map))
+        };
+        assert!(with_info(b"comment", None).is_ok());
+        let empty_file = Value::Dictionary(BTreeMap::new());
+        for (key, value) in [
+            (b"name".as_slice(), None),
+            (b"pieces", None),
+            (b"length", None),
+            (b"piece length", Some(Value::Bytes(b"16".to_vec()))),
+            (b"length", Some(Value::Integer(-1))),
+            (b"files", Some(Value::List(vec![empty_file]))),
+        ] {
+            assert!(with_info(key, value).is_err());
+        }
+
+        assert!(Metainfo::decode(Value::Integer(1)).is_err());
+        let mut map = metainfo_value(1, 1, 20).into_dictionary().unwrap();
+        map.insert(b"info".to_vec(), Value::List(vec![]));
+        assert!(Metainfo::decode(Value::Dictionary(map)).is_err());
+        assert!(MetainfoInfo::decode(Value::Bytes(vec![])).is_err());
+        assert!(matches!(
+            Metainfo::decode_bytes(b"d8:announce1:xe"),
+            Err(DeError::Message(_))
+        ));
+        assert!(matches!(
+            Metainfo::decode_bytes(b"d8:announce"),
+            Err(DeError::Bencode(_))
+        ));
+    }
+
+    #[test]
+    fn test_name_encoding() {
+        // "中文" in GBK
+        let gbk = [0xd6, 0xd0, 0xce, 0xc4];
+        let metainfo = metainfo_with_name(&gbk, Some("中文"), "GBK");
+        assert_eq!(metainfo.encoding().as_deref(), Some("GBK"));
+        assert_eq!(metainfo.info().name(), "中文");
+
+        let metainfo = metainfo_with_name(&gbk, None, "GBK");
+        if cfg!(feature = "encoding") {
+            assert_eq!(metainfo.info().name(), "中文");
+        } else {
+            assert_eq!(metainfo.info().name(), "\u{fffd}\u{fffd}\u{fffd}\u{fffd}");
+        }
+    }
+
+    #[test]
+    fn test_piece_layers() {
+        assert_eq!(metainfo(1, 1, 20).piece_layers(), None);
+
+        let root = [0xff; 32].to_vec();
+        let layer = [0xab; 64].to_vec();
+        let mut layers = BTreeMap::new();
+        layers.insert(root.clone(), Value::Bytes(layer.clone()));
+        let mut map = metainfo_value(1, 1, 20).into_dictionary().unwrap();
+        map.insert(b"piece layers".to_vec(), Value::Dictionary(layers));
+        // The binary root survives a round trip through the decoder
+        let encoded = encode_bencoded_value(&Value::Dictionary(map));
+        let (value, _) = decode_bencoded_value(&encoded);
+        let metainfo = Metainfo::decode(value).unwrap();
+
+        let mut expected = BTreeMap::new();
+        expected.insert(root, layer);
+        assert_eq!(metainfo.piece_layers(), Some(&expected));
+    }
+
+    #[test]
+    fn test_non_canonical_key_order() {
+        let info = [
+            b"d4:name10:sample.txt6:lengthi1e6:pieces20:".as_slice(),
+            &[0; 20],
+            b"12:piece lengthi1ee",
+        ]
+        .concat();
+        let buf = [
+            b"d8:announce14:http://tracker4:info".as_slice(),
+            &info,
+            b"e",
+        ]
+        .concat();
+
+        let mut metainfo = Metainfo::decode_bytes(&buf).unwrap();
+        use sha1::Digest;
+        let hash = InfoHash::new(sha1::Sha1::digest(&info).into());
+        assert_eq!(metainfo.info().hash(), &hash);
+        assert_eq!(metainfo.encode(), buf);
+        metainfo.recompute_info_hash();
+        assert_eq!(metainfo.info().hash(), &hash);
+
+        // Editing the dictionary re-encodes it canonically
+        let (value, _) = decode_bencoded_value(&buf);
+        assert_ne!(Metainfo::decode(value).unwrap().info().hash(), &hash);
+        metainfo.info_mut().set_name("sample.txt".to_owned());
+        metainfo.recompute_info_hash();
+        assert_ne!(metainfo.info().hash(), &hash);
+    }
+
+    #[test]
+    fn test_multi_file() {
+        assert_eq!(metainfo(1, 1, 20).info().total_length(), 1);
+
+        let file = |length, path: &[&str]| {
+            let mut file = BTreeMap::new();
+            file.insert(b"length".to_vec(), Value::Integer(length));
+            let path = path.iter().map(|c| Value::Bytes(c.as_bytes().into()));
+            file.insert(b"path".to_vec(), Value::List(path.collect()));
+            Value::Dictionary(file)
+        };
+        let multi_file = |piece_length, pieces, files| {
+            let mut map = metainfo_value(1, piece_length, pieces)
+                .into_dictionary()
+                .unwrap();
+            let Some(Value::Dictionary(info)) = map.get_mut(b"info".as_slice()) else {
+                unreachable!();
+            };
+            info.remove(b"length".as_slice());
+            info.insert(b"files".to_vec(), Value::List(files));
+            Metainfo::decode(Value::Dictionary(map)).unwrap()
+        };
+        let files = vec![file(3, &["a.txt"]), file(4, &["sub", "b.txt"])];
+        let metainfo = multi_file(4, 40, files);
+
+        assert_eq!(
+            metainfo.info().mode(),
+            &MetainfoMode::MultiFile {
+                files: vec![
+                    FileEntry {
+                        length: 3,
+                        path: vec!["a.txt".into()]
+                    },
+                    FileEntry {
+                        length: 4,
+                        path: vec!["sub".into(), "b.txt".into()]
+                    },
+                ]
+            }
+        );
+        assert_eq!(metainfo.info().total_length(), 7);
+        assert_eq!(metainfo.info().length(), 7);
+        assert!(metainfo.validate().is_empty());
+
+        // Files adding up to more than 4 GiB
+        let gib = 1 << 30;
+        let files = vec![file(3 * gib, &["a.bin"]), file(3 * gib + 5, &["b.bin"])];
+        let metainfo = multi_file(gib, 7 * 20, files);
+        let info = metainfo.info();
+        assert_eq!(info.length(), 6 * (1 << 30) + 5);
+        assert_eq!(info.piece_len(5), 1 << 30);
+        assert_eq!(info.piece_len(6), 5);
+        assert_eq!(info.pieces().last().unwrap().offset(), 6 * (1 << 30));
+        assert_eq!(info.pieces_covering(5 << 30..(6 << 30) + 1), 5..=6);
+        assert!(metainfo.validate().is_empty());
+    }
+
+    #[test]
+    fn test_non_utf8_keys() {
+        let mut map = metainfo_value(1, 1, 20).into_dictionary().unwrap();
+        let Some(Value::Dictionary(info)) = map.get_mut(b"info".as_slice()) else {
+            unreachable!();
+        };
+        info.insert(vec![0xff, 0xfe], Value::Integer(1));
+        let info_buf = encode_bencoded_value(&Value::Dictionary(info.clone()));
+        let encoded = encode_bencoded_value(&Value::Dictionary(map));
+
+        let (value, _) = try_decode_bencoded_value(&encoded).unwrap();
+        assert!(value.to_string().contains('\u{fffd}'));
+        let metainfo = Metainfo::decode(value).unwrap();
+        assert_eq!(metainfo.encode(), encoded);
+        use sha1::Digest;
+        let hash = InfoHash::new(sha1::Sha1::digest(&info_buf).into());
+        assert_eq!(metainfo.info().hash(), &hash);
+    }
+
+    #[test]
+    fn test_trackers() {
+        assert_eq!(metainfo(1, 1, 20).trackers(), &["http://tracker"]);
+
+        let mut map = metainfo_value(1, 1, 20).into_dictionary().unwrap();
+        let tier = |urls: &[&str]| {
+            Value::List(
+                urls.iter()
+                    .map(|url| Value::Bytes(url.as_bytes().into()))
+                    .collect(),
+            )
+        };
+        map.insert(
+            b"announce-list".to_vec(),
+            Value::List(vec![
+                tier(&["http://a", "http://tracker"]),
+                tier(&[]),
+                tier(&["http://b", "http://a"]),
+            ]),
+        );
+        let metainfo = Metainfo::decode(Value::Dictionary(map)).unwrap();
+        assert_eq!(metainfo.announce_list().len(), 2);
+        assert_eq!(
+            metainfo.trackers(),
+            &["http://tracker", "http://a", "http://b"]
+        );
+    }
+
+    #[test]
+    fn test_escaped() {
+        let value = decode_bencoded_value_exact(
+            b"d1:\"l7:say \"#\"3:\xffa\xfe5:\\x41\n3:\xc3\xa9\xc3i-1ee1:#0:e",
+        )
+        .unwrap();
+        assert_eq!(
+            value.escaped().to_string(),
+            r##"{"\"":["say \"#\"","\xffa\xfe","\\x41\n","é\xc3",-1],"#":""}"##
+        );
+
+        // The lossy form cannot tell these apart
+        let replaced = Value::Bytes("\u{fffd}".into());
+        let invalid = Value::Bytes(vec![0xff]);
+        assert_eq!(replaced.to_string(), invalid.to_string());
+        assert_ne!(
+            replaced.escaped().to_string(),
+            invalid.escaped().to_string()
+        );
+    }
+
+    #[test]
+    fn test_to_json_value() {
+        let value = decode_bencoded_value_exact(
+            b"d2:fei1e4:listli1e5:helloli-2eee4:name4:spam3:raw2:\xff\x001:\xfei0ee",
+        )
+        .unwrap();
+        assert_eq!(
+            to_json_value(&value),
+            serde_json::json!({
+                "fe": 1,
+                "hex:fe": 0,
+                "list": [1, "hello", [-2]],
+                "name": "spam",
+                "raw": "hex:ff00",
+            })
+        );
+        assert_eq!(
+            serde_json::to_string(&to_json_value(&value)).unwrap(),
+            r#"{"fe":1,"hex:fe":0,"list":[1,"hello",[-2]],"name":"spam","raw":"hex:ff00"}"#
+        );
+
+        // Neither strings that look hex encoded nor dictionaries that look like bytes are
+        // mistaken for them
+        let value = decode_bencoded_value_exact(b"l6:hex:ffd3:hex4:ff00ee").unwrap();
+        assert_eq!(
+            to_json_value(&value),
+            serde_json::json!(["hex:6865783a6666", { "hex": "ff00" }])
+        );
+    }
+
+    #[test]
+    fn test_announce_list_tiers() {
+        let mut map = metainfo_value(1, 1, 20).into_dictionary().unwrap();
+        let announce_list =
+            decode_bencoded_value_exact(b"ll14:http://primary13:udp://primaryel13:http://backupee")
+                .unwrap();
+        map.insert(b"announce-list".to_vec(), announce_list);
+        let encoded = encode_bencoded_value(&Value::Dictionary(map));
+
+        let metainfo = Metainfo::decode_bytes(&encoded).unwrap();
+        assert_eq!(
+            metainfo.announce_list(),
+            &[
+                vec!["http://primary".to_owned(), "udp://primary".to_owned()],
+                vec!["http://backup".to_owned()],
+            ]
+        );
+        assert_eq!(
+            metainfo.trackers(),
+            &[
+                "http://tracker",
+                "http://primary",
+                "udp://primary",
+                "http://backup"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pieces_covering() {
+        let info = metainfo(100, 16, 7 * 20).info;
+        assert_eq!(info.pieces_covering(0..1), 0..=0);
+        assert_eq!(info.pieces_covering(0..16), 0..=0);
+        assert_eq!(info.pieces_covering(0..17), 0..=1);
+        assert_eq!(info.pieces_covering(15..33), 0..=2);
+        assert_eq!(info.pieces_covering(16..32), 1..=1);
+        assert_eq!(info.pieces_covering(90..100), 5..=6);
+        assert_eq!(info.pieces_covering(90..1000), 5..=6);
+        assert!(info.pieces_covering(20..20).is_empty());
+        assert!(info.pieces_covering(100..200).is_empty());
+        assert_eq!(
+            info.pieces_covering(u64::MAX - 1..u64::MAX),
+            RangeInclusive::new(1, 0)
+        );
+
+        assert_eq!(
+            metainfo(100, 0, 20).info.pieces_covering(0..10),
+            RangeInclusive::new(1, 0)
+        );
+
+        // Piece indices run out before the content does
+        let info = metainfo(1 << 33, 1, 20).info;
+        let last = u64::from(u32::MAX);
+        assert_eq!(info.pieces_covering(last..last + 1), u32::MAX..=u32::MAX);
+        assert_eq!(info.pieces_covering(last..last + 10), u32::MAX..=u32::MAX);
+        assert_eq!(
+            info.pieces_covering(last + 1..last + 2),
+            RangeInclusive::new(1, 0)
+        );
+    }
+
+    #[test]
+    fn test_piece_len() {
+        let info = metainfo(2 * 16 + 1, 16, 3 * 20).info;
+        assert_eq!(info.piece_len(0), 16);
+        assert_eq!(info.piece_len(1), 16);
+        assert_eq!(info.piece_len(2), 1);
+        assert_eq!(metainfo(32, 16, 2 * 20).info.piece_len(1), 16);
+        assert_eq!(info.piece_len(3), 0);
+        assert_eq!(info.piece_len(u32::MAX), 0);
+    }
+
+    #[test]
+    fn test_pieces() {
+        let buf = std::fs::read("sample.torrent").unwrap();
+        let sample = Metainfo::decode_bytes(&buf).unwrap();
+        let info = sample.info();
+        assert_ne!(info.length() % u64::from(info.piece_length()), 0);
+        let pieces = info.pieces().collect::<Vec<_>>();
+        assert_eq!(pieces.len(), info.piece_hashes().count());
+        let mut offset = 0;
+        for (piece, hash) in pieces.iter().zip(info.piece_hashes()) {
+            assert_eq!(piece.offset(), offset);
+            assert_eq!(piece.length(), info.piece_len(piece.index()));
+            assert_eq!(piece.hash(), hash);
+            offset += u64::from(piece.length());
+        }
+        assert_eq!(offset, info.length());
+        let last = pieces.last().unwrap();
+        assert_eq!(
+            u64::from(last.length()),
+            info.length() % u64::from(info.piece_length())
+        );
+
+        // More hashes than the content needs
+        let info = metainfo(17, 16, 3 * 20).info;
+        let lengths = info
+            .pieces()
+            .map(|piece| piece.length())
+            .collect::<Vec<_>>();
+        assert_eq!(lengths, &[16, 1, 0]);
+    }
+
+    #[test]
+    fn test_validate() {
+        let buf = std::fs::read("sample.torrent").unwrap();
+        let (value, _) = decode_bencoded_value(&buf);
+        assert_eq!(Metainfo::decode(value).unwrap().validate(), vec![]);
+
+        // The last piece holds a single byte
+        assert_eq!(metainfo(2 * 16 + 1, 16, 3 * 20).validate(), vec![]);
+        assert_eq!(metainfo(3 * 16, 16, 3 * 20).validate(), vec![]);
+
+        // Truncated content
+        assert_eq!(
+            metainfo(2 * 16, 16, 3 * 20).validate(),
+            vec![MetainfoWarning::LastPieceLengthMismatch {
+                total_length: 32,
+                piece_length: 16,
+                piece_count: 3,
+            }]
+        );
+        // Missing pieces
+        assert_eq!(
+            metainfo(3 * 16 + 1, 16, 3 * 20).validate(),
+            vec![MetainfoWarning::LastPieceLengthMismatch {
+                total_length: 49,
+                piece_length: 16,
+                piece_count: 3,
+            }]
+        );
+        assert_eq!(
+            metainfo(3 * 16, 16, 3 * 20 - 1).validate(),
+            vec![MetainfoWarning::TruncatedPieceHash { pieces_length: 59 }]
+        );
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize)]
@@ -186,7 +1831,7 @@ pub enum Value {
     Bytes(Vec<u8>),
     Integer(i64),
     List(Vec<Self>),
-    Dictionary(BTreeMap<String, Self>),
+    Dictionary(BTreeMap<Vec<u8>, Self>),
 }
 
 impl Value {
@@ -211,14 +1856,107 @@ impl Value {
         Some(list)
     }
 
-    pub fn into_dictionary(self) -> Option<BTreeMap<String, Self>> {
+    pub fn into_dictionary(self) -> Option<BTreeMap<Vec<u8>, Self>> {
         let Self::Dictionary(dictionary) = self else {
             return None;
         };
         Some(dictionary)
     }
+
+    /// Formats the value like [`Display`](fmt::Display) but with byte strings quoted and escaped
+    /// so that distinct values never look the same.
+    ///
+    /// Quotes, backslashes and control characters are escaped as in Rust string literals, and
+    /// every byte that is not part of valid UTF-8 is written as `\xNN`.
+    pub fn escaped(&self) -> EscapedValue<'_> {
+        EscapedValue(self)
+    }
+}
+
+/// See [`Value::escaped`].
+pub struct EscapedValue<'a>(&'a Value);
+
+impl fmt::Display for EscapedValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Value::Bytes(bytes) => write_escaped(f, bytes),
+            Value::Integer(integer) => write!(f, "{integer}"),
+            Value::List(list) => {
+                write!(f, "[")?;
+                for (i, element) in list.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", element.escaped())?;
+                }
+                write!(f, "]")
+            }
+            Value::Dictionary(dictionary) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in dictionary.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_escaped(f, key)?;
+                    write!(f, ":{}", value.escaped())?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_escaped(f: &mut fmt::Formatter<'_>, mut bytes: &[u8]) -> fmt::Result {
+    write!(f, "\"")?;
+    loop {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => {
+                write!(f, "{}", text.escape_debug())?;
+                break;
+            }
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                let text = std::str::from_utf8(valid).unwrap();
+                write!(f, "{}", text.escape_debug())?;
+                let (invalid, rest) = rest.split_at(e.error_len().unwrap_or(rest.len()));
+                for byte in invalid {
+                    write!(f, "\\x{byte:02x}")?;
+                }
+                bytes = rest;
+            }
+        }
+    }
+    write!(f, "\"")
+}
+
+/// Marks a JSON string holding a hex encoded byte string, see [`to_json_value`]
+pub const JSON_HEX_PREFIX: &str = "hex:";
+
+/// Converts `value` to plain JSON, with byte strings, including dictionary keys, as strings.
+///
+/// Byte strings that are not UTF-8, or that start with [`JSON_HEX_PREFIX`], are hex encoded
+/// behind that prefix, so that every JSON string maps back to exactly one byte string.
+pub fn to_json_value(value: &Value) -> serde_json::Value {
+    fn json_string(bytes: &[u8]) -> String {
+        match std::str::from_utf8(bytes) {
+            Ok(text) if !text.starts_with(JSON_HEX_PREFIX) => text.to_owned(),
+            _ => format!("{JSON_HEX_PREFIX}{}", hex::encode(bytes)),
+        }
+    }
+    match value {
+        Value::Bytes(bytes) => serde_json::Value::String(json_string(bytes)),
+        Value::Integer(integer) => serde_json::Value::from(*integer),
+        Value::List(list) => list.iter().map(to_json_value).collect(),
+        Value::Dictionary(dictionary) => dictionary
+            .iter()
+            .map(|(key, value)| (json_string(key), to_json_value(value)))
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+    }
 }
 
+/// Shows byte strings as `r#"..."#` with invalid UTF-8 replaced, which is lossy and cannot be
+/// parsed back; see [`Value::escaped`] for an unambiguous form.
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         // fn fmt_value(v: &Value, f: &mut fmt::Formatter<'_>, indents: usize) -> fmt::Result {}
@@ -238,7 +1976,7 @@ impl fmt::Display for Value {
             Value::Dictionary(dictionary) => {
                 write!(f, "{{")?;
                 for (i, (key, value)) in dictionary.iter().enumerate() {
-                    write!(f, "\"{key}\":{value}")?;
+                    write!(f, "\"{}\":{value}", String::from_utf8_lossy(key))?;
                     if i + 1 < dictionary.len() {
                         write!(f, ",")?;
                     }
@@ -250,86 +1988,703 @@ impl fmt::Display for Value {
     }
 }
 
-#[derive(Debug, Getters)]
+#[derive(Debug, Clone, Getters)]
 pub struct Metainfo {
     #[getset(get = "pub")]
     announce: String,
+    /// Tiers of tracker URLs (BEP 12)
+    #[getset(get = "pub")]
+    announce_list: Vec<Vec<String>>,
     #[getset(get = "pub")]
     info: MetainfoInfo,
+    #[getset(get = "pub")]
+    encoding: Option<String>,
+    piece_layers: Option<BTreeMap<Vec<u8>, Vec<u8>>>,
+    raw: BTreeMap<Vec<u8>, Value>,
+}
+
+/// The top-level keys of a torrent file that [`Metainfo`] parses.
+#[derive(Deserialize)]
+struct MetainfoFields {
+    announce: String,
+    /// Kept undecoded so that malformed tiers can be skipped
+    #[serde(rename = "announce-list")]
+    announce_list: Option<Value>,
+    encoding: Option<serde_bytes::ByteBuf>,
+    info: Value,
+    #[serde(rename = "piece layers")]
+    piece_layers: Option<BTreeMap<serde_bytes::ByteBuf, serde_bytes::ByteBuf>>,
+}
+
+/// The keys of an info dictionary that [`MetainfoInfo`] parses.
+#[derive(Deserialize)]
+struct MetainfoInfoFields {
+    name: Option<serde_bytes::ByteBuf>,
+    #[serde(rename = "name.utf-8")]
+    name_utf8: Option<serde_bytes::ByteBuf>,
+    #[serde(rename = "piece length")]
+    piece_length: u32,
+    pieces: serde_bytes::ByteBuf,
+    length: Option<u64>,
+    files: Option<Vec<FileEntryFields>>,
+}
+
+#[derive(Deserialize)]
+struct FileEntryFields {
+    length: u64,
+    path: Option<Vec<serde_bytes::ByteBuf>>,
+    #[serde(rename = "path.utf-8")]
+    path_utf8: Option<Vec<serde_bytes::ByteBuf>>,
 }
 
 impl Metainfo {
-    pub fn decode(value: Value) -> Self {
-        let mut value = value.into_dictionary().unwrap();
-        let announce =
-            String::from_utf8(value.remove("announce").unwrap().into_bytes().unwrap()).unwrap();
-        let info = MetainfoInfo::decode(value.remove("info").unwrap());
-        Self { announce, info }
+    pub fn decode(value: Value) -> Result<Self, DeError> {
+        // Everything but the info dictionary, kept for re-encoding
+        let Value::Dictionary(mut raw) = value.clone() else {
+            return Err(DeError::Message("torrent is not a dictionary".into()));
+        };
+        raw.remove(b"info".as_slice());
+        let fields: MetainfoFields = de::from_value(value)?;
+        let announce_list = fields
+            .announce_list
+            .and_then(|announce_list| announce_list.into_list())
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|tier| {
+                let tier = tier
+                    .into_list()?
+                    .into_iter()
+                    .filter_map(|url| String::from_utf8(url.into_bytes()?).ok())
+                    .collect::<Vec<_>>();
+                (!tier.is_empty()).then_some(tier)
+            })
+            .collect();
+        let encoding = fields
+            .encoding
+            .map(|encoding| String::from_utf8_lossy(&encoding).into_owned());
+        let info = MetainfoInfo::decode_with_encoding(fields.info, encoding.as_deref())?;
+        let piece_layers = fields.piece_layers.map(|piece_layers| {
+            piece_layers
+                .into_iter()
+                .map(|(root, layer)| (root.into_vec(), layer.into_vec()))
+                .collect()
+        });
+        Ok(Self {
+            announce: fields.announce,
+            announce_list,
+            info,
+            encoding,
+            piece_layers,
+            raw,
+        })
+    }
+
+    /// Decodes a torrent file, hashing the info dictionary exactly as it appears in `buf` even if
+    /// its keys are not in canonical order.
+    pub fn decode_bytes(buf: &[u8]) -> Result<Self, DeError> {
+        let (value, _) = try_decode_bencoded_value(buf)?;
+        let mut metainfo = Self::decode(value)?;
+        if let Some(span) = dictionary_value_span(buf, b"info")? {
+            metainfo.info.set_encoded(buf[span].to_vec());
+        }
+        Ok(metainfo)
+    }
+
+    /// Bencodes the torrent, including the keys this type does not parse.
+    ///
+    /// An info dictionary that has not been edited since [`Self::decode_bytes`] is written back
+    /// byte for byte.
+    pub fn encode(&self) -> Vec<u8> {
+        let Some(info) = &self.info.encoded else {
+            let mut map = self.raw.clone();
+            map.insert(b"info".to_vec(), Value::Dictionary(self.info.raw.clone()));
+            return encode_bencoded_value(&Value::Dictionary(map));
+        };
+        let mut encoded_value = vec![b'd'];
+        let mut info = Some(info);
+        for (key, value) in &self.raw {
+            if key.as_slice() > b"info".as_slice() {
+                if let Some(info) = info.take() {
+                    encode_bytes_into(b"info", &mut encoded_value);
+                    encoded_value.extend(info);
+                }
+            }
+            encode_bytes_into(key, &mut encoded_value);
+            encode_bencoded_value_into(value, &mut encoded_value);
+        }
+        if let Some(info) = info {
+            encode_bytes_into(b"info", &mut encoded_value);
+            encoded_value.extend(info);
+        }
+        encoded_value.push(b'e');
+        encoded_value
+    }
+
+    pub fn set_announce(&mut self, announce: String) {
+        self.raw.insert(
+            b"announce".to_vec(),
+            Value::Bytes(announce.clone().into_bytes()),
+        );
+        self.announce = announce;
+    }
+
+    pub fn info_mut(&mut self) -> &mut MetainfoInfo {
+        &mut self.info
+    }
+
+    /// Re-hashes the info dictionary after it has been edited.
+    pub fn recompute_info_hash(&mut self) {
+        self.info.recompute_hash();
+    }
+
+    /// Lists `announce` followed by every other tracker of the announce list, tier by tier, each
+    /// tracker once.
+    pub fn trackers(&self) -> Vec<&str> {
+        let mut trackers = vec![self.announce.as_str()];
+        for tracker in self.announce_list.iter().flatten() {
+            if !trackers.contains(&tracker.as_str()) {
+                trackers.push(tracker);
+            }
+        }
+        trackers
+    }
+
+    /// Maps each file's merkle root to the concatenated hashes of its piece layer in v2 and
+    /// hybrid torrents (BEP 52).
+    pub fn piece_layers(&self) -> Option<&BTreeMap<Vec<u8>, Vec<u8>>> {
+        self.piece_layers.as_ref()
+    }
+
+    /// Cross-checks the declared length against the piece count, catching truncated or tampered
+    /// torrents before a download starts.
+    pub fn validate(&self) -> Vec<MetainfoWarning> {
+        let mut warnings = vec![];
+        if self.info.pieces.len() % 20 != 0 {
+            warnings.push(MetainfoWarning::TruncatedPieceHash {
+                pieces_length: self.info.pieces.len(),
+            });
+        }
+
+        let total_length = self.info.length();
+        let piece_length = u64::from(self.info.piece_length());
+        let piece_count = self.info.piece_hashes().count() as u64;
+        // Every piece but the last is full, and the last one holds at least a byte
+        let min_length = piece_count.saturating_sub(1) * piece_length + 1;
+        let max_length = piece_count * piece_length;
+        if piece_length == 0 || !(min_length..=max_length).contains(&total_length) {
+            warnings.push(MetainfoWarning::LastPieceLengthMismatch {
+                total_length,
+                piece_length,
+                piece_count,
+            });
+        }
+        warnings
     }
 }
 
-#[derive(Debug, Getters, CopyGetters)]
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum MetainfoWarning {
+    #[error("`pieces` is {pieces_length} bytes long, which is not a multiple of 20")]
+    TruncatedPieceHash { pieces_length: usize },
+    #[error(
+        "total length {total_length} does not fit in {piece_count} pieces of {piece_length} bytes"
+    )]
+    LastPieceLengthMismatch {
+        total_length: u64,
+        piece_length: u64,
+        piece_count: u64,
+    },
+}
+
+#[derive(Debug, Clone, Getters, CopyGetters)]
 pub struct MetainfoInfo {
+    /// Length of the whole content, summed over the files of a multi-file torrent
     #[getset(get_copy = "pub")]
-    length: u32,
+    length: u64,
+    #[getset(get = "pub")]
+    mode: MetainfoMode,
     #[getset(get = "pub")]
     name: String,
     #[getset(get_copy = "pub")]
     piece_length: u32,
     pieces: Vec<u8>,
     #[getset(get = "pub")]
-    hash: [u8; 20],
+    hash: InfoHash,
+    raw: BTreeMap<Vec<u8>, Value>,
+    /// The dictionary as read from the torrent file, until it is edited
+    encoded: Option<Vec<u8>>,
 }
 
 impl MetainfoInfo {
-    pub fn decode(value: Value) -> Self {
-        let bencoded = encode_bencoded_value(&value);
-        use sha1::Digest;
-        let mut hasher = sha1::Sha1::new();
-        hasher.update(&bencoded);
-        let hash = hasher.finalize().into();
-
-        let mut value = value.into_dictionary().unwrap();
-        let length = value.remove("length").unwrap().into_integer().unwrap();
-        let name = String::from_utf8(value.remove("name").unwrap().into_bytes().unwrap()).unwrap();
-        let piece_length = value
-            .remove("piece length")
-            .unwrap()
-            .into_integer()
-            .unwrap();
-        let pieces = value.remove("pieces").unwrap().into_bytes().unwrap();
-        Self {
-            length: u32::try_from(length).unwrap(),
+    pub fn decode(value: Value) -> Result<Self, DeError> {
+        Self::decode_with_encoding(value, None)
+    }
+
+    /// Decodes the info dictionary, reading text fields in the torrent's declared `encoding`
+    /// unless their `.utf-8` variants are present.
+    pub fn decode_with_encoding(value: Value, encoding: Option<&str>) -> Result<Self, DeError> {
+        let Value::Dictionary(raw) = value else {
+            return Err(DeError::Message("info is not a dictionary".into()));
+        };
+        let hash = info_hash(&raw);
+        let fields: MetainfoInfoFields = de::from_value(Value::Dictionary(raw.clone()))?;
+        let mode = match (fields.files, fields.length) {
+            (Some(files), _) => MetainfoMode::MultiFile {
+                files: files
+                    .into_iter()
+                    .map(|file| FileEntry::decode(file, encoding))
+                    .collect::<Result<_, _>>()?,
+            },
+            (None, Some(length)) => MetainfoMode::SingleFile {
+                length: usize_length(length)?,
+            },
+            (None, None) => return Err(serde::de::Error::missing_field("length")),
+        };
+        let length = mode.total_length() as u64;
+        let name = match (fields.name_utf8, fields.name) {
+            (Some(name), _) => decode_text(name.into_vec(), None),
+            (None, Some(name)) => decode_text(name.into_vec(), encoding),
+            (None, None) => return Err(serde::de::Error::missing_field("name")),
+        };
+        Ok(Self {
+            length,
+            mode,
             name,
-            piece_length: u32::try_from(piece_length).unwrap(),
-            pieces,
+            piece_length: fields.piece_length,
+            pieces: fields.pieces.into_vec(),
             hash,
+            raw,
+            encoded: None,
+        })
+    }
+
+    /// Takes the hash over `encoded`, the source bytes this dictionary was decoded from.
+    fn set_encoded(&mut self, encoded: Vec<u8>) {
+        use sha1::Digest;
+        self.hash = InfoHash(sha1::Sha1::digest(&encoded).into());
+        self.encoded = Some(encoded);
+    }
+
+    pub fn set_name(&mut self, name: String) {
+        self.encoded = None;
+        self.raw.remove(b"name.utf-8".as_slice());
+        self.raw
+            .insert(b"name".to_vec(), Value::Bytes(name.clone().into_bytes()));
+        self.name = name;
+    }
+
+    /// Updates [`Self::hash`], re-bencoding the info dictionary canonically if it has been edited
+    /// since it was decoded.
+    pub fn recompute_hash(&mut self) {
+        if self.encoded.is_none() {
+            self.hash = info_hash(&self.raw);
         }
     }
 
+    pub fn total_length(&self) -> usize {
+        self.mode.total_length()
+    }
+
+    /// How many bytes are still missing after `downloaded`, as announced in `left`.
+    pub fn bytes_left(&self, downloaded: u64) -> u64 {
+        self.length.saturating_sub(downloaded)
+    }
+
+    /// Returns the length of the piece at `index`, which is shorter than
+    /// [`Self::piece_length`] for the last piece unless the content fills it exactly, and zero
+    /// past the end of the content.
+    pub fn piece_len(&self, index: u32) -> u32 {
+        let offset = u64::from(self.piece_length) * u64::from(index);
+        let remaining = self.length.saturating_sub(offset);
+        self.piece_length.min(remaining as u32)
+    }
+
     pub fn piece_hashes(&self) -> impl Iterator<Item = &[u8]> {
         self.pieces.chunks(20)
     }
+
+    /// Lists every piece with where it lies in the content, one per piece hash.
+    ///
+    /// Pieces past the end of the content, which only a malformed torrent has, are empty.
+    pub fn pieces(&self) -> impl Iterator<Item = PieceInfo<'_>> {
+        let piece_length = u64::from(self.piece_length);
+        let total = self.length;
+        self.piece_hashes().enumerate().map(move |(index, hash)| {
+            let offset = index as u64 * piece_length;
+            PieceInfo {
+                index: u32::try_from(index).unwrap(),
+                offset,
+                length: total.saturating_sub(offset).min(piece_length) as u32,
+                hash,
+            }
+        })
+    }
+
+    /// Returns the indices of the pieces holding any byte of `range`.
+    ///
+    /// The range is clamped to the content length. A range that is empty after clamping, or a
+    /// zero piece length, maps to `1..=0`.
+    pub fn pieces_covering(&self, range: Range<u64>) -> RangeInclusive<u32> {
+        let piece_length = u64::from(self.piece_length);
+        let end = range.end.min(self.length);
+        let empty = RangeInclusive::new(1, 0);
+        if piece_length == 0 || range.start >= end {
+            return empty;
+        }
+        // Indices past `u32::MAX` cannot be requested, so they are not covered either
+        let Ok(first) = u32::try_from(range.start / piece_length) else {
+            return empty;
+        };
+        let last = u32::try_from((end - 1) / piece_length).unwrap_or(u32::MAX);
+        first..=last
+    }
+}
+
+/// A piece of the content, as listed by [`MetainfoInfo::pieces`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, CopyGetters)]
+#[getset(get_copy = "pub")]
+pub struct PieceInfo<'info> {
+    index: u32,
+    /// Offset of the piece's first byte in the content
+    offset: u64,
+    length: u32,
+    /// SHA-1 of the piece, shorter than 20 bytes if the torrent's `pieces` is truncated
+    hash: &'info [u8],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetainfoMode {
+    SingleFile {
+        length: usize,
+    },
+    /// The files are laid out back to back, in order, inside a directory named after the torrent
+    MultiFile {
+        files: Vec<FileEntry>,
+    },
+}
+
+impl MetainfoMode {
+    pub fn total_length(&self) -> usize {
+        match self {
+            Self::SingleFile { length } => *length,
+            Self::MultiFile { files } => files.iter().map(|file| file.length).sum(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    pub length: usize,
+    /// Path components relative to the torrent's directory
+    pub path: Vec<String>,
+}
+
+impl FileEntry {
+    fn decode(fields: FileEntryFields, encoding: Option<&str>) -> Result<Self, DeError> {
+        // Like `name`, a `.utf-8` variant overrides the declared encoding
+        let (path, encoding) = match (fields.path_utf8, fields.path) {
+            (Some(path), _) => (path, None),
+            (None, Some(path)) => (path, encoding),
+            (None, None) => return Err(serde::de::Error::missing_field("path")),
+        };
+        let path = path
+            .into_iter()
+            .map(|component| decode_text(component.into_vec(), encoding))
+            .collect();
+        Ok(Self {
+            length: usize_length(fields.length)?,
+            path,
+        })
+    }
+}
+
+fn usize_length(length: u64) -> Result<usize, DeError> {
+    usize::try_from(length)
+        .map_err(|_| DeError::Message(format!("length {length} does not fit in memory")))
+}
+
+fn info_hash(info: &BTreeMap<Vec<u8>, Value>) -> InfoHash {
+    // Encoded in place rather than cloned into a `Value` first, as it holds every piece hash
+    let mut bencoded = vec![b'd'];
+    for (key, value) in info {
+        encode_bytes_into(key, &mut bencoded);
+        encode_bencoded_value_into(value, &mut bencoded);
+    }
+    bencoded.push(b'e');
+    use sha1::Digest;
+    let mut hasher = sha1::Sha1::new();
+    hasher.update(&bencoded);
+    InfoHash(hasher.finalize().into())
+}
+
+/// SHA-1 of the bencoded info dictionary, which identifies a torrent to trackers and peers.
+///
+/// Displays as 40 lowercase hex characters and parses back from them in either case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct InfoHash([u8; 20]);
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum InfoHashError {
+    #[error("info hash {0:?} is not 40 hex characters")]
+    InvalidHex(String),
+    #[error("info hash {0:?} is not 32 base32 characters")]
+    InvalidBase32(String),
+    #[error("info hash {0:?} is neither 40 hex nor 32 base32 characters")]
+    InvalidLength(String),
+}
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+impl InfoHash {
+    pub const fn new(bytes: [u8; 20]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    /// Formats the hash as 32 unpadded RFC 4648 base32 characters, as some magnet links carry it.
+    pub fn to_base32(&self) -> String {
+        let mut encoded = String::with_capacity(32);
+        let mut bits = 0_u16;
+        let mut bit_count = 0;
+        for &byte in &self.0 {
+            bits = (bits << 8) | u16::from(byte);
+            bit_count += 8;
+            while bit_count >= 5 {
+                bit_count -= 5;
+                encoded.push(BASE32_ALPHABET[usize::from((bits >> bit_count) & 0x1f)] as char);
+            }
+        }
+        encoded
+    }
+
+    /// Parses 32 unpadded base32 characters in either case.
+    pub fn from_base32(text: &str) -> Result<Self, InfoHashError> {
+        let invalid = || InfoHashError::InvalidBase32(text.to_owned());
+        if text.len() != 32 {
+            return Err(invalid());
+        }
+        let mut decoded = [0; 20];
+        let mut bits = 0_u16;
+        let mut bit_count = 0;
+        let mut len = 0;
+        for c in text.bytes() {
+            let value = match c.to_ascii_uppercase() {
+                c @ b'A'..=b'Z' => c - b'A',
+                c @ b'2'..=b'7' => c - b'2' + 26,
+                _ => return Err(invalid()),
+            };
+            bits = (bits << 5) | u16::from(value);
+            bit_count += 5;
+            if bit_count >= 8 {
+                bit_count -= 8;
+                decoded[len] = (bits >> bit_count) as u8;
+                len += 1;
+            }
+        }
+        Ok(Self(decoded))
+    }
+}
+
+impl From<[u8; 20]> for InfoHash {
+    fn from(bytes: [u8; 20]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl AsRef<[u8]> for InfoHash {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for InfoHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl std::str::FromStr for InfoHash {
+    type Err = InfoHashError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut decoded = [0; 20];
+        hex::decode_to_slice(text, &mut decoded)
+            .map_err(|_| InfoHashError::InvalidHex(text.to_owned()))?;
+        Ok(Self(decoded))
+    }
+}
+
+/// Parses an info hash written either as 40 hex characters or as 32 base32 characters, the two
+/// forms magnet links use, in either case.
+pub fn parse_info_hash(text: &str) -> Result<InfoHash, InfoHashError> {
+    match text.len() {
+        40 => text.parse(),
+        32 => InfoHash::from_base32(text),
+        _ => Err(InfoHashError::InvalidLength(text.to_owned())),
+    }
+}
+
+/// Transcodes `text` from `encoding` to UTF-8, falling back to a lossy UTF-8 read when the
+/// encoding is unknown or the `encoding` feature is disabled.
+fn decode_text(text: Vec<u8>, encoding: Option<&str>) -> String {
+    #[cfg(feature = "encoding")]
+    if let Some(encoding) =
+        encoding.and_then(|label| encoding_rs::Encoding::for_label(label.as_bytes()))
+    {
+        let (text, _, _) = encoding.decode(&text);
+        return text.into_owned();
+    }
+    #[cfg(not(feature = "encoding"))]
+    let _ = encoding;
+    match String::from_utf8(text) {
+        Ok(text) => text,
+        Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+    }
 }
 
+/// Port announced by [`TrackerRequest::builder`] unless set otherwise
+pub const DEFAULT_PORT: u16 = 6881;
+
 pub struct TrackerRequest<'caller> {
-    pub info_hash: &'caller [u8],
+    pub info_hash: &'caller InfoHash,
     pub peer_id: &'caller [u8],
     pub port: u16,
     pub uploaded: u64,
     pub downloaded: u64,
     pub left: u64,
     pub compact: bool,
+    /// Echoes the `tracker id` from a previous announce
+    pub tracker_id: Option<&'caller str>,
+    /// Bytes received that failed the hash check
+    pub corrupt: Option<u64>,
+    /// Bytes received that were already downloaded
+    pub redundant: Option<u64>,
+    pub event: Option<TrackerEvent>,
+}
+
+pub struct TrackerRequestBuilder<'caller>(TrackerRequest<'caller>);
+
+impl<'caller> TrackerRequestBuilder<'caller> {
+    pub fn port(mut self, port: u16) -> Self {
+        self.0.port = port;
+        self
+    }
+
+    pub fn uploaded(mut self, uploaded: u64) -> Self {
+        self.0.uploaded = uploaded;
+        self
+    }
+
+    pub fn downloaded(mut self, downloaded: u64) -> Self {
+        self.0.downloaded = downloaded;
+        self
+    }
+
+    /// Defaults to the whole content; usually [`MetainfoInfo::bytes_left`] of the downloaded bytes
+    pub fn left(mut self, left: u64) -> Self {
+        self.0.left = left;
+        self
+    }
+
+    pub fn compact(mut self, compact: bool) -> Self {
+        self.0.compact = compact;
+        self
+    }
+
+    pub fn tracker_id(mut self, tracker_id: &'caller str) -> Self {
+        self.0.tracker_id = Some(tracker_id);
+        self
+    }
+
+    pub fn corrupt(mut self, corrupt: u64) -> Self {
+        self.0.corrupt = Some(corrupt);
+        self
+    }
+
+    pub fn redundant(mut self, redundant: u64) -> Self {
+        self.0.redundant = Some(redundant);
+        self
+    }
+
+    pub fn event(mut self, event: TrackerEvent) -> Self {
+        self.0.event = Some(event);
+        self
+    }
+
+    pub fn build(self) -> TrackerRequest<'caller> {
+        self.0
+    }
+}
+
+/// Marks the announces at the start and end of a download, left out of regular re-announces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackerEvent {
+    Started,
+    Stopped,
+    Completed,
+}
+
+impl TrackerEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Started => "started",
+            Self::Stopped => "stopped",
+            Self::Completed => "completed",
+        }
+    }
 }
 
 impl<'a> TrackerRequest<'a> {
+    /// Starts a request for a client on [`DEFAULT_PORT`] that has transferred nothing, asking
+    /// for compact peers, with `left` at zero until set.
+    /// Starts a request for the torrent described by `info`, announcing that nothing has been
+    /// transferred yet.
+    pub fn builder(info: &'a MetainfoInfo, peer_id: &'a [u8]) -> TrackerRequestBuilder<'a> {
+        TrackerRequestBuilder(TrackerRequest {
+            info_hash: info.hash(),
+            peer_id,
+            port: DEFAULT_PORT,
+            uploaded: 0,
+            downloaded: 0,
+            left: info.bytes_left(0),
+            compact: true,
+            tracker_id: None,
+            corrupt: None,
+            redundant: None,
+            event: None,
+        })
+    }
+
     pub fn url(&'a self, metainfo: &'a Metainfo) -> String {
-        let url_encoded_info_hash = urlencoding::encode_binary(metainfo.info().hash());
+        self.url_for(metainfo.announce(), metainfo)
+    }
+
+    /// Like [`Self::url`], but announces to `tracker` instead of the torrent's main tracker.
+    pub fn url_for(&'a self, tracker: &str, metainfo: &'a Metainfo) -> String {
+        self.url_with_hash(tracker, metainfo.info().hash())
+    }
+
+    /// Announces [`Self::info_hash`] to `tracker`, for when there is no metainfo at hand, such
+    /// as with a magnet link.
+    pub fn url_to(&self, tracker: &str) -> String {
+        self.url_with_hash(tracker, self.info_hash)
+    }
+
+    fn url_with_hash(&self, tracker: &str, info_hash: &InfoHash) -> String {
+        let url_encoded_info_hash = urlencoding::encode_binary(info_hash.as_bytes());
         let url_encoded_peer_id = urlencoding::encode_binary(self.peer_id);
 
         let mut url = String::new();
-        url.push_str(metainfo.announce());
-        url.push('?');
+        url.push_str(tracker);
+        // Private trackers put a passkey in the announce URL's own query
+        if !tracker.contains('?') {
+            url.push('?');
+        } else if !tracker.ends_with(['?', '&']) {
+            url.push('&');
+        }
         url.push_str("info_hash=");
         url.push_str(&url_encoded_info_hash);
         url.push('&');
@@ -350,88 +2705,291 @@ impl<'a> TrackerRequest<'a> {
         url.push('&');
         url.push_str("compact=");
         url.push_str(&(self.compact as u8).to_string());
+        if let Some(tracker_id) = self.tracker_id {
+            url.push('&');
+            url.push_str("trackerid=");
+            url.push_str(&urlencoding::encode(tracker_id));
+        }
+        if let Some(corrupt) = self.corrupt {
+            url.push('&');
+            url.push_str("corrupt=");
+            url.push_str(&corrupt.to_string());
+        }
+        if let Some(redundant) = self.redundant {
+            url.push('&');
+            url.push_str("redundant=");
+            url.push_str(&redundant.to_string());
+        }
+        if let Some(event) = self.event {
+            url.push('&');
+            url.push_str("event=");
+            url.push_str(event.as_str());
+        }
         url
     }
 }
 
+pub const DEFAULT_ANNOUNCE_INTERVAL: u64 = 1800;
+
 #[derive(Debug, Getters, CopyGetters)]
 pub struct TrackerResponse {
+    /// Seconds to wait before re-announcing, [`DEFAULT_ANNOUNCE_INTERVAL`] if the tracker did not
+    /// say
     #[getset(get_copy = "pub")]
     interval: u64,
+    #[getset(get_copy = "pub")]
+    interval_provided: bool,
     #[getset(get = "pub")]
     peers: Vec<SocketAddr>,
+    /// The id of each of [`Self::peers`], known only for peers listed as dictionaries
+    #[getset(get = "pub")]
+    peer_ids: Vec<Option<[u8; 20]>>,
+    #[getset(get = "pub")]
+    tracker_id: Option<String>,
 }
 
 impl TrackerResponse {
-    pub fn decode(value: Value) -> Self {
-        let mut value = value.into_dictionary().unwrap();
-        let interval =
-            u64::try_from(value.remove("interval").unwrap().into_integer().unwrap()).unwrap();
-        let peers = value.remove("peers").unwrap().into_bytes().unwrap();
-        let peers = peers.chunks_exact(6);
-        let peers = peers
-            .map(|bytes| {
-                use byteorder::ReadBytesExt;
-                let mut reader = io::Cursor::new(bytes);
-                let _ip = reader.read_u32::<BigEndian>().unwrap();
-                let port = reader.read_u16::<BigEndian>().unwrap();
-                SocketAddr::V4(SocketAddrV4::new(
-                    Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]),
-                    port,
-                ))
-            })
-            .collect();
+    pub fn decode(value: Value) -> Result<Self, TrackerError> {
+        let mut value = value.into_dictionary().ok_or(TrackerError::Malformed)?;
+        if let Some(reason) = value
+            .remove(b"failure reason".as_slice())
+            .and_then(|reason| reason.into_bytes())
+        {
+            return Err(TrackerError::Failure(
+                String::from_utf8_lossy(&reason).into_owned(),
+            ));
+        }
+        let provided_interval = value
+            .remove(b"interval".as_slice())
+            .and_then(|interval| interval.into_integer())
+            .and_then(|interval| u64::try_from(interval).ok());
+        let (mut peers, mut peer_ids) = match value.remove(b"peers".as_slice()) {
+            Some(Value::Bytes(peers)) => {
+                let peers = parse_compact_peers(&peers).map_err(|e| match e {
+                    CompactPeersError::InvalidLength { length, .. } => {
+                        TrackerError::InvalidCompactPeersLength { length }
+                    }
+                })?;
+                let peer_ids = vec![None; peers.len()];
+                (peers, peer_ids)
+            }
+            // The response to `compact=0`
+            Some(Value::List(peers)) => peers.into_iter().filter_map(decode_peer).unzip(),
+            _ => return Err(TrackerError::Malformed),
+        };
+        if let Some(peers6) = value
+            .remove(b"peers6".as_slice())
+            .and_then(|peers6| peers6.into_bytes())
+        {
+            let peers6 = parse_compact_peers_v6(&peers6)?;
+            peer_ids.extend(vec![None; peers6.len()]);
+            peers.extend(peers6);
+        }
+
+        let tracker_id = value
+            .remove(b"tracker id".as_slice())
+            .and_then(|tracker_id| tracker_id.into_bytes())
+            .map(|tracker_id| String::from_utf8_lossy(&tracker_id).into_owned());
+
+        Ok(Self {
+            interval: provided_interval.unwrap_or(DEFAULT_ANNOUNCE_INTERVAL),
+            interval_provided: provided_interval.is_some(),
+            peers,
+            peer_ids,
+            tracker_id,
+        })
+    }
+}
+
+/// Decodes a `{ip, port, peer id}` dictionary, skipping peers given by host name.
+fn decode_peer(peer: Value) -> Option<(SocketAddr, Option<[u8; 20]>)> {
+    let mut peer = peer.into_dictionary()?;
+    let ip = peer.remove(b"ip".as_slice())?.into_bytes()?;
+    let ip = std::str::from_utf8(&ip).ok()?.parse::<IpAddr>().ok()?;
+    let port = u16::try_from(peer.remove(b"port".as_slice())?.into_integer()?).ok()?;
+    let peer_id = peer
+        .remove(b"peer id".as_slice())
+        .and_then(|peer_id| peer_id.into_bytes())
+        .and_then(|peer_id| <[u8; 20]>::try_from(peer_id).ok());
+    Some((SocketAddr::new(ip, port), peer_id))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TrackerError {
+    #[error("tracker failure: {0}")]
+    Failure(String),
+    #[error("malformed tracker response")]
+    Malformed,
+    #[error("compact peers are {length} bytes long, which is not a multiple of 6")]
+    InvalidCompactPeersLength { length: usize },
+    #[error(transparent)]
+    InvalidCompactPeers(#[from] CompactPeersError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CompactPeersError {
+    #[error("compact peers are {length} bytes long, which is not a multiple of {entry_length}")]
+    InvalidLength { length: usize, entry_length: usize },
+}
+
+/// Parses 6-byte compact IPv4 peers, as found in tracker responses, DHT `values` and PEX.
+pub fn parse_compact_peers(bytes: &[u8]) -> Result<Vec<SocketAddr>, CompactPeersError> {
+    parse_compact_entries(bytes, 6, |ip| {
+        IpAddr::from(<[u8; 4]>::try_from(ip).unwrap())
+    })
+}
 
-        Self { interval, peers }
+/// Parses 18-byte compact IPv6 peers.
+pub fn parse_compact_peers_v6(bytes: &[u8]) -> Result<Vec<SocketAddr>, CompactPeersError> {
+    parse_compact_entries(bytes, 18, |ip| {
+        IpAddr::from(<[u8; 16]>::try_from(ip).unwrap())
+    })
+}
+
+fn parse_compact_entries(
+    bytes: &[u8],
+    entry_length: usize,
+    ip: impl Fn(&[u8]) -> IpAddr,
+) -> Result<Vec<SocketAddr>, CompactPeersError> {
+    if bytes.len() % entry_length != 0 {
+        return Err(CompactPeersError::InvalidLength {
+            length: bytes.len(),
+            entry_length,
+        });
     }
+    let peers = bytes
+        .chunks_exact(entry_length)
+        .map(|entry| {
+            let (addr, port) = entry.split_at(entry_length - 2);
+            SocketAddr::new(ip(addr), u16::from_be_bytes([port[0], port[1]]))
+        })
+        .collect();
+    Ok(peers)
 }
 
+const PROTOCOL: &[u8] = b"BitTorrent protocol";
+
+/// Byte and bit of the reserved handshake bytes advertising the extension protocol (BEP 10)
+const EXTENSION_PROTOCOL_BIT: (usize, u8) = (5, 0x10);
+/// Byte and bit of the reserved handshake bytes advertising the fast extension (BEP 6)
+const FAST_EXTENSION_BIT: (usize, u8) = (7, 0x04);
+/// Byte and bit of the reserved handshake bytes advertising a DHT node, which solicits `Port`
+/// messages (BEP 5)
+const DHT_BIT: (usize, u8) = (7, 0x01);
+
 #[derive(Debug, Getters)]
 pub struct HandshakeResponse {
     #[getset(get = "pub")]
-    info_hash: [u8; 20],
+    reserved: [u8; 8],
+    #[getset(get = "pub")]
+    info_hash: InfoHash,
     #[getset(get = "pub")]
     peer_id: [u8; 20],
 }
 
 impl HandshakeResponse {
-    pub async fn decode<R>(reader: &mut R) -> Self
+    pub async fn decode<R>(reader: &mut R) -> Result<Self, HandshakeError>
     where
         R: AsyncRead + Unpin,
     {
         use tokio::io::AsyncReadExt;
-        let length = reader.read_u8().await.unwrap();
-        let mut protocol = vec![0; length as usize];
-        reader.read_exact(&mut protocol).await.unwrap();
-        assert_eq!("BitTorrent protocol", String::from_utf8(protocol).unwrap());
+        let length = reader.read_u8().await?;
+        if usize::from(length) != PROTOCOL.len() {
+            return Err(HandshakeError::BadProtocolLength(length));
+        }
+        let mut protocol = vec![0; PROTOCOL.len()];
+        reader.read_exact(&mut protocol).await?;
+        if protocol != PROTOCOL {
+            return Err(HandshakeError::BadProtocolString(protocol));
+        }
         let mut reserved = [0; 8];
-        reader.read_exact(&mut reserved).await.unwrap();
+        reader.read_exact(&mut reserved).await?;
         let mut info_hash = [0; 20];
-        reader.read_exact(&mut info_hash).await.unwrap();
+        reader.read_exact(&mut info_hash).await?;
         let mut peer_id = [0; 20];
-        reader.read_exact(&mut peer_id).await.unwrap();
-        Self { info_hash, peer_id }
+        reader.read_exact(&mut peer_id).await?;
+        Ok(Self {
+            reserved,
+            info_hash: InfoHash(info_hash),
+            peer_id,
+        })
+    }
+
+    /// Checks that the peer is on the torrent we asked for.
+    pub fn verify(&self, expected_info_hash: &InfoHash) -> Result<(), HandshakeError> {
+        if &self.info_hash != expected_info_hash {
+            return Err(HandshakeError::InfoHashMismatch {
+                expected: *expected_info_hash,
+                got: self.info_hash,
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether the peer accepts extended messages (BEP 10).
+    pub fn supports_extensions(&self) -> bool {
+        self.has_reserved_bit(EXTENSION_PROTOCOL_BIT)
+    }
+
+    /// Whether the peer speaks the fast extension (BEP 6).
+    pub fn supports_fast_extension(&self) -> bool {
+        self.has_reserved_bit(FAST_EXTENSION_BIT)
+    }
+
+    /// Whether the peer runs a DHT node (BEP 5).
+    pub fn supports_dht(&self) -> bool {
+        self.has_reserved_bit(DHT_BIT)
+    }
+
+    fn has_reserved_bit(&self, (byte, bit): (usize, u8)) -> bool {
+        self.reserved[byte] & bit != 0
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum HandshakeError {
+    #[error(transparent)]
+    Io(std::io::Error),
+    #[error("peer closed the connection mid-handshake")]
+    UnexpectedEof,
+    #[error("protocol string is {0} bytes long instead of {}", PROTOCOL.len())]
+    BadProtocolLength(u8),
+    #[error("peer speaks {:?} instead of the BitTorrent protocol", String::from_utf8_lossy(.0))]
+    BadProtocolString(Vec<u8>),
+    #[error("peer is on torrent {got} instead of {expected}")]
+    InfoHashMismatch { expected: InfoHash, got: InfoHash },
+}
+
+impl From<std::io::Error> for HandshakeError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => Self::UnexpectedEof,
+            _ => Self::Io(e),
+        }
     }
 }
 
 pub struct HandshakeRequest<'caller> {
-    pub info_hash: &'caller [u8; 20],
+    pub info_hash: &'caller InfoHash,
     pub peer_id: &'caller [u8; 20],
 }
 
 impl HandshakeRequest<'_> {
-    pub async fn encode<W>(&self, writer: &mut W)
+    pub async fn encode<W>(&self, writer: &mut W) -> std::io::Result<()>
     where
         W: AsyncWrite + Unpin,
     {
         use tokio::io::AsyncWriteExt;
-        let protocol = b"BitTorrent protocol";
-        writer.write_u8(protocol.len() as u8).await.unwrap();
-        writer.write_all(protocol).await.unwrap();
-        writer.write_all(&[0; 8]).await.unwrap();
-        writer.write_all(self.info_hash).await.unwrap();
-        writer.write_all(self.peer_id).await.unwrap();
-        writer.flush().await.unwrap();
+        writer.write_u8(PROTOCOL.len() as u8).await?;
+        writer.write_all(PROTOCOL).await?;
+        let mut reserved = [0; 8];
+        for (byte, bit) in [EXTENSION_PROTOCOL_BIT, FAST_EXTENSION_BIT, DHT_BIT] {
+            reserved[byte] |= bit;
+        }
+        writer.write_all(&reserved).await?;
+        writer.write_all(self.info_hash.as_bytes()).await?;
+        writer.write_all(self.peer_id).await?;
+        writer.flush().await
     }
 }
 
@@ -444,23 +3002,135 @@ pub struct PeerMessageIn {
 }
 
 impl PeerMessageIn {
-    pub async fn decode<R>(reader: &mut R) -> Self
+    /// Reads one message, or `None` for a keep-alive, refusing messages longer than
+    /// [`peer::MAX_MESSAGE_LEN`](crate::peer::MAX_MESSAGE_LEN).
+    ///
+    /// A message cut short by the end of `reader` is an [`std::io::ErrorKind::UnexpectedEof`]
+    /// error.
+    pub async fn decode<R>(reader: &mut R) -> Result<Option<Self>, PeerError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        Self::decode_with_limit(reader, peer::MAX_MESSAGE_LEN).await
+    }
+
+    /// Like [`Self::decode`], but fails with [`PeerError::MessageTooLarge`] without reading or
+    /// allocating the rest of a message longer than `max_message_len`.
+    pub async fn decode_with_limit<R>(
+        reader: &mut R,
+        max_message_len: u32,
+    ) -> Result<Option<Self>, PeerError>
     where
         R: AsyncRead + Unpin,
     {
         use tokio::io::AsyncReadExt;
-        let message_length = reader.read_u32().await.unwrap();
-        let message_id = reader.read_u8().await.unwrap();
+        let message_length = reader.read_u32().await?;
+        if message_length == 0 {
+            return Ok(None);
+        }
+        if message_length > max_message_len {
+            return Err(PeerError::MessageTooLarge {
+                length: message_length,
+                max: max_message_len,
+            });
+        }
+        let message_id = reader.read_u8().await?;
         let message_id = PeerMessageId::from_code(message_id);
         let mut payload = vec![0; (message_length - 1) as usize];
-        reader.read_exact(&mut payload).await.unwrap();
-        Self {
+        reader.read_exact(&mut payload).await?;
+        Ok(Some(Self {
             message_id,
             payload,
+        }))
+    }
+
+    /// Parses the payload according to the message id.
+    ///
+    /// The bitfield covers every bit of the payload, as the piece count is not known here; use
+    /// [`Bitfield::from_message`] to check it against the torrent.
+    pub fn into_typed(self) -> Result<TypedPeerMessage, PeerError> {
+        let (message_id, length) = (self.message_id, self.payload.len());
+        let malformed = || PeerError::MalformedMessage { message_id, length };
+        let u32_at = |i: usize| {
+            self.payload
+                .get(i * 4..i * 4 + 4)
+                .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+        };
+        let index = || match self.payload.len() {
+            4 => Ok(u32_at(0).unwrap()),
+            _ => Err(malformed()),
+        };
+        let request = || match (self.payload.len(), u32_at(0), u32_at(1), u32_at(2)) {
+            (12, Some(index), Some(begin), Some(length)) => Ok(PeerMessageRequest {
+                index,
+                begin,
+                length,
+            }),
+            _ => Err(malformed()),
+        };
+        let no_payload = |message: TypedPeerMessage| match self.payload.len() {
+            0 => Ok(message),
+            _ => Err(malformed()),
+        };
+        match self.message_id {
+            PeerMessageId::Choke => no_payload(TypedPeerMessage::Choke),
+            PeerMessageId::Unchoke => no_payload(TypedPeerMessage::Unchoke),
+            PeerMessageId::Interested => no_payload(TypedPeerMessage::Interested),
+            PeerMessageId::NotInterested => no_payload(TypedPeerMessage::NotInterested),
+            PeerMessageId::Have => index().map(TypedPeerMessage::Have),
+            PeerMessageId::Bitfield => Ok(TypedPeerMessage::Bitfield(Bitfield::from_bytes(
+                &self.payload,
+                u32::try_from(self.payload.len() * 8).map_err(|_| malformed())?,
+            ))),
+            PeerMessageId::Request => request().map(TypedPeerMessage::Request),
+            PeerMessageId::Piece => match (u32_at(0), u32_at(1)) {
+                (Some(index), Some(begin)) => Ok(TypedPeerMessage::Piece(PeerMessageResponse {
+                    index,
+                    begin,
+                    block: self.payload[8..].to_vec(),
+                })),
+                _ => Err(malformed()),
+            },
+            PeerMessageId::Cancel => request().map(TypedPeerMessage::Cancel),
+            PeerMessageId::Port => match <[u8; 2]>::try_from(self.payload.as_slice()) {
+                Ok(port) => Ok(TypedPeerMessage::Port(u16::from_be_bytes(port))),
+                Err(_) => Err(malformed()),
+            },
+            PeerMessageId::Suggest => index().map(TypedPeerMessage::Suggest),
+            PeerMessageId::AllowedFast => index().map(TypedPeerMessage::AllowedFast),
+            PeerMessageId::HaveAll => no_payload(TypedPeerMessage::HaveAll),
+            PeerMessageId::HaveNone => no_payload(TypedPeerMessage::HaveNone),
+            PeerMessageId::Reject => request().map(TypedPeerMessage::Reject),
+            PeerMessageId::Extended => Ok(TypedPeerMessage::Extended(self.payload)),
+            PeerMessageId::Unknown(code) => Err(PeerError::UnknownMessage(code)),
         }
     }
 }
 
+/// An incoming message with its payload parsed, as returned by [`PeerMessageIn::into_typed`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TypedPeerMessage {
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Bitfield),
+    Request(PeerMessageRequest),
+    Piece(PeerMessageResponse),
+    Cancel(PeerMessageRequest),
+    /// The peer's DHT port (BEP 5)
+    Port(u16),
+    Suggest(u32),
+    AllowedFast(u32),
+    HaveAll,
+    HaveNone,
+    /// A request the peer will not answer (BEP 6)
+    Reject(PeerMessageRequest),
+    /// The raw payload, starting with the extended message id (BEP 10)
+    Extended(Vec<u8>),
+}
+
 #[derive(Debug)]
 pub struct PeerMessageOut<'caller> {
     pub message_id: PeerMessageId,
@@ -468,7 +3138,7 @@ pub struct PeerMessageOut<'caller> {
 }
 
 impl PeerMessageOut<'_> {
-    pub async fn encode<W>(&self, writer: &mut W)
+    pub async fn encode<W>(&self, writer: &mut W) -> std::io::Result<()>
     where
         W: AsyncWrite + Unpin,
     {
@@ -476,46 +3146,82 @@ impl PeerMessageOut<'_> {
         let message_len = self.payload.len() + 1;
         writer
             .write_u32(u32::try_from(message_len).unwrap())
-            .await
-            .unwrap();
-        writer.write_u8(self.message_id.code()).await.unwrap();
-        writer.write_all(self.payload).await.unwrap();
-        writer.flush().await.unwrap();
+            .await?;
+        writer.write_u8(self.message_id.code()).await?;
+        writer.write_all(self.payload).await?;
+        writer.flush().await
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PeerMessageId {
+    Choke,
     Bitfield,
     Interested,
+    NotInterested,
     Unchoke,
+    Have,
     Request,
     Piece,
+    Cancel,
+    Suggest,
+    AllowedFast,
+    HaveAll,
+    HaveNone,
+    Reject,
+    Extended,
+    Port,
+    /// Some other message, which is passed along for the caller to ignore
+    Unknown(u8),
 }
 
 impl PeerMessageId {
     pub fn from_code(code: u8) -> Self {
         match code {
+            0 => Self::Choke,
             5 => Self::Bitfield,
             2 => Self::Interested,
+            3 => Self::NotInterested,
             1 => Self::Unchoke,
+            4 => Self::Have,
             6 => Self::Request,
             7 => Self::Piece,
-            _ => panic!(),
+            8 => Self::Cancel,
+            13 => Self::Suggest,
+            17 => Self::AllowedFast,
+            14 => Self::HaveAll,
+            15 => Self::HaveNone,
+            16 => Self::Reject,
+            9 => Self::Port,
+            20 => Self::Extended,
+            code => Self::Unknown(code),
         }
     }
 
     pub fn code(&self) -> u8 {
         match self {
+            Self::Choke => 0,
             Self::Bitfield => 5,
             Self::Interested => 2,
+            Self::NotInterested => 3,
             Self::Unchoke => 1,
+            Self::Have => 4,
             Self::Request => 6,
             Self::Piece => 7,
+            Self::Cancel => 8,
+            Self::Suggest => 13,
+            Self::AllowedFast => 17,
+            Self::HaveAll => 14,
+            Self::HaveNone => 15,
+            Self::Reject => 16,
+            Self::Port => 9,
+            Self::Extended => 20,
+            Self::Unknown(code) => *code,
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct PeerMessageRequest {
     pub index: u32,
     pub begin: u32,
@@ -523,15 +3229,15 @@ pub struct PeerMessageRequest {
 }
 
 impl PeerMessageRequest {
-    pub async fn encode<W>(&self, writer: &mut W)
+    pub async fn encode<W>(&self, writer: &mut W) -> std::io::Result<()>
     where
         W: AsyncWrite + Unpin,
     {
         use tokio::io::AsyncWriteExt;
-        writer.write_u32(self.index).await.unwrap();
-        writer.write_u32(self.begin).await.unwrap();
-        writer.write_u32(self.length).await.unwrap();
-        writer.flush().await.unwrap();
+        writer.write_u32(self.index).await?;
+        writer.write_u32(self.begin).await?;
+        writer.write_u32(self.length).await?;
+        writer.flush().await
     }
 }
 
@@ -554,7 +3260,7 @@ impl PeerMessageRequest {
 //     }
 // }
 
-#[derive(Debug, Getters, CopyGetters)]
+#[derive(Debug, Clone, PartialEq, Eq, Getters, CopyGetters)]
 pub struct PeerMessageResponse {
     #[getset(get_copy = "pub")]
     index: u32,
@@ -565,21 +3271,21 @@ pub struct PeerMessageResponse {
 }
 
 impl PeerMessageResponse {
-    pub async fn decode<R>(reader: &mut R, reader_length: usize) -> Self
+    pub async fn decode<R>(reader: &mut R, reader_length: usize) -> Result<Self, PeerError>
     where
         R: AsyncRead + Unpin,
     {
         use tokio::io::AsyncReadExt;
-        let index = reader.read_u32().await.unwrap();
-        let begin = reader.read_u32().await.unwrap();
-        let block = reader_length - 4 - 4;
+        let index = reader.read_u32().await?;
+        let begin = reader.read_u32().await?;
+        let block = reader_length.saturating_sub(4 + 4);
         let mut block = vec![0; block];
-        reader.read_exact(&mut block).await.unwrap();
-        Self {
+        reader.read_exact(&mut block).await?;
+        Ok(Self {
             index,
             begin,
             block,
-        }
+        })
     }
 }
 
@@ -0,0 +1,132 @@
+use getset::Getters;
+
+use crate::{parse_info_hash, InfoHash};
+
+#[derive(Debug, Clone, PartialEq, Eq, Getters)]
+pub struct MagnetLink {
+    #[getset(get = "pub")]
+    info_hash: InfoHash,
+    /// The `dn` parameter
+    #[getset(get = "pub")]
+    display_name: Option<String>,
+    /// The `tr` parameters, in order
+    #[getset(get = "pub")]
+    trackers: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum MagnetError {
+    #[error("not a magnet URI")]
+    NotMagnet,
+    #[error("malformed query string")]
+    InvalidQuery,
+    #[error("no `xt=urn:btih:` parameter")]
+    MissingInfoHash,
+    #[error("info hash {0:?} is neither 40 hex nor 32 base32 characters")]
+    InvalidInfoHash(String),
+}
+
+/// Parses a `magnet:?` URI carrying a v1 info hash (BEP 9).
+pub fn parse_magnet(uri: &str) -> Result<MagnetLink, MagnetError> {
+    let query = uri.strip_prefix("magnet:?").ok_or(MagnetError::NotMagnet)?;
+    let params: Vec<(String, String)> =
+        serde_urlencoded::from_str(query).map_err(|_| MagnetError::InvalidQuery)?;
+
+    let mut info_hash = None;
+    let mut display_name = None;
+    let mut trackers = vec![];
+    for (key, value) in params {
+        match key.as_str() {
+            "xt" => {
+                // Other exact topics, such as v2 `urn:btmh:` hashes, are skipped
+                if let Some(hash) = value.strip_prefix("urn:btih:") {
+                    let parsed = parse_info_hash(hash)
+                        .map_err(|_| MagnetError::InvalidInfoHash(hash.to_owned()))?;
+                    info_hash = Some(parsed);
+                }
+            }
+            "dn" => display_name = Some(value),
+            "tr" => trackers.push(value),
+            _ => {}
+        }
+    }
+    Ok(MagnetLink {
+        info_hash: info_hash.ok_or(MagnetError::MissingInfoHash)?,
+        display_name,
+        trackers,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TrackerRequest;
+
+    const HASH: &str = "d69f91e6b2ae4c542468d1073a71d4ea13879a7f";
+
+    #[test]
+    fn test_hex_info_hash() {
+        let uri = format!(
+            "magnet:?xt=urn:btih:{HASH}&dn=sample.txt\
+             &tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce\
+             &tr=udp%3A%2F%2Ftracker.example%3A80"
+        );
+        let magnet = parse_magnet(&uri).unwrap();
+        assert_eq!(magnet.info_hash().to_string(), HASH);
+        assert_eq!(magnet.display_name().as_deref(), Some("sample.txt"));
+        assert_eq!(
+            magnet.trackers(),
+            &[
+                "http://bittorrent-test-tracker.codecrafters.io/announce",
+                "udp://tracker.example:80",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tracker_request() {
+        let magnet = parse_magnet(&format!("magnet:?xt=urn:btih:{HASH}&tr=http://t/a")).unwrap();
+        let req = TrackerRequest {
+            info_hash: magnet.info_hash(),
+            peer_id: b"00112233445566778899",
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 1,
+            compact: true,
+            tracker_id: None,
+            corrupt: None,
+            redundant: None,
+            event: None,
+        };
+        let url = req.url_to(&magnet.trackers()[0]);
+        assert!(url.starts_with("http://t/a?info_hash=%D6%9F%91%E6%B2%AELT%24h"));
+    }
+
+    #[test]
+    fn test_base32_info_hash() {
+        let magnet = parse_magnet("magnet:?xt=urn:btih:22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7").unwrap();
+        assert_eq!(magnet.info_hash().to_string(), HASH);
+        assert_eq!(magnet.display_name(), &None);
+        assert!(magnet.trackers().is_empty());
+
+        let lowercase = parse_magnet("magnet:?xt=urn:btih:22pzdzvsvzgfijdi2edtu4ou5ijypgt7");
+        assert_eq!(lowercase.unwrap().info_hash(), magnet.info_hash());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(
+            parse_magnet("http://example.com"),
+            Err(MagnetError::NotMagnet)
+        );
+        assert_eq!(
+            parse_magnet("magnet:?dn=sample.txt"),
+            Err(MagnetError::MissingInfoHash)
+        );
+        assert_eq!(
+            parse_magnet("magnet:?xt=urn:btih:d69f"),
+            Err(MagnetError::InvalidInfoHash("d69f".to_owned()))
+        );
+    }
+}
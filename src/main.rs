@@ -4,14 +4,31 @@ use std::{
     io::{self, Read},
     net::SocketAddr,
     path::Path,
+    time::Duration,
 };
 
 use bittorrent_starter_rust::{
-    decode_bencoded_value, HandshakeRequest, HandshakeResponse, Metainfo, PeerMessageId,
-    PeerMessageIn, PeerMessageOut, PeerMessageRequest, PeerMessageResponse, TrackerRequest,
-    TrackerResponse,
+    announce::{announce_to_trackers, AnnounceConfig, HttpVersion},
+    create::{create_torrent, DEFAULT_PIECE_LENGTH},
+    decode_bencoded_value_exact,
+    download::{
+        create_output_file, download_all, download_to_writer, part_file_path, DownloadConfig,
+    },
+    parse_info_hash,
+    peer::{
+        generate_peer_id, ConnectOptions, PeerConnection, BLOCK_SIZE, CLIENT_PREFIX,
+        DEFAULT_CONNECT_TIMEOUT, MAX_MESSAGE_LEN, MAX_PIPELINE_DEPTH,
+    },
+    pool::read_peers_file,
+    probe::piece_availability,
+    progress::Progress,
+    rate::RateLimiter,
+    seed::seed,
+    to_json_value,
+    verify::{verify_against, verify_file, Checksum},
+    BencodeError, Metainfo, MetainfoMode, TrackerEvent, TrackerRequest, TrackerRequestBuilder,
 };
-use tokio::net::TcpStream;
+use tokio_util::sync::CancellationToken;
 
 // Available if you need it!
 // use serde_bencode;
@@ -22,179 +39,362 @@ async fn main() {
     let args: Vec<String> = env::args().collect();
     let command = &args[1];
 
-    let my_peer_id = b"00112233445566778899";
+    let my_peer_id = &match flag_value(&args, "--peer-id") {
+        Some(peer_id) => <[u8; 20]>::try_from(peer_id.as_bytes()).unwrap(),
+        None => generate_peer_id(CLIENT_PREFIX),
+    };
     let my_port = 6881;
 
     if command == "decode" {
         let encoded_value = &args[2];
-        let (decoded_value, _) = decode_bencoded_value(encoded_value.as_bytes());
-        println!("{decoded_value}");
+        let show_consumed = args.iter().any(|arg| arg == "--show-consumed");
+        let decoded_value = match decode_bencoded_value_exact(encoded_value.as_bytes()) {
+            Ok(decoded) => decoded,
+            Err(e @ BencodeError::TrailingGarbage { consumed, total }) => {
+                // Report how far decoding got before rejecting the trailing data
+                if show_consumed {
+                    println!("Consumed: {consumed} of {total} bytes");
+                }
+                eprintln!("cannot decode {encoded_value:?}: {e}");
+                std::process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("cannot decode {encoded_value:?}: {e}");
+                std::process::exit(1);
+            }
+        };
+        if args.iter().any(|arg| arg == "--json") {
+            let json = to_json_value(&decoded_value);
+            println!("{}", serde_json::to_string_pretty(&json).unwrap());
+        } else {
+            println!("{decoded_value}");
+        }
+        if show_consumed {
+            let total = encoded_value.len();
+            println!("Consumed: {total} of {total} bytes");
+        }
     } else if command == "info" {
         let metainfo = parse_metainfo_file(&args[2]).unwrap();
+        let info_hash = metainfo.info().hash();
+        let info_hash = match flag_value(&args, "--info-hash-format").unwrap_or("hex") {
+            "hex" => info_hash.to_string(),
+            "base32" => info_hash.to_base32(),
+            "raw" => {
+                // Only the hash itself so that it can be piped
+                use std::io::Write;
+                io::stdout().write_all(info_hash.as_bytes()).unwrap();
+                return;
+            }
+            format => panic!("unknown info hash format: {format}"),
+        };
         println!("Tracker URL: {}", metainfo.announce());
         println!("Length: {}", metainfo.info().length());
-        println!(
-            "Info Hash: {}",
-            DisplayHex::from(&metainfo.info().hash()[..])
-        );
+        println!("Info Hash: {info_hash}");
         println!("Piece Length: {}", metainfo.info().piece_length());
+        if let MetainfoMode::MultiFile { files } = metainfo.info().mode() {
+            println!("Files:");
+            for file in files {
+                println!("{} ({} bytes)", file.path.join("/"), file.length);
+            }
+        }
         println!("Piece hashes:");
         for piece_hash in metainfo.info().piece_hashes() {
             println!("{}", DisplayHex::from(piece_hash))
         }
     } else if command == "peers" {
         let metainfo = parse_metainfo_file(&args[2]).unwrap();
-        let resp = peers(&metainfo, my_peer_id, my_port).await;
-        for peer in resp.peers() {
+        let peers = peers(
+            &metainfo,
+            &starting_request(&metainfo, my_peer_id, my_port).build(),
+            &args,
+        )
+        .await;
+        for peer in peers {
             println!("{peer}");
         }
     } else if command == "handshake" {
         let metainfo = parse_metainfo_file(&args[2]).unwrap();
         let peer = &args[3];
         let peer: SocketAddr = peer.parse().unwrap();
-        let (_stream, handshake) = establish(&metainfo, my_peer_id, peer).await;
-        println!("Peer ID: {}", DisplayHex::from(&handshake.peer_id()[..]));
+        // Lets a peer be probed for a torrent other than the one in the file
+        let info_hash = match flag_value(&args, "--info-hash") {
+            Some(info_hash) => parse_info_hash(info_hash).unwrap(),
+            None => *metainfo.info().hash(),
+        };
+        let conn =
+            PeerConnection::connect_with(peer, &info_hash, my_peer_id, &connect_options(&args))
+                .await
+                .unwrap();
+        println!(
+            "Peer ID: {}",
+            DisplayHex::from(&conn.handshake().peer_id()[..])
+        );
     } else if command == "download_piece" {
         let metainfo = parse_metainfo_file(&args[4]).unwrap();
-        let peers = peers(&metainfo, my_peer_id, my_port).await;
-        let (mut stream, _handshake) = establish(&metainfo, my_peer_id, peers.peers()[0]).await;
-        // println!(
-        //     "{}, {}",
-        //     stream.local_addr().unwrap(),
-        //     stream.peer_addr().unwrap()
-        // );
-        // let mut line = String::new();
-        // tokio::io::BufReader::new(tokio::io::stdin())
-        //     .read_line(&mut line)
-        //     .await
-        //     .unwrap();
-        let available_pieces = PeerMessageIn::decode(&mut stream).await;
-        assert!(matches!(
-            available_pieces.message_id(),
-            PeerMessageId::Bitfield
-        ));
-        PeerMessageOut {
-            message_id: PeerMessageId::Interested,
-            payload: &[],
-        }
-        .encode(&mut stream)
+        let peers = peers(
+            &metainfo,
+            &starting_request(&metainfo, my_peer_id, my_port).build(),
+            &args,
+        )
         .await;
-        let unchoke = PeerMessageIn::decode(&mut stream).await;
-        assert!(matches!(unchoke.message_id(), PeerMessageId::Unchoke));
-        // let piece_indices = args[5..].iter().map(|s| s.parse::<u32>().unwrap());
-        let piece_index = args[5].parse::<u32>().unwrap();
-        let block_size = 2_u32.pow(14);
+        let connect_options = ConnectOptions {
+            piece_count: Some(metainfo.info().piece_hashes().count() as u32),
+            ..connect_options(&args)
+        };
+        let mut conn = PeerConnection::connect_with(
+            peers[0],
+            metainfo.info().hash(),
+            my_peer_id,
+            &connect_options,
+        )
+        .await
+        .unwrap();
+        conn.unchoke().await.unwrap();
+        let piece_indices = args[5..]
+            .iter()
+            .take_while(|arg| !arg.starts_with("--"))
+            .map(|s| s.parse::<u32>().unwrap())
+            .collect::<Vec<_>>();
         let output_file_path = &args[3];
         let _ = tokio::fs::remove_file(output_file_path).await;
-        let mut output_file = tokio::fs::File::options()
-            .write(true)
-            .create(true)
-            .open(output_file_path)
+        let part_file_path = part_file_path(Path::new(output_file_path));
+        let mut output_file = match create_output_file(&part_file_path).await {
+            Ok(output_file) => output_file,
+            Err(e) => {
+                eprintln!("cannot create {}: {e}", part_file_path.display());
+                std::process::exit(1);
+            }
+        };
+        for &piece_index in &piece_indices {
+            let piece = conn
+                .download_piece_of(metainfo.info(), piece_index)
+                .await
+                .unwrap();
+            // A single piece makes up the whole output
+            if piece_indices.len() > 1 {
+                let offset = u64::from(piece_index) * u64::from(metainfo.info().piece_length());
+                use tokio::io::AsyncSeekExt;
+                output_file.seek(io::SeekFrom::Start(offset)).await.unwrap();
+            }
+            use tokio::io::AsyncWriteExt;
+            output_file.write_all(&piece).await.unwrap();
+        }
+        output_file.sync_all().await.unwrap();
+        drop(output_file);
+        tokio::fs::rename(&part_file_path, output_file_path)
             .await
             .unwrap();
-        // for piece_index in piece_indices {
-        {
-            let piece_length = metainfo
-                .info()
-                .piece_length()
-                .min(metainfo.info().length() - metainfo.info().piece_length() * piece_index);
-
-            let mut remaining_piece = piece_length;
-            while remaining_piece > 0 {
-                let begin = piece_length - remaining_piece;
-                let block_size = remaining_piece.min(block_size);
-                remaining_piece -= block_size;
-
-                let req = PeerMessageRequest {
-                    index: piece_index,
-                    begin,
-                    length: block_size,
-                };
-                let mut payload = vec![];
-                req.encode(&mut payload).await;
-                let req = PeerMessageOut {
-                    message_id: PeerMessageId::Request,
-                    payload: &payload,
-                };
-                req.encode(&mut stream).await;
-
-                let resp = PeerMessageIn::decode(&mut stream).await;
-                assert!(matches!(resp.message_id(), PeerMessageId::Piece));
-                let payload_length = resp.payload().len();
-                let mut payload = io::Cursor::new(resp.payload());
-                let resp = PeerMessageResponse::decode(&mut payload, payload_length).await;
-                assert_eq!(resp.block().len(), block_size as usize);
-                use tokio::io::AsyncWriteExt;
-                output_file.write_all(resp.block()).await.unwrap();
-            }
+        for piece_index in piece_indices {
+            println!("Piece {piece_index} downloaded to {output_file_path}");
         }
-        println!("Piece {piece_index} downloaded to {output_file_path}");
     } else if command == "download" {
         let metainfo = parse_metainfo_file(&args[4]).unwrap();
-        let peers = peers(&metainfo, my_peer_id, my_port).await;
-        let (mut stream, _handshake) = establish(&metainfo, my_peer_id, peers.peers()[0]).await;
-        let available_pieces = PeerMessageIn::decode(&mut stream).await;
-        assert!(matches!(
-            available_pieces.message_id(),
-            PeerMessageId::Bitfield
-        ));
-        PeerMessageOut {
-            message_id: PeerMessageId::Interested,
-            payload: &[],
+        for warning in metainfo.validate() {
+            eprintln!("warning: {warning}");
         }
-        .encode(&mut stream)
-        .await;
-        let unchoke = PeerMessageIn::decode(&mut stream).await;
-        assert!(matches!(unchoke.message_id(), PeerMessageId::Unchoke));
-        let block_size = 2_u32.pow(14);
+        // A peers file replaces tracker discovery
+        let peers_file = flag_value(&args, "--peers-file");
+        let peer_addrs = match peers_file {
+            Some(peers_file) => read_peers_file(peers_file).unwrap(),
+            None => {
+                let req = starting_request(&metainfo, my_peer_id, my_port)
+                    .event(TrackerEvent::Started)
+                    .build();
+                peers(&metainfo, &req, &args).await
+            }
+        };
+        // `-` streams the content to stdout, so status goes to stderr instead
         let output_file_path = &args[3];
-        let _ = tokio::fs::remove_file(output_file_path).await;
-        let mut output_file = tokio::fs::File::options()
-            .write(true)
-            .create(true)
-            .open(output_file_path)
+        let to_stdout = output_file_path == "-";
+        let default_config = DownloadConfig::default();
+        let config = DownloadConfig {
+            concurrency: flag_value(&args, "--concurrency")
+                .map_or(default_config.concurrency, |n| n.parse().unwrap()),
+            max_total_bytes: flag_value(&args, "--max-total-bytes").map(|n| n.parse().unwrap()),
+            max_download_rate: flag_value(&args, "--max-download-rate")
+                .map(|rate| rate.parse().unwrap()),
+            connect_options: connect_options(&args),
+            rarest_first: args.iter().any(|arg| arg == "--rarest-first"),
+            resume: args.iter().any(|arg| arg == "--resume"),
+            progress: args
+                .iter()
+                .any(|arg| arg == "--progress")
+                .then(|| std::sync::Arc::new(Progress::new())),
+            ..default_config
+        };
+        // Redraws the progress line on stderr, which stays clear of content streamed to stdout
+        let progress_bar = config.progress.as_ref().map(|progress| {
+            let progress = std::sync::Arc::clone(progress);
+            let mut changed = progress.subscribe();
+            let total_length = metainfo.info().length();
+            let total_pieces = metainfo.info().piece_hashes().count();
+            tokio::spawn(async move {
+                while changed.changed().await.is_ok() {
+                    eprint!(
+                        "\r{:5.1}% {}/{total_pieces} pieces",
+                        progress.percent_complete(total_length),
+                        progress.completed_pieces()
+                    );
+                }
+            })
+        });
+        let cancel = CancellationToken::new();
+        tokio::spawn({
+            let cancel = cancel.clone();
+            async move {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    cancel.cancel();
+                }
+            }
+        });
+        let report = if to_stdout {
+            let mut stdout = tokio::io::stdout();
+            download_to_writer(
+                &metainfo,
+                &peer_addrs,
+                my_peer_id,
+                &config,
+                &mut stdout,
+                cancel,
+            )
             .await
-            .unwrap();
-        for (piece_index, _piece_hash) in metainfo.info().piece_hashes().enumerate() {
-            let piece_index = u32::try_from(piece_index).unwrap();
-            let piece_length = metainfo
-                .info()
-                .piece_length()
-                .min(metainfo.info().length() - metainfo.info().piece_length() * piece_index);
-
-            let mut remaining_piece = piece_length;
-            while remaining_piece > 0 {
-                let begin = piece_length - remaining_piece;
-                let block_size = remaining_piece.min(block_size);
-                remaining_piece -= block_size;
-
-                let req = PeerMessageRequest {
-                    index: piece_index,
-                    begin,
-                    length: block_size,
-                };
-                let mut payload = vec![];
-                req.encode(&mut payload).await;
-                let req = PeerMessageOut {
-                    message_id: PeerMessageId::Request,
-                    payload: &payload,
-                };
-                req.encode(&mut stream).await;
-
-                let resp = PeerMessageIn::decode(&mut stream).await;
-                assert!(matches!(resp.message_id(), PeerMessageId::Piece));
-                let payload_length = resp.payload().len();
-                let mut payload = io::Cursor::new(resp.payload());
-                let resp = PeerMessageResponse::decode(&mut payload, payload_length).await;
-                assert_eq!(resp.block().len(), block_size as usize);
-                use tokio::io::AsyncWriteExt;
-                output_file.write_all(resp.block()).await.unwrap();
+        } else {
+            download_all(
+                &metainfo,
+                &peer_addrs,
+                my_peer_id,
+                &config,
+                output_file_path,
+                cancel,
+            )
+            .await
+        }
+        .unwrap();
+        if let Some(progress_bar) = progress_bar {
+            progress_bar.abort();
+            eprintln!();
+        }
+        if report.is_complete() && peers_file.is_none() {
+            let length = metainfo.info().length();
+            let req = starting_request(&metainfo, my_peer_id, my_port)
+                .downloaded(length)
+                .left(0)
+                .event(TrackerEvent::Completed)
+                .build();
+            // The peers it returns are not needed
+            peers(&metainfo, &req, &args).await;
+        }
+        let mut status = vec![];
+        if report.is_complete() {
+            status.push(format!(
+                "Downloaded {} to {output_file_path}",
+                metainfo.info().name()
+            ));
+        } else {
+            if report.budget_exhausted() {
+                status.push("Stopped after reaching the byte budget".to_owned());
+            }
+            status.push(format!(
+                "Download incomplete: {}/{} pieces written to {output_file_path}",
+                report.completed_pieces().len(),
+                report.total_pieces()
+            ));
+        }
+        for line in status {
+            if to_stdout {
+                eprintln!("{line}");
+            } else {
+                println!("{line}");
             }
         }
+    } else if command == "verify" {
+        let metainfo = parse_metainfo_file(&args[2]).unwrap();
+        let file_path = &args[3];
+        let report = verify_file(metainfo.info(), file_path).unwrap();
+        if let Some(report_path) = flag_value(&args, "--report") {
+            std::fs::write(report_path, report.to_json()).unwrap();
+        }
         println!(
-            "Downloaded {} to {output_file_path}",
-            metainfo.info().name()
+            "Verified {}/{} pieces of {file_path}",
+            report.verified().len(),
+            report.total()
         );
+        if !report.is_complete() {
+            println!("Failed pieces: {:?}", report.failed());
+            std::process::exit(1);
+        }
+        let mut checksums = vec![];
+        if let Some(size) = flag_value(&args, "--size") {
+            checksums.push(Checksum::Size(size.parse().unwrap()));
+        }
+        if let Some(sha256) = flag_value(&args, "--sha256") {
+            let hash = hex::decode(sha256).unwrap().try_into().unwrap();
+            checksums.push(Checksum::Sha256(hash));
+        }
+        for checksum in checksums {
+            if !verify_against(file_path, &checksum).unwrap() {
+                println!("Checksum mismatch: {checksum:?}");
+                std::process::exit(1);
+            }
+        }
+    } else if command == "availability" {
+        let metainfo = parse_metainfo_file(&args[2]).unwrap();
+        let req = starting_request(&metainfo, my_peer_id, my_port).build();
+        let peers = peers(&metainfo, &req, &args).await;
+        let peer_limit = flag_value(&args, "--peer-limit").map_or(50, |n| n.parse().unwrap());
+        let availability = piece_availability(
+            &metainfo,
+            &peers,
+            my_peer_id,
+            peer_limit,
+            Duration::from_secs(5),
+        )
+        .await;
+        for (index, count) in availability.into_iter().enumerate() {
+            println!("{index}: {count}");
+        }
+    } else if command == "seed" {
+        let metainfo = parse_metainfo_file(&args[2]).unwrap();
+        let file_path = Path::new(&args[3]).to_owned();
+        let port = flag_value(&args, "--port").map_or(my_port, |n| n.parse().unwrap());
+        let listener = tokio::net::TcpListener::bind(("0.0.0.0", port))
+            .await
+            .unwrap();
+        println!("Seeding on {}", listener.local_addr().unwrap());
+        let upload_limiter = flag_value(&args, "--max-upload-rate")
+            .map(|rate| std::sync::Arc::new(RateLimiter::new(rate.parse().unwrap())));
+        seed(
+            listener,
+            metainfo,
+            *my_peer_id,
+            file_path,
+            upload_limiter,
+            None,
+            max_message_len(&args),
+        )
+        .await
+        .unwrap();
+    } else if command == "edit" {
+        let mut metainfo = parse_metainfo_file(&args[2]).unwrap();
+        let output_file_path = &args[3];
+        if let Some(announce) = flag_value(&args, "--announce") {
+            metainfo.set_announce(announce.to_owned());
+        }
+        if let Some(name) = flag_value(&args, "--name") {
+            metainfo.info_mut().set_name(name.to_owned());
+        }
+        metainfo.recompute_info_hash();
+        std::fs::write(output_file_path, metainfo.encode()).unwrap();
+        println!("Info Hash: {}", metainfo.info().hash());
+    } else if command == "create" {
+        let content_path = Path::new(&args[2]);
+        let output_file_path = &args[3];
+        let announce = flag_value(&args, "--announce").unwrap();
+        let piece_length = flag_value(&args, "--piece-length")
+            .map_or(DEFAULT_PIECE_LENGTH, |n| n.parse().unwrap());
+        let metainfo = create_torrent(content_path, announce, piece_length).unwrap();
+        std::fs::write(output_file_path, metainfo.encode()).unwrap();
+        println!("Info Hash: {}", metainfo.info().hash());
     } else {
         println!("unknown command: {}", args[1])
     }
@@ -219,44 +419,62 @@ impl fmt::Display for DisplayHex<'_> {
     }
 }
 
+/// Returns the argument following `flag`, if any.
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    let position = args.iter().position(|arg| arg == flag)?;
+    args.get(position + 1).map(String::as_str)
+}
+
+fn connect_options(args: &[String]) -> ConnectOptions {
+    ConnectOptions {
+        socks5_proxy: flag_value(args, "--socks5-proxy").map(|proxy| proxy.parse().unwrap()),
+        max_pipeline_depth: flag_value(args, "--max-pipeline-depth")
+            .map_or(MAX_PIPELINE_DEPTH, |depth| depth.parse().unwrap()),
+        block_size: flag_value(args, "--block-size")
+            .map_or(BLOCK_SIZE, |size| size.parse().unwrap()),
+        connect_timeout: flag_value(args, "--connect-timeout")
+            .map_or(DEFAULT_CONNECT_TIMEOUT, |secs| {
+                Duration::from_secs(secs.parse().unwrap())
+            }),
+        max_message_len: max_message_len(args),
+        ..Default::default()
+    }
+}
+
+fn max_message_len(args: &[String]) -> u32 {
+    flag_value(args, "--max-message-len").map_or(MAX_MESSAGE_LEN, |len| len.parse().unwrap())
+}
+
 fn parse_metainfo_file(path: impl AsRef<Path>) -> io::Result<Metainfo> {
     let mut file = std::fs::File::options().read(true).open(path)?;
     let mut buf = vec![];
     file.read_to_end(&mut buf)?;
-    let (decoded_value, _) = decode_bencoded_value(&buf);
-    Ok(Metainfo::decode(decoded_value))
+    Metainfo::decode_bytes(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
 }
 
-async fn peers(metainfo: &Metainfo, my_peer_id: &[u8; 20], my_port: u16) -> TrackerResponse {
-    let client = reqwest::Client::new();
-
-    let req = TrackerRequest {
-        info_hash: metainfo.info().hash(),
-        peer_id: my_peer_id,
-        port: my_port,
-        uploaded: 0,
-        downloaded: 0,
-        left: metainfo.info().length() as u64,
-        compact: true,
-    };
-
-    let url = req.url(metainfo);
-    let resp = client.get(url).send().await.unwrap().bytes().await.unwrap();
-    let (resp, _) = decode_bencoded_value(&resp);
-    TrackerResponse::decode(resp)
+/// Announces for a session that has not transferred anything yet.
+fn starting_request<'a>(
+    metainfo: &'a Metainfo,
+    my_peer_id: &'a [u8; 20],
+    my_port: u16,
+) -> TrackerRequestBuilder<'a> {
+    TrackerRequest::builder(metainfo.info(), my_peer_id).port(my_port)
 }
 
-async fn establish(
-    metainfo: &Metainfo,
-    my_peer_id: &[u8; 20],
-    peer: SocketAddr,
-) -> (TcpStream, HandshakeResponse) {
-    let mut stream = TcpStream::connect(peer).await.unwrap();
-    let handshake = HandshakeRequest {
-        info_hash: metainfo.info().hash(),
-        peer_id: my_peer_id,
+async fn peers(metainfo: &Metainfo, req: &TrackerRequest<'_>, args: &[String]) -> Vec<SocketAddr> {
+    let config = AnnounceConfig {
+        max_trackers: flag_value(args, "--max-trackers").map(|n| n.parse().unwrap()),
+        enough_peers: flag_value(args, "--enough-peers").map(|n| n.parse().unwrap()),
+        http_version: if args.iter().any(|arg| arg == "--http2-prior-knowledge") {
+            HttpVersion::Http2PriorKnowledge
+        } else {
+            HttpVersion::Negotiate
+        },
+        ..Default::default()
     };
-    handshake.encode(&mut stream).await;
-    let handshake = HandshakeResponse::decode(&mut stream).await;
-    (stream, handshake)
+    let report = announce_to_trackers(metainfo, req, &config).await;
+    for (tracker, e) in report.failures() {
+        eprintln!("{tracker}: {e}");
+    }
+    report.into_peers()
 }
@@ -0,0 +1,257 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    encode_bencoded_value,
+    extension::{ExtendedHandshake, HANDSHAKE_ID},
+    peer::{PeerConnection, PeerError},
+    try_decode_bencoded_value, InfoHash, MetainfoInfo, Value,
+};
+
+pub const UT_METADATA: &str = "ut_metadata";
+/// The extended message id we ask peers to send `ut_metadata` messages with
+const UT_METADATA_ID: u8 = 1;
+/// Every metadata piece but the last is this long
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+/// Refuses metadata larger than this, so a peer cannot make us allocate without bound
+const MAX_METADATA_SIZE: u32 = 16 * 1024 * 1024;
+
+const REQUEST: i64 = 0;
+const DATA: i64 = 1;
+const REJECT: i64 = 2;
+
+#[derive(Debug, thiserror::Error)]
+pub enum MetadataError {
+    #[error(transparent)]
+    Peer(#[from] PeerError),
+    #[error("peer does not serve metadata")]
+    Unsupported,
+    #[error("metadata of {0} bytes is too large")]
+    TooLarge(u32),
+    #[error("peer rejected the request for metadata piece {piece}")]
+    Rejected { piece: u32 },
+    #[error("malformed ut_metadata message")]
+    Malformed,
+    #[error("metadata does not match the info hash")]
+    HashMismatch,
+}
+
+/// Downloads the info dictionary from the peer with the `ut_metadata` extension (BEP 9),
+/// checking it against `info_hash`.
+///
+/// `conn` must not have exchanged any message since the handshake.
+pub async fn fetch_metadata(
+    conn: &mut PeerConnection,
+    info_hash: &InfoHash,
+) -> Result<MetainfoInfo, MetadataError> {
+    if !conn.handshake().supports_extensions() {
+        return Err(MetadataError::Unsupported);
+    }
+    let mut extensions = BTreeMap::new();
+    extensions.insert(UT_METADATA.to_owned(), UT_METADATA_ID);
+    conn.send_extended_handshake(&ExtendedHandshake::ours(extensions, 6881))
+        .await?;
+    let handshake = loop {
+        let payload = conn.recv_extended().await?;
+        if let Some((&HANDSHAKE_ID, dict)) = payload.split_first() {
+            break ExtendedHandshake::decode(dict);
+        }
+    };
+    let (Some(&peer_id), Some(metadata_size)) = (
+        handshake.extensions.get(UT_METADATA),
+        handshake.metadata_size,
+    ) else {
+        return Err(MetadataError::Unsupported);
+    };
+    if metadata_size > MAX_METADATA_SIZE {
+        return Err(MetadataError::TooLarge(metadata_size));
+    }
+
+    let metadata_size = metadata_size as usize;
+    let piece_count = (metadata_size + METADATA_PIECE_SIZE - 1) / METADATA_PIECE_SIZE;
+    let mut metadata = Vec::with_capacity(metadata_size);
+    for piece in 0..u32::try_from(piece_count).unwrap() {
+        conn.send_extended(peer_id, &message(REQUEST, piece, None))
+            .await?;
+        loop {
+            let payload = conn.recv_extended().await?;
+            let Some((&UT_METADATA_ID, message)) = payload.split_first() else {
+                continue;
+            };
+            let data = decode_data(message, piece)?;
+            let expected_len = (metadata_size - metadata.len()).min(METADATA_PIECE_SIZE);
+            if data.len() != expected_len {
+                return Err(MetadataError::Malformed);
+            }
+            metadata.extend(data);
+            break;
+        }
+    }
+
+    // Checked before decoding so that only the torrent's own info dictionary is parsed
+    use sha1::Digest;
+    if sha1::Sha1::digest(&metadata).as_slice() != info_hash.as_bytes() {
+        return Err(MetadataError::HashMismatch);
+    }
+    let (info, _) = try_decode_bencoded_value(&metadata).map_err(|_| MetadataError::Malformed)?;
+    let mut info = MetainfoInfo::decode(info).map_err(|_| MetadataError::Malformed)?;
+    info.set_encoded(metadata);
+    Ok(info)
+}
+
+fn message(msg_type: i64, piece: u32, total_size: Option<usize>) -> Vec<u8> {
+    let mut map = BTreeMap::new();
+    map.insert(b"msg_type".to_vec(), Value::Integer(msg_type));
+    map.insert(b"piece".to_vec(), Value::Integer(piece.into()));
+    if let Some(total_size) = total_size {
+        map.insert(b"total_size".to_vec(), Value::Integer(total_size as i64));
+    }
+    encode_bencoded_value(&Value::Dictionary(map))
+}
+
+/// Splits a data message for `piece` into its dictionary and the metadata bytes that follow.
+fn decode_data(message: &[u8], piece: u32) -> Result<&[u8], MetadataError> {
+    let (dict, read) = try_decode_bencoded_value(message).map_err(|_| MetadataError::Malformed)?;
+    let mut dict = dict.into_dictionary().ok_or(MetadataError::Malformed)?;
+    let mut field = |key: &[u8]| dict.remove(key).and_then(|value| value.into_integer());
+    match (field(b"msg_type"), field(b"piece")) {
+        (Some(DATA), Some(index)) if index == i64::from(piece) => Ok(&message[read..]),
+        (Some(REJECT), _) => Err(MetadataError::Rejected { piece }),
+        _ => Err(MetadataError::Malformed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::{download::tests::info_for, peer::tests::handshaking_peer};
+
+    const PEER_UT_METADATA_ID: u8 = 3;
+
+    fn info_buf() -> Vec<u8> {
+        // The hashes of 1000 pieces span two metadata pieces
+        encode_bencoded_value(&info_for(&[7; 1000 * 16], 16))
+    }
+
+    async fn send_extended(stream: &mut TcpStream, payload: &[u8]) {
+        stream
+            .write_u32(u32::try_from(payload.len() + 1).unwrap())
+            .await
+            .unwrap();
+        stream.write_u8(20).await.unwrap();
+        stream.write_all(payload).await.unwrap();
+    }
+
+    async fn recv(stream: &mut TcpStream) -> Vec<u8> {
+        let length = stream.read_u32().await.unwrap();
+        let mut message = vec![0; length as usize];
+        stream.read_exact(&mut message).await.unwrap();
+        message
+    }
+
+    /// Serves `metadata` after a bitfield, rejecting requests for pieces in `reject`.
+    async fn metadata_peer(listener: TcpListener, metadata: Vec<u8>, reject: Option<u32>) {
+        let mut stream = handshaking_peer(&listener).await;
+        stream.write_all(&[0, 0, 0, 2, 5, 0xff]).await.unwrap();
+
+        let ours = recv(&mut stream).await;
+        assert_eq!(ours[..2], [20, HANDSHAKE_ID]);
+        let ours = ExtendedHandshake::decode(&ours[2..]);
+        let our_id = ours.extensions[UT_METADATA];
+        let mut extensions = BTreeMap::new();
+        extensions.insert(UT_METADATA.to_owned(), PEER_UT_METADATA_ID);
+        let theirs = ExtendedHandshake {
+            metadata_size: Some(u32::try_from(metadata.len()).unwrap()),
+            ..ExtendedHandshake::ours(extensions, 6881)
+        };
+        send_extended(&mut stream, &theirs.encode()).await;
+
+        loop {
+            let request = recv(&mut stream).await;
+            assert_eq!(request[..2], [20, PEER_UT_METADATA_ID]);
+            let (dict, _) = try_decode_bencoded_value(&request[2..]).unwrap();
+            let mut dict = dict.into_dictionary().unwrap();
+            assert_eq!(
+                dict.remove(b"msg_type".as_slice()),
+                Some(Value::Integer(REQUEST))
+            );
+            let piece = dict
+                .remove(b"piece".as_slice())
+                .unwrap()
+                .into_integer()
+                .unwrap() as u32;
+            let mut payload = vec![our_id];
+            if reject == Some(piece) {
+                payload.extend(message(REJECT, piece, None));
+            } else {
+                payload.extend(message(DATA, piece, Some(metadata.len())));
+                let start = piece as usize * METADATA_PIECE_SIZE;
+                let end = (start + METADATA_PIECE_SIZE).min(metadata.len());
+                payload.extend(&metadata[start..end]);
+            }
+            send_extended(&mut stream, &payload).await;
+        }
+    }
+
+    async fn fetch(metadata: Vec<u8>, reject: Option<u32>) -> Result<MetainfoInfo, MetadataError> {
+        fetch_for(&info_buf(), metadata, reject).await
+    }
+
+    /// Fetches `metadata` from a peer while asking for the info dictionary `expected`.
+    async fn fetch_for(
+        expected: &[u8],
+        metadata: Vec<u8>,
+        reject: Option<u32>,
+    ) -> Result<MetainfoInfo, MetadataError> {
+        use sha1::Digest;
+        let info_hash = InfoHash::new(sha1::Sha1::digest(expected).into());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(metadata_peer(listener, metadata, reject));
+        let mut conn = PeerConnection::connect(peer, &info_hash, b"00112233445566778899")
+            .await
+            .unwrap();
+        fetch_metadata(&mut conn, &info_hash).await
+    }
+
+    #[tokio::test]
+    async fn test_fetch_metadata() {
+        let info = fetch(info_buf(), None).await.unwrap();
+        let (value, _) = try_decode_bencoded_value(&info_buf()).unwrap();
+        let expected = MetainfoInfo::decode(value).unwrap();
+        assert_eq!(info.hash(), expected.hash());
+        assert_eq!(info.name(), "content.bin");
+        assert_eq!(info.piece_hashes().count(), 1000);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_metadata_hash_mismatch() {
+        let mut metadata = info_buf();
+        let last = metadata.len() - 2;
+        metadata[last] ^= 0xff;
+        let res = fetch(metadata, None).await;
+        assert!(matches!(res, Err(MetadataError::HashMismatch)));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_malformed_metadata() {
+        let mut info = BTreeMap::new();
+        info.insert(b"length".to_vec(), Value::Integer(16));
+        let metadata = encode_bencoded_value(&Value::Dictionary(info));
+        // Metadata that is not the torrent's is rejected before it is parsed
+        let res = fetch(metadata.clone(), None).await;
+        assert!(matches!(res, Err(MetadataError::HashMismatch)));
+        let res = fetch_for(&metadata, metadata.clone(), None).await;
+        assert!(matches!(res, Err(MetadataError::Malformed)));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_metadata_rejected() {
+        let res = fetch(info_buf(), Some(1)).await;
+        assert!(matches!(res, Err(MetadataError::Rejected { piece: 1 })));
+    }
+}
@@ -0,0 +1,1723 @@
+use std::{
+    collections::VecDeque,
+    future::{self, Future},
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, BytesMut};
+use getset::{CopyGetters, Getters, Setters};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    bitfield::{Bitfield, BitfieldError},
+    extension::ExtendedHandshake,
+    rate::RateLimiter,
+    socks,
+    udp_tracker::random_u64,
+    verify::PieceVerifier,
+    HandshakeError, HandshakeRequest, HandshakeResponse, InfoHash, MetainfoInfo, PeerMessageId,
+    PeerMessageIn, PeerMessageOut, PeerMessageRequest, PeerMessageResponse, TypedPeerMessage,
+};
+
+pub const BLOCK_SIZE: u32 = 1 << 14;
+pub const INITIAL_PIPELINE_DEPTH: usize = 4;
+pub const MAX_PIPELINE_DEPTH: usize = 64;
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Live peers send at least a keep-alive every two minutes
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(150);
+/// Peers drop connections that stay silent for about two minutes
+pub const DEFAULT_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+/// Identifies this client at the start of its peer ids, in the Azureus style
+pub const CLIENT_PREFIX: &str = "-RS0001-";
+/// Room for the bitfield of a torrent with millions of pieces, far more than a block of
+/// [`crate::seed::MAX_REQUEST_LENGTH`] bytes needs
+pub const MAX_MESSAGE_LEN: u32 = 1 << 20;
+
+/// Generates a peer id made of `client_prefix`, such as [`CLIENT_PREFIX`], followed by random
+/// bytes.
+///
+/// # Panics
+///
+/// If `client_prefix` is longer than 20 bytes.
+pub fn generate_peer_id(client_prefix: &str) -> [u8; 20] {
+    let prefix = client_prefix.as_bytes();
+    assert!(
+        prefix.len() <= 20,
+        "peer id prefix {client_prefix:?} is too long"
+    );
+    let mut peer_id = [0; 20];
+    peer_id[..prefix.len()].copy_from_slice(prefix);
+    for chunk in peer_id[prefix.len()..].chunks_mut(8) {
+        chunk.copy_from_slice(&random_u64().to_ne_bytes()[..chunk.len()]);
+    }
+    peer_id
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PeerError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("expected {expected:?} message, got {got:?}")]
+    UnexpectedMessage {
+        expected: PeerMessageId,
+        got: PeerMessageId,
+    },
+    #[error("peer sent an unrequested block at offset {begin} of piece {index}")]
+    UnexpectedBlock { index: u32, begin: u32 },
+    #[error("peer rejected our request for {length} bytes at offset {begin} of piece {index}")]
+    RequestRejected { index: u32, begin: u32, length: u32 },
+    #[error(
+        "piece {index} failed hash verification: expected {}, got {}",
+        hex::encode(expected),
+        hex::encode(got)
+    )]
+    HashMismatch {
+        index: u32,
+        expected: [u8; 20],
+        got: [u8; 20],
+    },
+    #[error("piece {index} is out of range for a torrent of {piece_count} pieces")]
+    PieceOutOfRange { index: u32, piece_count: u32 },
+    #[error("hash of piece {index} is {length} bytes long instead of 20")]
+    MalformedPieceHash { index: u32, length: usize },
+    #[error("block of {length} bytes at offset {begin} runs past the end of piece {index}")]
+    BlockOutOfRange { index: u32, begin: u32, length: u32 },
+    #[error("request for {0} bytes is not between 1 and {max}", max = crate::seed::MAX_REQUEST_LENGTH)]
+    InvalidRequestLength(u32),
+    #[error("peer closed the connection")]
+    ConnectionClosed,
+    #[error("peer did not unchoke us within {0:?}")]
+    UnchokeTimeout(Duration),
+    #[error("peer did not respond within {0:?}")]
+    Timeout(Duration),
+    #[error(transparent)]
+    Handshake(#[from] HandshakeError),
+    #[error("peer sent a {message_id:?} message with a malformed {length} byte payload")]
+    MalformedMessage {
+        message_id: PeerMessageId,
+        length: usize,
+    },
+    #[error("peer sent a message with unknown id {0}")]
+    UnknownMessage(u8),
+    #[error("peer sent a {length} byte message, more than the limit of {max}")]
+    MessageTooLarge { length: u32, max: u32 },
+    #[error(transparent)]
+    InvalidBitfield(#[from] BitfieldError),
+}
+
+#[derive(Debug, Clone, CopyGetters)]
+#[getset(get_copy = "pub")]
+pub struct ConnectionStats {
+    /// How many block requests are kept in flight
+    pipeline_depth: usize,
+    blocks_received: u64,
+}
+
+impl Default for ConnectionStats {
+    fn default() -> Self {
+        Self {
+            pipeline_depth: INITIAL_PIPELINE_DEPTH,
+            blocks_received: 0,
+        }
+    }
+}
+
+#[derive(Debug, Getters, CopyGetters, Setters)]
+pub struct PeerConnection {
+    stream: TcpStream,
+    recv_buf: BytesMut,
+    read_buffer_size: usize,
+    max_message_len: u32,
+    piece_count: Option<u32>,
+    unchoke_timeout: Duration,
+    read_timeout: Duration,
+    download_limiter: Option<Arc<RateLimiter>>,
+    max_pipeline_depth: usize,
+    block_size: u32,
+    #[getset(get = "pub")]
+    handshake: HandshakeResponse,
+    #[getset(get = "pub")]
+    stats: ConnectionStats,
+    /// What the peer announced having, once its bitfield has been read
+    bitfield: Option<Bitfield>,
+    #[getset(get_copy = "pub")]
+    choked: bool,
+    /// Pieces the peer hinted we should fetch with `Suggest Piece`
+    #[getset(get = "pub")]
+    suggested: Vec<u32>,
+    /// Pieces we may request even while choked (fast extension)
+    #[getset(get = "pub")]
+    allowed_fast: Vec<u32>,
+    /// The UDP port of the peer's DHT node, once it sent a `Port` message (BEP 5)
+    #[getset(get_copy = "pub")]
+    dht_port: Option<u16>,
+    /// How long to wait for a block before shrinking the pipeline
+    #[getset(get_copy = "pub", set = "pub")]
+    request_timeout: Duration,
+    /// Sends a keep-alive once we have sent nothing for this long while waiting on the peer
+    #[getset(get_copy = "pub", set = "pub")]
+    keep_alive_interval: Duration,
+    last_sent: Instant,
+    /// When the peer was last heard from, or asked for a block if that was later, so that time
+    /// spent idle between pieces does not count towards the read timeout
+    silent_since: Instant,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    /// Reaches peers through this SOCKS5 proxy instead of dialing them directly
+    pub socks5_proxy: Option<SocketAddr>,
+    /// How many bytes to read from the socket at a time
+    pub read_buffer_size: usize,
+    /// Drops the peer rather than buffering a message whose length prefix exceeds this
+    pub max_message_len: u32,
+    /// How long a peer may keep us choked after we declared interest
+    pub unchoke_timeout: Duration,
+    /// Sends small request messages immediately instead of batching them (`TCP_NODELAY`)
+    pub nodelay: bool,
+    /// Lets the OS probe idle connections after this long to detect dead peers
+    pub keepalive: Option<Duration>,
+    /// Caps how many block requests the adaptive pipeline keeps in flight
+    pub max_pipeline_depth: usize,
+    /// How many bytes each block request asks for, clamped to between 1 and [`BLOCK_SIZE`] as
+    /// peers drop larger requests; the last block of a piece may be shorter
+    pub block_size: u32,
+    /// How long to wait for the TCP connection, through the proxy if any, before giving up
+    pub connect_timeout: Duration,
+    /// How long the peer may take to answer our handshake
+    pub handshake_timeout: Duration,
+    /// How long the peer may stay silent, keep-alives included, once connected
+    pub read_timeout: Duration,
+    /// Paces block requests so that the blocks they ask for arrive no faster than its rate
+    pub download_limiter: Option<Arc<RateLimiter>>,
+    /// The torrent's piece count, which the peer's bitfield must match; unknown while fetching
+    /// the metadata, in which case every bit of the bitfield counts
+    pub piece_count: Option<u32>,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            socks5_proxy: None,
+            read_buffer_size: 64 * 1024,
+            max_message_len: MAX_MESSAGE_LEN,
+            unchoke_timeout: Duration::from_secs(30),
+            nodelay: true,
+            keepalive: None,
+            max_pipeline_depth: MAX_PIPELINE_DEPTH,
+            block_size: BLOCK_SIZE,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            download_limiter: None,
+            piece_count: None,
+        }
+    }
+}
+
+impl PeerConnection {
+    pub async fn connect(
+        peer: SocketAddr,
+        info_hash: &InfoHash,
+        peer_id: &[u8; 20],
+    ) -> Result<Self, PeerError> {
+        Self::connect_with(peer, info_hash, peer_id, &ConnectOptions::default()).await
+    }
+
+    pub async fn connect_with(
+        peer: SocketAddr,
+        info_hash: &InfoHash,
+        peer_id: &[u8; 20],
+        options: &ConnectOptions,
+    ) -> Result<Self, PeerError> {
+        let stream = async {
+            match options.socks5_proxy {
+                Some(proxy) => socks::connect_via(proxy, peer).await,
+                None => TcpStream::connect(peer).await,
+            }
+        };
+        let mut stream = tokio::time::timeout(options.connect_timeout, stream)
+            .await
+            .map_err(|_| PeerError::Timeout(options.connect_timeout))??;
+        stream.set_nodelay(options.nodelay)?;
+        if let Some(keepalive) = options.keepalive {
+            let keepalive = socket2::TcpKeepalive::new().with_time(keepalive);
+            socket2::SockRef::from(&stream).set_tcp_keepalive(&keepalive)?;
+        }
+        let handshake = tokio::time::timeout(options.handshake_timeout, async {
+            HandshakeRequest { info_hash, peer_id }
+                .encode(&mut stream)
+                .await?;
+            HandshakeResponse::decode(&mut stream).await
+        })
+        .await
+        .map_err(|_| PeerError::Timeout(options.handshake_timeout))??;
+        handshake.verify(info_hash)?;
+        let max_pipeline_depth = options.max_pipeline_depth.max(1);
+        Ok(Self {
+            stream,
+            recv_buf: BytesMut::with_capacity(options.read_buffer_size),
+            read_buffer_size: options.read_buffer_size.max(1),
+            max_message_len: options.max_message_len,
+            piece_count: options.piece_count,
+            unchoke_timeout: options.unchoke_timeout,
+            read_timeout: options.read_timeout,
+            download_limiter: options.download_limiter.clone(),
+            max_pipeline_depth,
+            block_size: options.block_size.clamp(1, BLOCK_SIZE),
+            handshake,
+            stats: ConnectionStats {
+                pipeline_depth: INITIAL_PIPELINE_DEPTH.min(max_pipeline_depth),
+                ..Default::default()
+            },
+            bitfield: None,
+            choked: true,
+            suggested: vec![],
+            allowed_fast: vec![],
+            dht_port: None,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            keep_alive_interval: DEFAULT_KEEP_ALIVE_INTERVAL,
+            last_sent: Instant::now(),
+            silent_since: Instant::now(),
+        })
+    }
+
+    /// Advertises the pieces we have, which must be the first message after the handshake.
+    pub async fn send_bitfield(&mut self, bitfield: &Bitfield) -> Result<(), PeerError> {
+        self.send(PeerMessageId::Bitfield, bitfield.as_bytes())
+            .await
+    }
+
+    /// Tells the peer we now have the piece at `index`.
+    pub async fn send_have(&mut self, index: u32) -> Result<(), PeerError> {
+        self.send(PeerMessageId::Have, &index.to_be_bytes()).await
+    }
+
+    /// Sends our BEP 10 extended handshake.
+    pub async fn send_extended_handshake(
+        &mut self,
+        handshake: &ExtendedHandshake,
+    ) -> Result<(), PeerError> {
+        self.send(PeerMessageId::Extended, &handshake.encode())
+            .await
+    }
+
+    /// Sends the extended message `id` the peer assigned to an extension in its handshake.
+    pub async fn send_extended(&mut self, id: u8, payload: &[u8]) -> Result<(), PeerError> {
+        let mut message = vec![id];
+        message.extend(payload);
+        self.send(PeerMessageId::Extended, &message).await
+    }
+
+    /// Reads messages until an extended one arrives and returns its payload, starting with the
+    /// extended message id. A bitfield read along the way is kept.
+    pub async fn recv_extended(&mut self) -> Result<Vec<u8>, PeerError> {
+        loop {
+            let message = self.recv_message().await?;
+            match message.message_id() {
+                PeerMessageId::Extended => return Ok(message.payload().clone()),
+                PeerMessageId::Bitfield => self.record_bitfield(&message)?,
+                _ => {}
+            }
+        }
+    }
+
+    /// Reads the peer's bitfield, declares interest and waits to be unchoked.
+    pub async fn unchoke(&mut self) -> Result<(), PeerError> {
+        self.declare_interest().await?;
+        self.wait_for_unchoke().await
+    }
+
+    /// Reads the peer's bitfield and declares interest without waiting to be unchoked, so that
+    /// allowed fast pieces can already be requested.
+    pub async fn declare_interest(&mut self) -> Result<(), PeerError> {
+        self.read_bitfield().await?;
+        self.send(PeerMessageId::Interested, &[]).await
+    }
+
+    /// Reads the bitfield the peer sends right after the handshake, or the `HaveAll` or
+    /// `HaveNone` a peer speaking the fast extension may send instead.
+    pub async fn read_bitfield(&mut self) -> Result<(), PeerError> {
+        loop {
+            let message = self.recv_message().await?;
+            match message.message_id() {
+                PeerMessageId::Bitfield => return self.record_bitfield(&message),
+                PeerMessageId::HaveAll | PeerMessageId::HaveNone => return Ok(()),
+                id if recorded_on_receipt(id) => {}
+                got => {
+                    return Err(PeerError::UnexpectedMessage {
+                        expected: PeerMessageId::Bitfield,
+                        got,
+                    })
+                }
+            }
+        }
+    }
+
+    /// Keeps the peer's bitfield, failing with [`PeerError::InvalidBitfield`] if it does not fit
+    /// [`ConnectOptions::piece_count`].
+    fn record_bitfield(&mut self, message: &PeerMessageIn) -> Result<(), PeerError> {
+        let bitfield = match self.piece_count {
+            Some(piece_count) => Bitfield::from_message(message, piece_count)?,
+            None => {
+                let length = message.payload().len();
+                let piece_count =
+                    u32::try_from(length * 8).map_err(|_| PeerError::MalformedMessage {
+                        message_id: PeerMessageId::Bitfield,
+                        length,
+                    })?;
+                Bitfield::from_bytes(message.payload(), piece_count)
+            }
+        };
+        self.bitfield = Some(bitfield);
+        Ok(())
+    }
+
+    /// Gives up with [`PeerError::UnchokeTimeout`] if the peer keeps us choked for longer than
+    /// [`ConnectOptions::unchoke_timeout`].
+    pub async fn wait_for_unchoke(&mut self) -> Result<(), PeerError> {
+        if !self.choked {
+            return Ok(());
+        }
+        let unchoke_timeout = self.unchoke_timeout;
+        tokio::time::timeout(unchoke_timeout, self.expect_message(PeerMessageId::Unchoke))
+            .await
+            .map_err(|_| PeerError::UnchokeTimeout(unchoke_timeout))??;
+        Ok(())
+    }
+
+    /// Waits until we are unchoked or the peer allows another piece fast, subject to the same
+    /// timeout as [`Self::wait_for_unchoke`].
+    pub async fn wait_for_permission(&mut self) -> Result<(), PeerError> {
+        let unchoke_timeout = self.unchoke_timeout;
+        tokio::time::timeout(unchoke_timeout, async {
+            while self.choked {
+                let message = self.recv_message().await?;
+                match message.message_id() {
+                    PeerMessageId::AllowedFast => return Ok(()),
+                    // Rejects of requests dropped by a choke are expected
+                    PeerMessageId::Unchoke | PeerMessageId::Reject => {}
+                    id if recorded_on_receipt(id) => {}
+                    got => {
+                        return Err(PeerError::UnexpectedMessage {
+                            expected: PeerMessageId::Unchoke,
+                            got,
+                        })
+                    }
+                }
+            }
+            Ok(())
+        })
+        .await
+        .map_err(|_| PeerError::UnchokeTimeout(unchoke_timeout))?
+    }
+
+    /// Reads messages until the peer announces another piece with `Have`, for when it has none
+    /// of the pieces we still need. Blocks of requests cancelled earlier are skipped.
+    pub async fn wait_for_have(&mut self) -> Result<(), PeerError> {
+        loop {
+            let message = self.recv_message().await?;
+            match message.message_id() {
+                PeerMessageId::Have | PeerMessageId::HaveAll => return Ok(()),
+                PeerMessageId::Unchoke | PeerMessageId::Piece | PeerMessageId::Reject => {}
+                id if recorded_on_receipt(id) => {}
+                got => {
+                    return Err(PeerError::UnexpectedMessage {
+                        expected: PeerMessageId::Have,
+                        got,
+                    })
+                }
+            }
+        }
+    }
+
+    /// The pieces the peer announced in its bitfield or with `Have` since, sized by the torrent's
+    /// piece count if it is known and by the bitfield message otherwise.
+    pub fn bitfield(&self) -> Option<&Bitfield> {
+        self.bitfield.as_ref()
+    }
+
+    /// Whether the peer announced the piece at `index`, in its bitfield or a later `Have`.
+    pub fn has_piece(&self, index: u32) -> bool {
+        self.bitfield
+            .as_ref()
+            .is_some_and(|bitfield| bitfield.has_piece(index))
+    }
+
+    /// Lists the pieces the peer announced in its bitfield or with `Have` since, which is empty
+    /// before [`Self::read_bitfield`].
+    pub fn available_pieces(&self) -> impl Iterator<Item = u32> + '_ {
+        self.bitfield
+            .iter()
+            .flat_map(|bitfield| bitfield.iter_set())
+    }
+
+    /// Requests every block of the piece, reassembles them and verifies the result against
+    /// `expected_hash`.
+    pub async fn download_piece(
+        &mut self,
+        index: u32,
+        piece_len: usize,
+        expected_hash: &[u8; 20],
+    ) -> Result<Vec<u8>, PeerError> {
+        let piece = self
+            .download_piece_until(index, piece_len, expected_hash, future::pending())
+            .await?;
+        Ok(piece.expect("the piece never completes elsewhere"))
+    }
+
+    /// Downloads the piece at `index` of the torrent described by `info`, including a short last
+    /// piece, and verifies it against the torrent's piece hash.
+    ///
+    /// Fails with [`PeerError::PieceOutOfRange`] before sending anything if the torrent has no
+    /// such piece, and with [`PeerError::MalformedPieceHash`] if its hash is truncated.
+    pub async fn download_piece_of(
+        &mut self,
+        info: &MetainfoInfo,
+        index: u32,
+    ) -> Result<Vec<u8>, PeerError> {
+        let Some(piece) = info.pieces().nth(index as usize) else {
+            return Err(PeerError::PieceOutOfRange {
+                index,
+                piece_count: info.piece_hashes().count() as u32,
+            });
+        };
+        let hash = piece
+            .hash()
+            .try_into()
+            .map_err(|_| PeerError::MalformedPieceHash {
+                index,
+                length: piece.hash().len(),
+            })?;
+        self.download_piece(index, piece.length() as usize, hash)
+            .await
+    }
+
+    /// Like [`Self::download_piece`], but gives up once `completed_elsewhere` resolves, sending a
+    /// `Cancel` for every block still in flight and returning `None`.
+    ///
+    /// Up to [`ConnectionStats::pipeline_depth`] blocks are requested at a time. The depth grows
+    /// by one each time a full pipeline of blocks arrives without a timeout and halves whenever
+    /// the peer goes quiet for the request timeout.
+    ///
+    /// While choked, nothing is requested until the peer unchokes us or allows the piece fast.
+    /// A peer that chokes us mid-piece drops our outstanding requests, so we declare interest
+    /// again and re-send them once unchoked, giving up with [`PeerError::UnchokeTimeout`] after
+    /// the unchoke timeout.
+    pub async fn download_piece_until<F>(
+        &mut self,
+        index: u32,
+        piece_len: usize,
+        expected_hash: &[u8; 20],
+        completed_elsewhere: F,
+    ) -> Result<Option<Vec<u8>>, PeerError>
+    where
+        F: Future<Output = ()>,
+    {
+        tokio::pin!(completed_elsewhere);
+        let piece_length = u32::try_from(piece_len).unwrap();
+        let mut piece = vec![0; piece_len];
+        let mut next_begin = 0;
+        let mut in_flight = VecDeque::new();
+        // Requests the peer dropped when it choked us
+        let mut dropped = VecDeque::new();
+        // Blocks received since the depth last changed
+        let mut streak = 0;
+        while next_begin < piece_length || !in_flight.is_empty() || !dropped.is_empty() {
+            if self.choked && !self.allowed_fast.contains(&index) {
+                let unchoke_timeout = self.unchoke_timeout;
+                let give_up = tokio::time::sleep(unchoke_timeout);
+                tokio::pin!(give_up);
+                while self.choked && !self.allowed_fast.contains(&index) {
+                    tokio::select! {
+                        message = self.recv_message() => {
+                            message?;
+                        }
+                        () = &mut completed_elsewhere => return Ok(None),
+                        () = &mut give_up => {
+                            return Err(PeerError::UnchokeTimeout(unchoke_timeout));
+                        }
+                    }
+                }
+            }
+            while in_flight.len() < self.stats.pipeline_depth {
+                let req = match dropped.pop_front() {
+                    Some(req) => req,
+                    None if next_begin < piece_length => {
+                        let req = PeerMessageRequest {
+                            index,
+                            begin: next_begin,
+                            length: (piece_length - next_begin).min(self.block_size),
+                        };
+                        next_begin += req.length;
+                        req
+                    }
+                    None => break,
+                };
+                self.send_request(PeerMessageId::Request, &req).await?;
+                in_flight.push_back(req);
+            }
+
+            let message = tokio::select! {
+                message = tokio::time::timeout(self.request_timeout, self.recv_message()) => {
+                    message
+                }
+                () = &mut completed_elsewhere => {
+                    for req in &in_flight {
+                        self.send_request(PeerMessageId::Cancel, req).await?;
+                    }
+                    return Ok(None);
+                }
+            };
+            let Ok(message) = message else {
+                self.stats.pipeline_depth = (self.stats.pipeline_depth / 2).max(1);
+                streak = 0;
+                continue;
+            };
+            let message = message?;
+            if message.message_id() == PeerMessageId::Choke {
+                // Requests for a piece allowed fast are still answered
+                if !self.allowed_fast.contains(&index) {
+                    dropped.extend(in_flight.drain(..));
+                    self.send(PeerMessageId::Interested, &[]).await?;
+                }
+                continue;
+            }
+            if message.message_id() == PeerMessageId::Reject {
+                let Ok(TypedPeerMessage::Reject(req)) = message.into_typed() else {
+                    continue;
+                };
+                // A fast peer rejects the requests it drops on choking us, which are re-sent
+                // once unchoked
+                if !in_flight.contains(&req) {
+                    continue;
+                }
+                return Err(PeerError::RequestRejected {
+                    index: req.index,
+                    begin: req.begin,
+                    length: req.length,
+                });
+            }
+            if message.message_id() == PeerMessageId::Unchoke
+                || recorded_on_receipt(message.message_id())
+            {
+                continue;
+            }
+            if message.message_id() != PeerMessageId::Piece {
+                return Err(PeerError::UnexpectedMessage {
+                    expected: PeerMessageId::Piece,
+                    got: message.message_id(),
+                });
+            }
+            let payload_length = message.payload().len();
+            let mut payload = io::Cursor::new(message.payload());
+            let resp = PeerMessageResponse::decode(&mut payload, payload_length).await?;
+            // Blocks of cancelled requests may still trickle in
+            let Some(position) = in_flight
+                .iter()
+                .position(|req| req.index == resp.index() && req.begin == resp.begin())
+            else {
+                continue;
+            };
+            let req = in_flight.remove(position).unwrap();
+            if resp.block().len() != req.length as usize {
+                return Err(PeerError::UnexpectedBlock {
+                    index: resp.index(),
+                    begin: resp.begin(),
+                });
+            }
+            let begin = req.begin as usize;
+            piece[begin..begin + resp.block().len()].copy_from_slice(resp.block());
+            self.stats.blocks_received += 1;
+
+            streak += 1;
+            if streak >= self.stats.pipeline_depth {
+                self.stats.pipeline_depth =
+                    (self.stats.pipeline_depth + 1).min(self.max_pipeline_depth);
+                streak = 0;
+            }
+        }
+
+        let mut verifier = PieceVerifier::new();
+        verifier.update(&piece);
+        let got = verifier.finalize();
+        if &got != expected_hash {
+            return Err(PeerError::HashMismatch {
+                index,
+                expected: *expected_hash,
+                got,
+            });
+        }
+        Ok(Some(piece))
+    }
+
+    async fn send(&mut self, message_id: PeerMessageId, payload: &[u8]) -> Result<(), PeerError> {
+        PeerMessageOut {
+            message_id,
+            payload,
+        }
+        .encode(&mut self.stream)
+        .await?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    async fn send_request(
+        &mut self,
+        message_id: PeerMessageId,
+        req: &PeerMessageRequest,
+    ) -> Result<(), PeerError> {
+        if let (PeerMessageId::Request, Some(limiter)) = (message_id, &self.download_limiter) {
+            limiter.acquire(req.length.into()).await;
+        }
+        let mut payload = vec![];
+        req.encode(&mut payload).await?;
+        self.send(message_id, &payload).await?;
+        if message_id == PeerMessageId::Request {
+            self.silent_since = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Reads the next message.
+    ///
+    /// This is cancel safe: a partially received message stays buffered for the next call.
+    async fn recv_message(&mut self) -> Result<PeerMessageIn, PeerError> {
+        loop {
+            if self.recv_buf.len() >= 4 {
+                let message_length = (&self.recv_buf[..4]).get_u32();
+                if message_length > self.max_message_len {
+                    return Err(PeerError::MessageTooLarge {
+                        length: message_length,
+                        max: self.max_message_len,
+                    });
+                }
+                let message_length = message_length as usize;
+                if self.recv_buf.len() >= 4 + message_length {
+                    let message = self.recv_buf.split_to(4 + message_length);
+                    let max_message_len = self.max_message_len;
+                    let Some(message) =
+                        PeerMessageIn::decode_with_limit(&mut &message[..], max_message_len)
+                            .await?
+                    else {
+                        continue;
+                    };
+                    match message.message_id() {
+                        PeerMessageId::Choke => self.choked = true,
+                        PeerMessageId::Unchoke => self.choked = false,
+                        PeerMessageId::Suggest | PeerMessageId::AllowedFast => {
+                            self.record_hint(&message);
+                        }
+                        PeerMessageId::Have => self.record_have(&message),
+                        PeerMessageId::HaveAll | PeerMessageId::HaveNone => {
+                            self.record_have_all(&message);
+                        }
+                        PeerMessageId::Port => self.record_port(&message),
+                        _ => {}
+                    }
+                    return Ok(message);
+                }
+            }
+            self.recv_buf.reserve(self.read_buffer_size);
+            let keep_alive_at = self.last_sent + self.keep_alive_interval;
+            let give_up_at = self.silent_since + self.read_timeout;
+            let read = tokio::select! {
+                read = self.stream.read_buf(&mut self.recv_buf) => read?,
+                () = tokio::time::sleep_until(keep_alive_at.into()) => {
+                    self.stream.write_all(&[0; 4]).await?;
+                    self.last_sent = Instant::now();
+                    continue;
+                }
+                () = tokio::time::sleep_until(give_up_at.into()) => {
+                    return Err(PeerError::Timeout(self.read_timeout));
+                }
+            };
+            self.silent_since = Instant::now();
+            if read == 0 {
+                if self.recv_buf.is_empty() {
+                    return Err(PeerError::ConnectionClosed);
+                }
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+    }
+
+    fn record_hint(&mut self, message: &PeerMessageIn) {
+        let Ok(index) = <[u8; 4]>::try_from(message.payload().as_slice()) else {
+            return;
+        };
+        let index = u32::from_be_bytes(index);
+        let hints = match message.message_id() {
+            PeerMessageId::Suggest => &mut self.suggested,
+            _ => &mut self.allowed_fast,
+        };
+        if !hints.contains(&index) {
+            hints.push(index);
+        }
+    }
+
+    fn record_have(&mut self, message: &PeerMessageIn) {
+        let Ok(index) = <[u8; 4]>::try_from(message.payload().as_slice()) else {
+            return;
+        };
+        // Peers with no pieces may skip the bitfield
+        let bitfield = match (&mut self.bitfield, self.piece_count) {
+            (Some(bitfield), _) => bitfield,
+            (None, Some(piece_count)) => self.bitfield.insert(Bitfield::new(piece_count)),
+            (None, None) => return,
+        };
+        let index = u32::from_be_bytes(index);
+        if index < bitfield.piece_count() {
+            bitfield.set(index);
+        }
+    }
+
+    /// Keeps a `HaveAll` or `HaveNone` in place of the bitfield, which needs the piece count.
+    fn record_have_all(&mut self, message: &PeerMessageIn) {
+        let (Some(piece_count), true) = (self.piece_count, message.payload().is_empty()) else {
+            return;
+        };
+        let mut bitfield = Bitfield::new(piece_count);
+        if message.message_id() == PeerMessageId::HaveAll {
+            (0..piece_count).for_each(|index| bitfield.set(index));
+        }
+        self.bitfield = Some(bitfield);
+    }
+
+    fn record_port(&mut self, message: &PeerMessageIn) {
+        if let Ok(port) = <[u8; 2]>::try_from(message.payload().as_slice()) {
+            self.dht_port = Some(u16::from_be_bytes(port));
+        }
+    }
+
+    async fn expect_message(
+        &mut self,
+        expected: PeerMessageId,
+    ) -> Result<PeerMessageIn, PeerError> {
+        let message = loop {
+            let message = self.recv_message().await?;
+            if !recorded_on_receipt(message.message_id()) {
+                break message;
+            }
+        };
+        if message.message_id() != expected {
+            return Err(PeerError::UnexpectedMessage {
+                expected,
+                got: message.message_id(),
+            });
+        }
+        Ok(message)
+    }
+}
+
+/// Whether [`PeerConnection::recv_message`] keeps all there is to the message, so that callers
+/// waiting for something else can skip it.
+fn recorded_on_receipt(message_id: PeerMessageId) -> bool {
+    matches!(
+        message_id,
+        PeerMessageId::Choke
+            | PeerMessageId::Suggest
+            | PeerMessageId::AllowedFast
+            | PeerMessageId::Have
+            | PeerMessageId::HaveAll
+            | PeerMessageId::HaveNone
+            | PeerMessageId::Port
+    )
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::BTreeMap;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::{Metainfo, Value};
+
+    /// Accepts a connection and answers its handshake with the same bytes, so that the info hash
+    /// and reserved bits match whatever the dialer sent.
+    pub(crate) async fn handshaking_peer(listener: &TcpListener) -> TcpStream {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut handshake = [0; 68];
+        stream.read_exact(&mut handshake).await.unwrap();
+        stream.write_all(&handshake).await.unwrap();
+        stream
+    }
+
+    /// A `Bitfield` message announcing all `piece_count` pieces.
+    pub(crate) fn full_bitfield(piece_count: u32) -> Vec<u8> {
+        let mut bitfield = Bitfield::new(piece_count);
+        (0..piece_count).for_each(|index| bitfield.set(index));
+        let mut message = (bitfield.as_bytes().len() as u32 + 1)
+            .to_be_bytes()
+            .to_vec();
+        message.push(PeerMessageId::Bitfield.code());
+        message.extend_from_slice(bitfield.as_bytes());
+        message
+    }
+
+    /// Completes the handshake and unchokes, then serves blocks of `content` split into pieces of
+    /// `piece_length` bytes.
+    pub(crate) async fn serving_peer(listener: TcpListener, content: Vec<u8>, piece_length: u32) {
+        let stream = handshaking_peer(&listener).await;
+        serve_connection(stream, content, piece_length).await;
+    }
+
+    /// Like [`serving_peer`], for a connection whose handshake is done.
+    async fn serve_connection(mut stream: TcpStream, content: Vec<u8>, piece_length: u32) {
+        let piece_count = (content.len() as u32 + piece_length - 1) / piece_length;
+        stream.write_all(&full_bitfield(piece_count)).await.unwrap();
+        let mut interested = [0; 5];
+        stream.read_exact(&mut interested).await.unwrap();
+        stream.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+        serve_blocks(stream, content, piece_length).await;
+    }
+
+    /// Answers every request with the block of `content` it asks for, ignoring other messages.
+    pub(crate) async fn serve_blocks(mut stream: TcpStream, content: Vec<u8>, piece_length: u32) {
+        loop {
+            let Ok(message_length) = stream.read_u32().await else {
+                return;
+            };
+            let mut message = vec![0; message_length as usize];
+            if stream.read_exact(&mut message).await.is_err() {
+                return;
+            }
+            if message.first() != Some(&PeerMessageId::Request.code()) {
+                continue;
+            }
+            let index = u32::from_be_bytes(message[1..5].try_into().unwrap());
+            let begin = u32::from_be_bytes(message[5..9].try_into().unwrap());
+            let length = u32::from_be_bytes(message[9..13].try_into().unwrap());
+            let offset = (index * piece_length + begin) as usize;
+            let block = &content[offset..offset + length as usize];
+            let mut message = vec![];
+            message.extend((9 + length).to_be_bytes());
+            message.push(7);
+            message.extend(index.to_be_bytes());
+            message.extend(begin.to_be_bytes());
+            message.extend(block);
+            if stream.write_all(&message).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    async fn connect(piece: Vec<u8>) -> PeerConnection {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        let piece_length = piece.len() as u32;
+        tokio::spawn(serving_peer(listener, piece, piece_length));
+        let mut conn =
+            PeerConnection::connect(peer, &InfoHash::new([1; 20]), b"00112233445566778899")
+                .await
+                .unwrap();
+        conn.unchoke().await.unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_generate_peer_id() {
+        let a = generate_peer_id(CLIENT_PREFIX);
+        let b = generate_peer_id(CLIENT_PREFIX);
+        assert_ne!(a, b);
+        assert!(a.starts_with(b"-RS0001-"));
+        assert!(b.starts_with(b"-RS0001-"));
+        assert_eq!(
+            &generate_peer_id("00112233445566778899"),
+            b"00112233445566778899"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_download_piece() {
+        let piece = (0..BLOCK_SIZE * 2 + 7).map(|i| i as u8).collect::<Vec<_>>();
+        use sha1::Digest;
+        let hash: [u8; 20] = sha1::Sha1::digest(&piece).into();
+        let mut conn = connect(piece.clone()).await;
+        assert_eq!(conn.handshake().info_hash(), &InfoHash::new([1; 20]));
+        assert_eq!(conn.available_pieces().collect::<Vec<_>>(), &[0]);
+        let downloaded = conn.download_piece(0, piece.len(), &hash).await.unwrap();
+        assert_eq!(downloaded, piece);
+    }
+
+    #[tokio::test]
+    async fn test_download_piece_out_of_range() {
+        let piece_length = BLOCK_SIZE;
+        let content = vec![7; piece_length as usize * 2 + 10];
+        let metainfo = crate::download::tests::metainfo_for(&content, piece_length);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(serving_peer(listener, content.clone(), piece_length));
+        let mut conn =
+            PeerConnection::connect(peer, &InfoHash::new([1; 20]), b"00112233445566778899")
+                .await
+                .unwrap();
+        conn.unchoke().await.unwrap();
+        let piece = conn.download_piece_of(metainfo.info(), 2).await.unwrap();
+        assert_eq!(piece, vec![7; 10]);
+        for index in [3, u32::MAX] {
+            let res = conn.download_piece_of(metainfo.info(), index).await;
+            assert!(matches!(
+                res,
+                Err(PeerError::PieceOutOfRange { index: got, piece_count: 3 }) if got == index
+            ));
+        }
+
+        // The hash of the last piece is cut short
+        let Value::Dictionary(mut info) = crate::download::tests::info_for(&content, piece_length)
+        else {
+            unreachable!()
+        };
+        info.insert("pieces".into(), Value::Bytes(vec![0; 50]));
+        let mut map = BTreeMap::new();
+        map.insert("announce".into(), Value::Bytes(b"http://tracker".into()));
+        map.insert("info".into(), Value::Dictionary(info));
+        let metainfo = Metainfo::decode(Value::Dictionary(map)).unwrap();
+        assert!(matches!(
+            conn.download_piece_of(metainfo.info(), 2).await,
+            Err(PeerError::MalformedPieceHash {
+                index: 2,
+                length: 10
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_reuse_connection() {
+        let piece_length = BLOCK_SIZE;
+        let content = (0..piece_length * 3)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let metainfo = crate::download::tests::metainfo_for(&content, piece_length);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        let accepted = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        tokio::spawn({
+            let accepted = Arc::clone(&accepted);
+            let content = content.clone();
+            async move {
+                loop {
+                    let stream = handshaking_peer(&listener).await;
+                    accepted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    tokio::spawn(serve_connection(stream, content.clone(), piece_length));
+                }
+            }
+        });
+
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_file_path = output_dir.path().join("content.bin");
+
+        // Every piece is downloaded over the one connection
+        let report = crate::download::download_all(
+            &metainfo,
+            &[peer],
+            b"00112233445566778899",
+            &crate::download::DownloadConfig::default(),
+            &output_file_path,
+            tokio_util::sync::CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert!(report.is_complete());
+        assert_eq!(std::fs::read(&output_file_path).unwrap(), content);
+        assert_eq!(accepted.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_block_size() {
+        // Neither block size divides the piece evenly, so the last block is short
+        let piece = (0..BLOCK_SIZE * 2 + 7).map(|i| i as u8).collect::<Vec<_>>();
+        use sha1::Digest;
+        let hash: [u8; 20] = sha1::Sha1::digest(&piece).into();
+        // Larger blocks are capped at the largest size peers accept
+        for (block_size, blocks) in [(5000, 7), (BLOCK_SIZE, 3), (BLOCK_SIZE * 2, 3)] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let peer = listener.local_addr().unwrap();
+            let piece_length = piece.len() as u32;
+            tokio::spawn(serving_peer(listener, piece.clone(), piece_length));
+            let options = ConnectOptions {
+                block_size,
+                ..Default::default()
+            };
+
+            let mut conn = PeerConnection::connect_with(
+                peer,
+                &InfoHash::new([1; 20]),
+                b"00112233445566778899",
+                &options,
+            )
+            .await
+            .unwrap();
+            conn.unchoke().await.unwrap();
+            let downloaded = conn.download_piece(0, piece.len(), &hash).await.unwrap();
+            assert_eq!(downloaded, piece);
+            assert_eq!(conn.stats().blocks_received(), blocks, "{block_size}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout() {
+        // The proxy accepts the connection but never answers the greeting
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let options = ConnectOptions {
+            socks5_proxy: Some(proxy.local_addr().unwrap()),
+            connect_timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let res = PeerConnection::connect_with(
+            "127.0.0.1:6881".parse().unwrap(),
+            &InfoHash::new([1; 20]),
+            b"00112233445566778899",
+            &options,
+        )
+        .await;
+        assert!(matches!(res, Err(PeerError::Timeout(_))));
+        drop(proxy);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        // The connection is queued by the OS but never accepted or answered
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let options = ConnectOptions {
+            handshake_timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let res = PeerConnection::connect_with(
+            listener.local_addr().unwrap(),
+            &InfoHash::new([1; 20]),
+            b"00112233445566778899",
+            &options,
+        )
+        .await;
+        assert!(
+            matches!(res, Err(PeerError::Timeout(timeout)) if timeout == Duration::from_millis(50))
+        );
+        assert!(start.elapsed() < Duration::from_secs(2));
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn test_info_hash_mismatch() {
+        // Answers with the handshake of another torrent
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0; 68];
+            stream.read_exact(&mut handshake).await.unwrap();
+            handshake[28..48].copy_from_slice(&[2; 20]);
+            stream.write_all(&handshake).await.unwrap();
+        });
+        let res =
+            PeerConnection::connect(peer, &InfoHash::new([1; 20]), b"00112233445566778899").await;
+        assert!(matches!(
+            res,
+            Err(PeerError::Handshake(HandshakeError::InfoHashMismatch { expected, got }))
+                if expected == InfoHash::new([1; 20]) && got == InfoHash::new([2; 20])
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout() {
+        // Completes the handshake and then goes silent
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let _stream = handshaking_peer(&listener).await;
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+        let options = ConnectOptions {
+            read_timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let mut conn = PeerConnection::connect_with(
+            peer,
+            &InfoHash::new([1; 20]),
+            b"00112233445566778899",
+            &options,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            conn.read_bitfield().await,
+            Err(PeerError::Timeout(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_small_read_buffer() {
+        let piece = (0..BLOCK_SIZE + 7).map(|i| i as u8).collect::<Vec<_>>();
+        use sha1::Digest;
+        let hash: [u8; 20] = sha1::Sha1::digest(&piece).into();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        let piece_length = piece.len() as u32;
+        tokio::spawn(serving_peer(listener, piece.clone(), piece_length));
+        let options = ConnectOptions {
+            read_buffer_size: 7,
+            ..Default::default()
+        };
+
+        let mut conn = PeerConnection::connect_with(
+            peer,
+            &InfoHash::new([1; 20]),
+            b"00112233445566778899",
+            &options,
+        )
+        .await
+        .unwrap();
+        conn.unchoke().await.unwrap();
+        let downloaded = conn.download_piece(0, piece.len(), &hash).await.unwrap();
+        assert_eq!(downloaded, piece);
+    }
+
+    #[tokio::test]
+    async fn test_socket_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(serving_peer(listener, vec![0; 10], 10));
+        let options = ConnectOptions {
+            keepalive: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+
+        let conn = PeerConnection::connect_with(
+            peer,
+            &InfoHash::new([1; 20]),
+            b"00112233445566778899",
+            &options,
+        )
+        .await
+        .unwrap();
+        assert!(conn.stream.nodelay().unwrap());
+        assert!(socket2::SockRef::from(&conn.stream).keepalive().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_connect_through_socks5_proxy() {
+        let piece = vec![7; 100];
+        use sha1::Digest;
+        let hash: [u8; 20] = sha1::Sha1::digest(&piece).into();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(serving_peer(listener, piece.clone(), 100));
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let options = ConnectOptions {
+            socks5_proxy: Some(proxy.local_addr().unwrap()),
+            ..Default::default()
+        };
+        tokio::spawn(socks::tests::socks5_proxy(proxy));
+
+        let mut conn = PeerConnection::connect_with(
+            peer,
+            &InfoHash::new([1; 20]),
+            b"00112233445566778899",
+            &options,
+        )
+        .await
+        .unwrap();
+        conn.unchoke().await.unwrap();
+        let downloaded = conn.download_piece(0, piece.len(), &hash).await.unwrap();
+        assert_eq!(downloaded, piece);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_when_completed_elsewhere() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        let (received_tx, received_rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let mut stream = handshaking_peer(&listener).await;
+            stream.write_all(&[0, 0, 0, 2, 5, 0xff]).await.unwrap();
+            let mut interested = [0; 5];
+            stream.read_exact(&mut interested).await.unwrap();
+            stream.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+            // Never answer the request
+            let mut request = [0; 17];
+            stream.read_exact(&mut request).await.unwrap();
+            let mut cancel = [0; 17];
+            stream.read_exact(&mut cancel).await.unwrap();
+            received_tx.send((request, cancel)).unwrap();
+        });
+        let mut conn =
+            PeerConnection::connect(peer, &InfoHash::new([1; 20]), b"00112233445566778899")
+                .await
+                .unwrap();
+        conn.unchoke().await.unwrap();
+
+        let (completed_tx, completed_rx) = tokio::sync::oneshot::channel::<()>();
+        completed_tx.send(()).unwrap();
+        let piece = conn
+            .download_piece_until(2, 100, &[0; 20], async {
+                completed_rx.await.unwrap();
+            })
+            .await
+            .unwrap();
+        assert_eq!(piece, None);
+
+        let (request, cancel) = received_rx.await.unwrap();
+        assert_eq!(request[4], PeerMessageId::Request.code());
+        assert_eq!(cancel[4], PeerMessageId::Cancel.code());
+        assert_eq!(request[5..], cancel[5..]);
+        assert_eq!(&cancel[5..], &[0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 100]);
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_depth_grows() {
+        let piece = (0..BLOCK_SIZE * 20).map(|i| i as u8).collect::<Vec<_>>();
+        use sha1::Digest;
+        let hash: [u8; 20] = sha1::Sha1::digest(&piece).into();
+        let mut conn = connect(piece.clone()).await;
+        assert_eq!(conn.stats().pipeline_depth(), INITIAL_PIPELINE_DEPTH);
+        let downloaded = conn.download_piece(0, piece.len(), &hash).await.unwrap();
+        assert_eq!(downloaded, piece);
+        assert_eq!(conn.stats().blocks_received(), 20);
+        assert!(conn.stats().pipeline_depth() > INITIAL_PIPELINE_DEPTH);
+    }
+
+    #[tokio::test]
+    async fn test_max_pipeline_depth() {
+        let piece = (0..BLOCK_SIZE * 8).map(|i| i as u8).collect::<Vec<_>>();
+        use sha1::Digest;
+        let hash: [u8; 20] = sha1::Sha1::digest(&piece).into();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(serving_peer(listener, piece.clone(), piece.len() as u32));
+        let options = ConnectOptions {
+            max_pipeline_depth: 2,
+            ..Default::default()
+        };
+        let mut conn = PeerConnection::connect_with(
+            peer,
+            &InfoHash::new([1; 20]),
+            b"00112233445566778899",
+            &options,
+        )
+        .await
+        .unwrap();
+        conn.unchoke().await.unwrap();
+        assert_eq!(conn.stats().pipeline_depth(), 2);
+        let downloaded = conn.download_piece(0, piece.len(), &hash).await.unwrap();
+        assert_eq!(downloaded, piece);
+        assert_eq!(conn.stats().pipeline_depth(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_depth_shrinks_on_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        let piece = vec![7; BLOCK_SIZE as usize * 2];
+        use sha1::Digest;
+        let hash: [u8; 20] = sha1::Sha1::digest(&piece).into();
+        tokio::spawn(async move {
+            let mut stream = handshaking_peer(&listener).await;
+            stream.write_all(&[0, 0, 0, 2, 5, 0xff]).await.unwrap();
+            let mut interested = [0; 5];
+            stream.read_exact(&mut interested).await.unwrap();
+            stream.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+            let mut requests = [0; 34];
+            stream.read_exact(&mut requests).await.unwrap();
+            // Stay quiet for longer than the request timeout
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            for begin in [0, BLOCK_SIZE] {
+                let mut message = vec![];
+                message.extend((9 + BLOCK_SIZE).to_be_bytes());
+                message.push(7);
+                message.extend(0_u32.to_be_bytes());
+                message.extend(begin.to_be_bytes());
+                message.extend(vec![7; BLOCK_SIZE as usize]);
+                stream.write_all(&message).await.unwrap();
+            }
+        });
+        let mut conn =
+            PeerConnection::connect(peer, &InfoHash::new([1; 20]), b"00112233445566778899")
+                .await
+                .unwrap();
+        conn.unchoke().await.unwrap();
+        conn.set_request_timeout(Duration::from_millis(20));
+
+        let downloaded = conn.download_piece(0, piece.len(), &hash).await.unwrap();
+        assert_eq!(downloaded, piece);
+        assert!(conn.stats().pipeline_depth() < INITIAL_PIPELINE_DEPTH);
+    }
+
+    #[tokio::test]
+    async fn test_allowed_fast_while_choked() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        let content = (0..200).map(|i| i as u8).collect::<Vec<_>>();
+        let piece = content[100..].to_vec();
+        use sha1::Digest;
+        let hash: [u8; 20] = sha1::Sha1::digest(&piece).into();
+        tokio::spawn(async move {
+            let mut stream = handshaking_peer(&listener).await;
+            stream.write_all(&[0, 0, 0, 2, 5, 0xc0]).await.unwrap();
+            stream
+                .write_all(&[0, 0, 0, 5, 17, 0, 0, 0, 1])
+                .await
+                .unwrap();
+            stream
+                .write_all(&[0, 0, 0, 5, 13, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            let mut interested = [0; 5];
+            stream.read_exact(&mut interested).await.unwrap();
+            // Never unchoke
+            serve_blocks(stream, content, 100).await;
+        });
+        let mut conn =
+            PeerConnection::connect(peer, &InfoHash::new([1; 20]), b"00112233445566778899")
+                .await
+                .unwrap();
+        conn.declare_interest().await.unwrap();
+        let downloaded = conn.download_piece(1, 100, &hash).await.unwrap();
+        assert_eq!(downloaded, piece);
+        assert!(conn.choked());
+        assert_eq!(conn.allowed_fast(), &[1]);
+        assert_eq!(conn.suggested(), &[0]);
+    }
+
+    #[tokio::test]
+    async fn test_choked_mid_piece() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        let piece = (0..BLOCK_SIZE * 4).map(|i| i as u8).collect::<Vec<_>>();
+        use sha1::Digest;
+        let hash: [u8; 20] = sha1::Sha1::digest(&piece).into();
+        tokio::spawn({
+            let piece = piece.clone();
+            async move {
+                let mut stream = handshaking_peer(&listener).await;
+                stream.write_all(&[0, 0, 0, 2, 5, 0x80]).await.unwrap();
+                let mut interested = [0; 5];
+                stream.read_exact(&mut interested).await.unwrap();
+                stream.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+                // Answer the first request, then choke and drop every request until the peer
+                // declares interest again
+                let mut request = [0; 17];
+                stream.read_exact(&mut request).await.unwrap();
+                let mut message = (9 + BLOCK_SIZE).to_be_bytes().to_vec();
+                message.push(7);
+                message.extend(&request[5..13]);
+                message.extend(&piece[..BLOCK_SIZE as usize]);
+                message.extend([0, 0, 0, 1, 0]);
+                stream.write_all(&message).await.unwrap();
+                loop {
+                    let message_length = stream.read_u32().await.unwrap();
+                    let mut message = vec![0; message_length as usize];
+                    stream.read_exact(&mut message).await.unwrap();
+                    if message == [PeerMessageId::Interested.code()] {
+                        break;
+                    }
+                }
+                stream.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+                serve_blocks(stream, piece, BLOCK_SIZE * 4).await;
+            }
+        });
+        let mut conn =
+            PeerConnection::connect(peer, &InfoHash::new([1; 20]), b"00112233445566778899")
+                .await
+                .unwrap();
+        conn.unchoke().await.unwrap();
+        let downloaded = conn.download_piece(0, piece.len(), &hash).await.unwrap();
+        assert_eq!(downloaded, piece);
+        assert!(!conn.choked());
+    }
+
+    #[tokio::test]
+    async fn test_request_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut stream = handshaking_peer(&listener).await;
+            stream.write_all(&[0, 0, 0, 2, 5, 0x80]).await.unwrap();
+            let mut interested = [0; 5];
+            stream.read_exact(&mut interested).await.unwrap();
+            stream.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+            let mut request = [0; 17];
+            stream.read_exact(&mut request).await.unwrap();
+            let mut reject = vec![0, 0, 0, 13, PeerMessageId::Reject.code()];
+            reject.extend(&request[5..]);
+            stream.write_all(&reject).await.unwrap();
+            let mut sink = vec![];
+            let _ = stream.read_to_end(&mut sink).await;
+        });
+        let mut conn =
+            PeerConnection::connect(peer, &InfoHash::new([1; 20]), b"00112233445566778899")
+                .await
+                .unwrap();
+        conn.unchoke().await.unwrap();
+        assert!(matches!(
+            conn.download_piece(0, 10, &[0; 20]).await,
+            Err(PeerError::RequestRejected {
+                index: 0,
+                begin: 0,
+                length: 10
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_unchoke_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut stream = handshaking_peer(&listener).await;
+            stream.write_all(&[0, 0, 0, 2, 5, 0xff]).await.unwrap();
+            // Never unchoke
+            let mut sink = vec![];
+            let _ = stream.read_to_end(&mut sink).await;
+        });
+        let options = ConnectOptions {
+            unchoke_timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let mut conn = PeerConnection::connect_with(
+            peer,
+            &InfoHash::new([1; 20]),
+            b"00112233445566778899",
+            &options,
+        )
+        .await
+        .unwrap();
+        let res = conn.unchoke().await;
+        assert!(matches!(res, Err(PeerError::UnchokeTimeout(_))));
+    }
+
+    #[tokio::test]
+    async fn test_closed_after_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            handshaking_peer(&listener).await;
+        });
+        let mut conn =
+            PeerConnection::connect(peer, &InfoHash::new([1; 20]), b"00112233445566778899")
+                .await
+                .unwrap();
+        let res = conn.unchoke().await;
+        assert!(matches!(res, Err(PeerError::ConnectionClosed)));
+    }
+
+    #[tokio::test]
+    async fn test_message_too_large() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut stream = handshaking_peer(&listener).await;
+            // Claims a message of nearly 4 GiB, then goes quiet
+            stream
+                .write_all(&[0xff, 0xff, 0xff, 0xf0, 5])
+                .await
+                .unwrap();
+            let _ = stream.read(&mut [0; 1]).await;
+        });
+        let mut conn =
+            PeerConnection::connect(peer, &InfoHash::new([1; 20]), b"00112233445566778899")
+                .await
+                .unwrap();
+        let res = conn.unchoke().await;
+        assert!(matches!(
+            res,
+            Err(PeerError::MessageTooLarge {
+                length: 0xffff_fff0,
+                max: MAX_MESSAGE_LEN
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_port_message() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut stream = handshaking_peer(&listener).await;
+            // Port 6881, before and after the bitfield
+            stream
+                .write_all(&[0, 0, 0, 3, 9, 0x1a, 0xe1])
+                .await
+                .unwrap();
+            stream.write_all(&[0, 0, 0, 2, 5, 0xff]).await.unwrap();
+            let mut interested = [0; 5];
+            stream.read_exact(&mut interested).await.unwrap();
+            stream
+                .write_all(&[0, 0, 0, 3, 9, 0x1a, 0xe2])
+                .await
+                .unwrap();
+            stream.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+            let _ = stream.read(&mut [0; 1]).await;
+        });
+        let mut conn =
+            PeerConnection::connect(peer, &InfoHash::new([1; 20]), b"00112233445566778899")
+                .await
+                .unwrap();
+        assert_eq!(conn.dht_port(), None);
+        conn.declare_interest().await.unwrap();
+        assert_eq!(conn.dht_port(), Some(6881));
+        conn.wait_for_unchoke().await.unwrap();
+        assert_eq!(conn.dht_port(), Some(6882));
+    }
+
+    #[tokio::test]
+    async fn test_have_updates_availability() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut stream = handshaking_peer(&listener).await;
+            stream
+                .write_all(&[0, 0, 0, 3, 5, 0b1000_0000, 0])
+                .await
+                .unwrap();
+            let mut interested = [0; 5];
+            stream.read_exact(&mut interested).await.unwrap();
+            stream
+                .write_all(&[0, 0, 0, 5, 4, 0, 0, 0, 9])
+                .await
+                .unwrap();
+            stream
+                .write_all(&[0, 0, 0, 5, 4, 0, 0, 0, 2])
+                .await
+                .unwrap();
+            stream.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+        });
+        let mut conn =
+            PeerConnection::connect(peer, &InfoHash::new([1; 20]), b"00112233445566778899")
+                .await
+                .unwrap();
+        conn.declare_interest().await.unwrap();
+        assert_eq!(conn.available_pieces().collect::<Vec<_>>(), &[0]);
+        conn.wait_for_unchoke().await.unwrap();
+        assert_eq!(conn.available_pieces().collect::<Vec<_>>(), &[0, 2, 9]);
+        assert!(conn.has_piece(9));
+        assert!(!conn.has_piece(1));
+        assert!(!conn.has_piece(100));
+    }
+
+    #[tokio::test]
+    async fn test_have_all_and_have_none() {
+        for (message_id, available) in [
+            (PeerMessageId::HaveAll, &[0, 1, 2][..]),
+            (PeerMessageId::HaveNone, &[2]),
+        ] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let peer = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                let mut stream = handshaking_peer(&listener).await;
+                stream
+                    .write_all(&[0, 0, 0, 1, message_id.code()])
+                    .await
+                    .unwrap();
+                let mut interested = [0; 5];
+                stream.read_exact(&mut interested).await.unwrap();
+                stream
+                    .write_all(&[0, 0, 0, 5, 4, 0, 0, 0, 2])
+                    .await
+                    .unwrap();
+                stream.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+            });
+            let options = ConnectOptions {
+                piece_count: Some(3),
+                ..Default::default()
+            };
+            let mut conn = PeerConnection::connect_with(
+                peer,
+                &InfoHash::new([1; 20]),
+                b"00112233445566778899",
+                &options,
+            )
+            .await
+            .unwrap();
+            assert!(conn.handshake().supports_fast_extension());
+            conn.unchoke().await.unwrap();
+            assert_eq!(
+                conn.available_pieces().collect::<Vec<_>>(),
+                available,
+                "{message_id:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_bitfield() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut stream = handshaking_peer(&listener).await;
+            // The spare bit after the third piece is set
+            stream.write_all(&[0, 0, 0, 2, 5, 0xf0]).await.unwrap();
+        });
+        let options = ConnectOptions {
+            piece_count: Some(3),
+            ..Default::default()
+        };
+        let mut conn = PeerConnection::connect_with(
+            peer,
+            &InfoHash::new([1; 20]),
+            b"00112233445566778899",
+            &options,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            conn.read_bitfield().await,
+            Err(PeerError::InvalidBitfield(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut stream = handshaking_peer(&listener).await;
+            // Keep-alives around the bitfield are skipped
+            stream
+                .write_all(&[0, 0, 0, 0, 0, 0, 0, 2, 5, 0xff, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            let mut interested = [0; 5];
+            stream.read_exact(&mut interested).await.unwrap();
+            // Only unchoke once we have been kept alive
+            let mut keep_alive = [0xff; 4];
+            stream.read_exact(&mut keep_alive).await.unwrap();
+            assert_eq!(keep_alive, [0; 4]);
+            stream.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+        });
+        let mut conn =
+            PeerConnection::connect(peer, &InfoHash::new([1; 20]), b"00112233445566778899")
+                .await
+                .unwrap();
+        conn.set_keep_alive_interval(Duration::from_millis(50));
+        conn.unchoke().await.unwrap();
+        assert_eq!(conn.available_pieces().count(), 8);
+        assert!(!conn.choked());
+    }
+
+    #[tokio::test]
+    async fn test_download_piece_hash_mismatch() {
+        let piece = vec![7; 100];
+        let mut conn = connect(piece.clone()).await;
+        let res = conn.download_piece(0, piece.len(), &[0; 20]).await;
+        use sha1::Digest;
+        let hash: [u8; 20] = sha1::Sha1::digest(&piece).into();
+        assert!(matches!(
+            res,
+            Err(PeerError::HashMismatch { index: 0, expected, got })
+                if expected == [0; 20] && got == hash
+        ));
+    }
+}
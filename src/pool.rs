@@ -0,0 +1,162 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io,
+    net::SocketAddr,
+    path::Path,
+};
+
+use tokio::sync::mpsc;
+
+/// Tracks which peers are waiting to be dialed and which are connected, so that overlapping
+/// announces only ever add peers we are not already talking to.
+#[derive(Debug, Default)]
+pub struct PeerPool {
+    pending: VecDeque<SocketAddr>,
+    known: HashSet<SocketAddr>,
+    connected: HashSet<SocketAddr>,
+    /// Forwards completed pieces to the tasks driving the connections
+    haves: HashMap<SocketAddr, mpsc::UnboundedSender<u32>>,
+}
+
+impl PeerPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues the peers that are neither waiting to be dialed nor connected, returning how many
+    /// were new.
+    pub fn add_peers(&mut self, peers: impl IntoIterator<Item = SocketAddr>) -> usize {
+        let mut added = 0;
+        for peer in peers {
+            if self.known.insert(peer) {
+                self.pending.push_back(peer);
+                added += 1;
+            }
+        }
+        added
+    }
+
+    /// Takes the next peer to dial and counts it as connected.
+    pub fn next_to_dial(&mut self) -> Option<SocketAddr> {
+        let peer = self.pending.pop_front()?;
+        self.connected.insert(peer);
+        Some(peer)
+    }
+
+    /// Forgets a peer once its connection is gone so that a later announce may bring it back.
+    pub fn mark_disconnected(&mut self, peer: SocketAddr) {
+        self.mark_dead(peer);
+        self.known.remove(&peer);
+    }
+
+    /// Drops a peer that closed on us without letting a later announce bring it back.
+    pub fn mark_dead(&mut self, peer: SocketAddr) {
+        self.connected.remove(&peer);
+        self.haves.remove(&peer);
+    }
+
+    /// Returns the channel through which [`Self::broadcast_have`] reaches the connection to
+    /// `peer`, replacing any earlier one.
+    pub fn subscribe_haves(&mut self, peer: SocketAddr) -> mpsc::UnboundedReceiver<u32> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.haves.insert(peer, tx);
+        rx
+    }
+
+    /// Queues a `Have` for the piece at `index` to every connected peer that subscribed, and
+    /// returns how many were reached.
+    pub fn broadcast_have(&mut self, index: u32) -> usize {
+        self.haves.retain(|_, tx| tx.send(index).is_ok());
+        self.haves.len()
+    }
+
+    pub fn is_connected(&self, peer: &SocketAddr) -> bool {
+        self.connected.contains(peer)
+    }
+
+    pub fn connected(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.connected.iter()
+    }
+
+    pub fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+/// Reads one `addr:port` per line, skipping blank lines and `#` comments.
+pub fn read_peers_file(path: impl AsRef<Path>) -> io::Result<Vec<SocketAddr>> {
+    parse_peers(&std::fs::read_to_string(path)?)
+}
+
+fn parse_peers(contents: &str) -> io::Result<Vec<SocketAddr>> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            line.parse().map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid peer {line:?}: {e}"),
+                )
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_dedup_across_announces() {
+        let mut pool = PeerPool::new();
+        assert_eq!(pool.add_peers([addr(1), addr(2), addr(1)]), 2);
+        assert_eq!(pool.next_to_dial(), Some(addr(1)));
+        assert!(pool.is_connected(&addr(1)));
+
+        // Both the connected and the still pending peer are skipped
+        assert_eq!(pool.add_peers([addr(1), addr(2), addr(3)]), 1);
+        assert_eq!(pool.next_to_dial(), Some(addr(2)));
+        assert_eq!(pool.next_to_dial(), Some(addr(3)));
+        assert_eq!(pool.next_to_dial(), None);
+
+        pool.mark_disconnected(addr(1));
+        assert!(!pool.is_connected(&addr(1)));
+        assert_eq!(pool.add_peers([addr(1), addr(2)]), 1);
+        assert_eq!(pool.pending_len(), 1);
+        assert_eq!(pool.connected().count(), 2);
+
+        pool.mark_dead(addr(2));
+        assert!(!pool.is_connected(&addr(2)));
+        assert_eq!(pool.add_peers([addr(2)]), 0);
+    }
+
+    #[test]
+    fn test_broadcast_have() {
+        let mut pool = PeerPool::new();
+        pool.add_peers([addr(1), addr(2), addr(3)]);
+        let mut haves = vec![];
+        while let Some(peer) = pool.next_to_dial() {
+            haves.push(pool.subscribe_haves(peer));
+        }
+        pool.mark_dead(addr(2));
+        drop(haves.pop());
+
+        assert_eq!(pool.broadcast_have(7), 1);
+        assert_eq!(haves[0].try_recv(), Ok(7));
+        assert!(haves[1].try_recv().is_err());
+    }
+
+    #[test]
+    fn test_parse_peers() {
+        let peers = parse_peers("# seeds\n127.0.0.1:1\n\n  [::1]:2  \n").unwrap();
+        assert_eq!(peers, &[addr(1), "[::1]:2".parse().unwrap()]);
+        let res = parse_peers("127.0.0.1");
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,102 @@
+use std::{net::SocketAddr, time::Duration};
+
+use tokio::task::JoinSet;
+
+use crate::{
+    peer::{ConnectOptions, PeerConnection, PeerError},
+    Metainfo,
+};
+
+/// Counts, for each piece, how many of the first `peer_limit` peers announce having it.
+///
+/// Each peer gets `timeout` to complete the handshake and send its bitfield; peers that do not
+/// are left out of the counts.
+pub async fn piece_availability(
+    metainfo: &Metainfo,
+    peers: &[SocketAddr],
+    peer_id: &[u8; 20],
+    peer_limit: usize,
+    timeout: Duration,
+) -> Vec<u32> {
+    let info_hash = *metainfo.info().hash();
+    let peer_id = *peer_id;
+    let piece_count = metainfo.info().piece_hashes().count();
+    let options = ConnectOptions {
+        piece_count: Some(piece_count as u32),
+        ..Default::default()
+    };
+    let mut probes = JoinSet::new();
+    for &peer in peers.iter().take(peer_limit) {
+        let options = options.clone();
+        probes.spawn(tokio::time::timeout(timeout, async move {
+            let mut conn =
+                PeerConnection::connect_with(peer, &info_hash, &peer_id, &options).await?;
+            conn.read_bitfield().await?;
+            Ok::<_, PeerError>(conn.available_pieces().collect::<Vec<_>>())
+        }));
+    }
+
+    let mut availability = vec![0; piece_count];
+    while let Some(pieces) = probes.join_next().await {
+        let Ok(Ok(Ok(pieces))) = pieces else {
+            continue;
+        };
+        for index in pieces {
+            if let Some(count) = availability.get_mut(index as usize) {
+                *count += 1;
+            }
+        }
+    }
+    availability
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::{download::tests::metainfo_for, peer::tests::handshaking_peer};
+
+    /// Completes the handshake and sends `bitfield`, or never answers if it is `None`.
+    async fn peer_with(bitfield: Option<u8>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut stream = match bitfield {
+                Some(bitfield) => {
+                    let mut stream = handshaking_peer(&listener).await;
+                    stream.write_all(&[0, 0, 0, 2, 5, bitfield]).await.unwrap();
+                    stream
+                }
+                None => listener.accept().await.unwrap().0,
+            };
+            let mut sink = vec![];
+            let _ = stream.read_to_end(&mut sink).await;
+        });
+        peer
+    }
+
+    #[tokio::test]
+    async fn test_piece_availability() {
+        let metainfo = metainfo_for(&[0; 3], 1);
+
+        let peers = [
+            peer_with(Some(0b1110_0000)).await,
+            peer_with(Some(0b1000_0000)).await,
+            peer_with(None).await,
+            peer_with(Some(0b1110_0000)).await,
+        ];
+        let availability = piece_availability(
+            &metainfo,
+            &peers,
+            b"00112233445566778899",
+            3,
+            Duration::from_millis(200),
+        )
+        .await;
+        assert_eq!(availability, &[2, 1, 1]);
+    }
+}
@@ -0,0 +1,94 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use tokio::sync::watch;
+
+/// Transfer counters shared by every task of a download or seed, which a CLI can poll or
+/// [`subscribe`](Progress::subscribe) to for drawing a progress bar.
+#[derive(Debug)]
+pub struct Progress {
+    /// Verified bytes written to the output, including pieces kept from an earlier download
+    downloaded_bytes: AtomicU64,
+    uploaded_bytes: AtomicU64,
+    completed_pieces: AtomicU32,
+    changed: watch::Sender<()>,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Self {
+            downloaded_bytes: AtomicU64::new(0),
+            uploaded_bytes: AtomicU64::new(0),
+            completed_pieces: AtomicU32::new(0),
+            changed: watch::channel(()).0,
+        }
+    }
+
+    pub fn downloaded_bytes(&self) -> u64 {
+        self.downloaded_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn uploaded_bytes(&self) -> u64 {
+        self.uploaded_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn completed_pieces(&self) -> u32 {
+        self.completed_pieces.load(Ordering::Relaxed)
+    }
+
+    /// Returns how much of `total_length` bytes has been downloaded, from 0 to 100.
+    pub fn percent_complete(&self, total_length: u64) -> f64 {
+        if total_length == 0 {
+            return 100.0;
+        }
+        (self.downloaded_bytes() as f64 / total_length as f64 * 100.0).min(100.0)
+    }
+
+    /// Returns a receiver that is marked changed whenever a counter moves.
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.changed.subscribe()
+    }
+
+    /// Counts a verified piece of `length` bytes.
+    pub fn record_piece(&self, length: u32) {
+        self.downloaded_bytes
+            .fetch_add(length.into(), Ordering::Relaxed);
+        self.completed_pieces.fetch_add(1, Ordering::Relaxed);
+        self.changed.send_replace(());
+    }
+
+    pub fn record_upload(&self, bytes: u64) {
+        self.uploaded_bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.changed.send_replace(());
+    }
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_progress() {
+        let progress = Progress::new();
+        let mut changed = progress.subscribe();
+        assert_eq!(progress.percent_complete(200), 0.0);
+        assert_eq!(progress.percent_complete(0), 100.0);
+
+        progress.record_piece(50);
+        assert!(changed.has_changed().unwrap());
+        changed.borrow_and_update();
+        assert_eq!(progress.downloaded_bytes(), 50);
+        assert_eq!(progress.completed_pieces(), 1);
+        assert_eq!(progress.percent_complete(200), 25.0);
+
+        progress.record_upload(30);
+        changed.changed().await.unwrap();
+        assert_eq!(progress.uploaded_bytes(), 30);
+        assert_eq!(progress.downloaded_bytes(), 50);
+    }
+}
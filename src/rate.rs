@@ -0,0 +1,84 @@
+use std::{sync::Mutex, time::Duration};
+
+use tokio::time::Instant;
+
+/// Caps throughput with a token bucket holding up to one second's worth of bytes, shared by
+/// every connection it is handed to.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Zero means unlimited
+    bytes_per_second: u64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Goes negative when bytes are taken on credit, which later callers then wait out
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a limiter that starts with a full bucket, or one that never waits if
+    /// `bytes_per_second` is zero.
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_second as f64,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second
+    }
+
+    /// Takes `bytes` from the bucket, waiting until the rate allows sending or requesting them.
+    pub async fn acquire(&self, bytes: u64) {
+        if self.bytes_per_second == 0 {
+            return;
+        }
+        let rate = self.bytes_per_second as f64;
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+            bucket.refilled_at = now;
+            bucket.tokens -= bytes as f64;
+            (bucket.tokens < 0.0).then(|| Duration::from_secs_f64(-bucket.tokens / rate))
+        };
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_acquire() {
+        let limiter = RateLimiter::new(10_000);
+        let start = Instant::now();
+        // The first second's worth is allowed straight away
+        limiter.acquire(10_000).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+        limiter.acquire(1000).await;
+        limiter.acquire(2000).await;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(290), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(500), "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn test_unlimited() {
+        let limiter = RateLimiter::new(0);
+        let start = Instant::now();
+        limiter.acquire(u64::MAX).await;
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
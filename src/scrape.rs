@@ -0,0 +1,186 @@
+use std::collections::BTreeMap;
+
+use getset::{CopyGetters, Getters};
+
+use crate::{InfoHash, Metainfo, TrackerError, Value};
+
+/// Derives the torrent's scrape URL from its announce URL, asking only about this torrent.
+///
+/// Returns `None` for trackers that do not support scraping, which by convention (BEP 48) are
+/// those whose last path segment does not start with `announce`.
+pub fn scrape_url(metainfo: &Metainfo) -> Option<String> {
+    let mut url = scrape_url_for(metainfo.announce())?;
+    if !url.contains('?') {
+        url.push('?');
+    } else if !url.ends_with(['?', '&']) {
+        url.push('&');
+    }
+    url.push_str("info_hash=");
+    url.push_str(&urlencoding::encode_binary(
+        metainfo.info().hash().as_bytes(),
+    ));
+    Some(url)
+}
+
+/// Replaces `announce` at the start of the last path segment of `announce_url` with `scrape`,
+/// keeping the rest of the segment and any query.
+pub fn scrape_url_for(announce_url: &str) -> Option<String> {
+    let (path, query) = match announce_url.find('?') {
+        Some(position) => announce_url.split_at(position),
+        None => (announce_url, ""),
+    };
+    let segment_start = path.rfind('/')? + 1;
+    let rest = path[segment_start..].strip_prefix("announce")?;
+    Some(format!("{}scrape{rest}{query}", &path[..segment_start]))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Getters)]
+pub struct ScrapeResponse {
+    #[getset(get = "pub")]
+    files: BTreeMap<InfoHash, ScrapeStats>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, CopyGetters)]
+#[getset(get_copy = "pub")]
+pub struct ScrapeStats {
+    /// Peers with the whole torrent
+    complete: u64,
+    /// How many times the torrent has been downloaded to completion
+    downloaded: u64,
+    /// Peers still downloading
+    incomplete: u64,
+}
+
+impl ScrapeResponse {
+    /// Decodes `{files: {<info hash>: {complete, downloaded, incomplete}}}`, counting missing
+    /// numbers as zero and skipping keys that are not info hashes.
+    pub fn decode(value: Value) -> Result<Self, TrackerError> {
+        let mut value = value.into_dictionary().ok_or(TrackerError::Malformed)?;
+        if let Some(reason) = value
+            .remove(b"failure reason".as_slice())
+            .and_then(|reason| reason.into_bytes())
+        {
+            return Err(TrackerError::Failure(
+                String::from_utf8_lossy(&reason).into_owned(),
+            ));
+        }
+        let files = value
+            .remove(b"files".as_slice())
+            .and_then(|files| files.into_dictionary())
+            .ok_or(TrackerError::Malformed)?;
+        let mut decoded = BTreeMap::new();
+        for (info_hash, stats) in files {
+            let Ok(info_hash) = <[u8; 20]>::try_from(info_hash) else {
+                continue;
+            };
+            let mut stats = stats.into_dictionary().ok_or(TrackerError::Malformed)?;
+            let mut count = |key: &[u8]| {
+                stats
+                    .remove(key)
+                    .and_then(|count| count.into_integer())
+                    .map_or(Ok(0), |count| {
+                        u64::try_from(count).map_err(|_| TrackerError::Malformed)
+                    })
+            };
+            let stats = ScrapeStats {
+                complete: count(b"complete")?,
+                downloaded: count(b"downloaded")?,
+                incomplete: count(b"incomplete")?,
+            };
+            decoded.insert(InfoHash::new(info_hash), stats);
+        }
+        Ok(Self { files: decoded })
+    }
+
+    pub fn get(&self, info_hash: &InfoHash) -> Option<&ScrapeStats> {
+        self.files.get(info_hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode_bencoded_value_exact;
+
+    #[test]
+    fn test_scrape_url_for() {
+        let cases = [
+            (
+                "http://example.com/announce",
+                Some("http://example.com/scrape"),
+            ),
+            (
+                "http://example.com/x/announce",
+                Some("http://example.com/x/scrape"),
+            ),
+            (
+                "http://example.com/announce.php",
+                Some("http://example.com/scrape.php"),
+            ),
+            (
+                "http://example.com/announce?x2%0644",
+                Some("http://example.com/scrape?x2%0644"),
+            ),
+            ("http://example.com/a", None),
+            (
+                "http://example.com/announce?x=2/4",
+                Some("http://example.com/scrape?x=2/4"),
+            ),
+            ("http://example.com/x%064announce", None),
+            ("http://example.com/x/announce/", None),
+        ];
+        for (announce, scrape) in cases {
+            assert_eq!(scrape_url_for(announce).as_deref(), scrape, "{announce}");
+        }
+    }
+
+    #[test]
+    fn test_scrape_url() {
+        let buf = std::fs::read("sample.torrent").unwrap();
+        let metainfo = Metainfo::decode_bytes(&buf).unwrap();
+        let url = scrape_url(&metainfo).unwrap();
+        assert_eq!(
+            url,
+            "http://bittorrent-test-tracker.codecrafters.io/scrape\
+             ?info_hash=%D6%9F%91%E6%B2%AELT%24h%D1%07%3Aq%D4%EA%13%87%9A%7F"
+        );
+    }
+
+    #[test]
+    fn test_decode() {
+        let encoded = b"d5:filesd\
+            20:aaaaaaaaaaaaaaaaaaaad8:completei5e10:downloadedi50e10:incompletei10ee\
+            20:bbbbbbbbbbbbbbbbbbbbd8:completei1ee\
+            ee";
+        let resp = ScrapeResponse::decode(decode_bencoded_value_exact(encoded).unwrap()).unwrap();
+        assert_eq!(resp.files().len(), 2);
+        let stats = resp.get(&InfoHash::new([b'a'; 20])).unwrap();
+        assert_eq!(
+            (stats.complete(), stats.downloaded(), stats.incomplete()),
+            (5, 50, 10)
+        );
+        let stats = resp.get(&InfoHash::new([b'b'; 20])).unwrap();
+        assert_eq!(
+            (stats.complete(), stats.downloaded(), stats.incomplete()),
+            (1, 0, 0)
+        );
+
+        let failure = decode_bencoded_value_exact(b"d14:failure reason4:nopee").unwrap();
+        assert!(matches!(
+            ScrapeResponse::decode(failure),
+            Err(TrackerError::Failure(reason)) if reason == "nope"
+        ));
+        let negative =
+            decode_bencoded_value_exact(b"d5:filesd20:aaaaaaaaaaaaaaaaaaaad8:completei-1eeee")
+                .unwrap();
+        assert!(matches!(
+            ScrapeResponse::decode(negative),
+            Err(TrackerError::Malformed)
+        ));
+        let no_files = decode_bencoded_value_exact(b"de").unwrap();
+        assert!(matches!(
+            ScrapeResponse::decode(no_files),
+            Err(TrackerError::Malformed)
+        ));
+    }
+}
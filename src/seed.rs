@@ -0,0 +1,479 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    peer::PeerError, progress::Progress, rate::RateLimiter, verify::bitfield_from_file,
+    HandshakeError, HandshakeRequest, HandshakeResponse, Metainfo, PeerMessageId, PeerMessageIn,
+    PeerMessageOut, PeerMessageRequest,
+};
+
+/// Peers that ask for more than the 16 KiB block size of BEP 3 in one request are dropped
+pub const MAX_REQUEST_LENGTH: u32 = 1 << 14;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SeedError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Handshake(#[from] HandshakeError),
+    #[error(transparent)]
+    Peer(#[from] PeerError),
+    /// A malformed request, or one for a piece we do not have
+    #[error(
+        "peer requested {length} bytes at offset {begin} of piece {index}, which we cannot serve"
+    )]
+    InvalidRequest { index: u32, begin: u32, length: u32 },
+}
+
+/// Accepts peers on `listener` and serves each of them from the file at `file_path` until
+/// accepting fails, sharing `upload_limiter` and `progress` between them if set.
+///
+/// Peers that send a message longer than `max_message_len`, such as
+/// [`MAX_MESSAGE_LEN`](crate::peer::MAX_MESSAGE_LEN), are
+/// dropped.
+pub async fn seed(
+    listener: TcpListener,
+    metainfo: Metainfo,
+    peer_id: [u8; 20],
+    file_path: PathBuf,
+    upload_limiter: Option<Arc<RateLimiter>>,
+    progress: Option<Arc<Progress>>,
+    max_message_len: u32,
+) -> io::Result<()> {
+    let metainfo = Arc::new(metainfo);
+    let file_path = Arc::new(file_path);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let metainfo = Arc::clone(&metainfo);
+        let file_path = Arc::clone(&file_path);
+        let upload_limiter = upload_limiter.clone();
+        let progress = progress.clone();
+        tokio::spawn(async move {
+            let _ = serve_peer(
+                stream,
+                &metainfo,
+                &peer_id,
+                &*file_path,
+                upload_limiter.as_deref(),
+                progress.as_deref(),
+                max_message_len,
+            )
+            .await;
+        });
+    }
+}
+
+/// Answers the handshake of a peer that connected to us, sends our bitfield, unchokes it once it
+/// is interested and then serves its block requests from the file at `file_path`.
+///
+/// Blocks are sent no faster than `upload_limiter` allows, and their lengths are added to the
+/// uploaded bytes of `progress`.
+///
+/// Returns once the peer closes the connection, or with an error for a message longer than
+/// `max_message_len` or a request that fails [`validate_request`] or asks for a piece we do not
+/// have.
+pub async fn serve_peer(
+    mut stream: TcpStream,
+    metainfo: &Metainfo,
+    peer_id: &[u8; 20],
+    file_path: impl AsRef<Path>,
+    upload_limiter: Option<&RateLimiter>,
+    progress: Option<&Progress>,
+    max_message_len: u32,
+) -> Result<(), SeedError> {
+    let info = metainfo.info();
+    let handshake = HandshakeResponse::decode(&mut stream).await?;
+    handshake.verify(info.hash())?;
+    HandshakeRequest {
+        info_hash: info.hash(),
+        peer_id,
+    }
+    .encode(&mut stream)
+    .await?;
+
+    let bitfield = {
+        let info = info.clone();
+        let file_path = file_path.as_ref().to_owned();
+        tokio::task::spawn_blocking(move || bitfield_from_file(&info, file_path))
+            .await
+            .unwrap()?
+    };
+    PeerMessageOut {
+        message_id: PeerMessageId::Bitfield,
+        payload: bitfield.as_bytes(),
+    }
+    .encode(&mut stream)
+    .await?;
+
+    let mut file = File::open(file_path).await?;
+    let mut choked = true;
+    loop {
+        let Some(message) = read_message(&mut stream, max_message_len).await? else {
+            return Ok(());
+        };
+        match message.message_id() {
+            PeerMessageId::Interested if choked => {
+                PeerMessageOut {
+                    message_id: PeerMessageId::Unchoke,
+                    payload: &[],
+                }
+                .encode(&mut stream)
+                .await?;
+                choked = false;
+            }
+            // Requests sent while choked are rejected if the peer speaks the fast extension
+            // (BEP 6) and dropped otherwise, as the protocol allows
+            PeerMessageId::Request if choked && handshake.supports_fast_extension() => {
+                PeerMessageOut {
+                    message_id: PeerMessageId::Reject,
+                    payload: message.payload(),
+                }
+                .encode(&mut stream)
+                .await?;
+            }
+            PeerMessageId::Request if !choked => {
+                let req = parse_request(message.payload())?;
+                validate_request(&req, metainfo)?;
+                let PeerMessageRequest {
+                    index,
+                    begin,
+                    length,
+                } = req;
+                if !bitfield.has_piece(index) {
+                    return Err(SeedError::InvalidRequest {
+                        index,
+                        begin,
+                        length,
+                    });
+                }
+                let offset = u64::from(index) * u64::from(info.piece_length()) + u64::from(begin);
+                file.seek(io::SeekFrom::Start(offset)).await?;
+                let mut payload = vec![0; 8 + length as usize];
+                payload[..4].copy_from_slice(&index.to_be_bytes());
+                payload[4..8].copy_from_slice(&begin.to_be_bytes());
+                file.read_exact(&mut payload[8..]).await?;
+                if let Some(limiter) = upload_limiter {
+                    limiter.acquire(length.into()).await;
+                }
+                PeerMessageOut {
+                    message_id: PeerMessageId::Piece,
+                    payload: &payload,
+                }
+                .encode(&mut stream)
+                .await?;
+                if let Some(progress) = progress {
+                    progress.record_upload(length.into());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Reads one message, skipping keep-alives, or `None` once the peer closed the connection.
+async fn read_message(
+    stream: &mut TcpStream,
+    max_message_len: u32,
+) -> Result<Option<PeerMessageIn>, SeedError> {
+    loop {
+        // Only a close between messages is clean
+        let mut prefix = [0; 4];
+        match stream.read_exact(&mut prefix).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let mut message = (&prefix[..]).chain(&mut *stream);
+        if let Some(message) =
+            PeerMessageIn::decode_with_limit(&mut message, max_message_len).await?
+        {
+            return Ok(Some(message));
+        }
+    }
+}
+
+fn parse_request(payload: &[u8]) -> Result<PeerMessageRequest, SeedError> {
+    let field = |i: usize| {
+        payload
+            .get(i * 4..i * 4 + 4)
+            .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+    };
+    match (payload.len(), field(0), field(1), field(2)) {
+        (12, Some(index), Some(begin), Some(length)) => Ok(PeerMessageRequest {
+            index,
+            begin,
+            length,
+        }),
+        _ => Err(SeedError::InvalidRequest {
+            index: field(0).unwrap_or(0),
+            begin: field(1).unwrap_or(0),
+            length: field(2).unwrap_or(0),
+        }),
+    }
+}
+
+/// Checks that `req` asks for between 1 and [`MAX_REQUEST_LENGTH`] bytes that lie within one
+/// piece of the torrent.
+pub fn validate_request(req: &PeerMessageRequest, metainfo: &Metainfo) -> Result<(), PeerError> {
+    let piece_count = metainfo.info().piece_hashes().count() as u32;
+    if req.index >= piece_count {
+        return Err(PeerError::PieceOutOfRange {
+            index: req.index,
+            piece_count,
+        });
+    }
+    if !(1..=MAX_REQUEST_LENGTH).contains(&req.length) {
+        return Err(PeerError::InvalidRequestLength(req.length));
+    }
+    let fits = req
+        .begin
+        .checked_add(req.length)
+        .is_some_and(|end| end <= metainfo.info().piece_len(req.index));
+    if !fits {
+        return Err(PeerError::BlockOutOfRange {
+            index: req.index,
+            begin: req.begin,
+            length: req.length,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+    use crate::{
+        download::{download_all, tests::metainfo_for, DownloadConfig},
+        peer::{PeerConnection, MAX_MESSAGE_LEN},
+    };
+
+    #[tokio::test]
+    async fn test_seed_and_download() {
+        let piece_length = 1 << 15;
+        let content = (0..piece_length * 3 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let metainfo = metainfo_for(&content, piece_length);
+        let seed_dir = tempfile::tempdir().unwrap();
+        let seed_file_path = seed_dir.path().join("content.bin");
+        std::fs::write(&seed_file_path, &content).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(seed(
+            listener,
+            metainfo.clone(),
+            *b"99887766554433221100",
+            seed_file_path,
+            None,
+            None,
+            MAX_MESSAGE_LEN,
+        ));
+
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_file_path = output_dir.path().join("content.bin");
+        let report = download_all(
+            &metainfo,
+            &[peer],
+            b"00112233445566778899",
+            &DownloadConfig::default(),
+            &output_file_path,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert!(report.is_complete());
+        assert_eq!(std::fs::read(&output_file_path).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn test_reject_large_message() {
+        let piece_length = 1 << 14;
+        let content = vec![7; piece_length as usize];
+        let metainfo = metainfo_for(&content, piece_length);
+        let seed_dir = tempfile::tempdir().unwrap();
+        let seed_file_path = seed_dir.path().join("content.bin");
+        std::fs::write(&seed_file_path, &content).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        let server = tokio::spawn({
+            let metainfo = metainfo.clone();
+            async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                serve_peer(
+                    stream,
+                    &metainfo,
+                    b"99887766554433221100",
+                    seed_file_path,
+                    None,
+                    None,
+                    16,
+                )
+                .await
+            }
+        });
+
+        let mut stream = TcpStream::connect(peer).await.unwrap();
+        HandshakeRequest {
+            info_hash: metainfo.info().hash(),
+            peer_id: b"00112233445566778899",
+        }
+        .encode(&mut stream)
+        .await
+        .unwrap();
+        // A request fits, but this does not
+        stream.write_all(&[0, 0, 0, 17, 7]).await.unwrap();
+        assert!(matches!(
+            server.await.unwrap(),
+            Err(SeedError::Peer(PeerError::MessageTooLarge {
+                length: 17,
+                max: 16
+            }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_reject_while_choked() {
+        let piece_length = 1 << 14;
+        let content = vec![7; piece_length as usize];
+        let metainfo = metainfo_for(&content, piece_length);
+        let seed_dir = tempfile::tempdir().unwrap();
+        let seed_file_path = seed_dir.path().join("content.bin");
+        std::fs::write(&seed_file_path, &content).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(seed(
+            listener,
+            metainfo.clone(),
+            *b"99887766554433221100",
+            seed_file_path,
+            None,
+            None,
+            MAX_MESSAGE_LEN,
+        ));
+
+        let mut stream = TcpStream::connect(peer).await.unwrap();
+        HandshakeRequest {
+            info_hash: metainfo.info().hash(),
+            peer_id: b"00112233445566778899",
+        }
+        .encode(&mut stream)
+        .await
+        .unwrap();
+        let handshake = HandshakeResponse::decode(&mut stream).await.unwrap();
+        assert!(handshake.supports_fast_extension());
+        let bitfield = read_message(&mut stream, MAX_MESSAGE_LEN).await.unwrap();
+        assert_eq!(bitfield.unwrap().message_id(), PeerMessageId::Bitfield);
+        // Requested without declaring interest first
+        let request = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 10];
+        PeerMessageOut {
+            message_id: PeerMessageId::Request,
+            payload: &request,
+        }
+        .encode(&mut stream)
+        .await
+        .unwrap();
+        let reject = read_message(&mut stream, MAX_MESSAGE_LEN)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(reject.message_id(), PeerMessageId::Reject);
+        assert_eq!(reject.payload(), &request);
+    }
+
+    #[tokio::test]
+    async fn test_reject_out_of_range_request() {
+        let piece_length = 1 << 14;
+        let content = vec![7; piece_length as usize + 10];
+        let metainfo = metainfo_for(&content, piece_length);
+        let seed_dir = tempfile::tempdir().unwrap();
+        let seed_file_path = seed_dir.path().join("content.bin");
+        std::fs::write(&seed_file_path, &content).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        let server = tokio::spawn({
+            let metainfo = metainfo.clone();
+            async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                serve_peer(
+                    stream,
+                    &metainfo,
+                    b"99887766554433221100",
+                    seed_file_path,
+                    None,
+                    None,
+                    MAX_MESSAGE_LEN,
+                )
+                .await
+            }
+        });
+
+        let mut conn =
+            PeerConnection::connect(peer, metainfo.info().hash(), b"00112233445566778899")
+                .await
+                .unwrap();
+        conn.unchoke().await.unwrap();
+        assert!(conn.has_piece(1));
+        assert!(!conn.has_piece(2));
+        // The last piece is only 10 bytes long
+        let res = conn.download_piece(1, 11, &[0; 20]).await;
+        assert!(res.is_err());
+        assert!(matches!(
+            server.await.unwrap(),
+            Err(SeedError::Peer(PeerError::BlockOutOfRange {
+                index: 1,
+                begin: 0,
+                length: 11
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_validate_request() {
+        let piece_length = 1 << 14;
+        let metainfo = metainfo_for(&vec![7; piece_length as usize + 10], piece_length);
+        let req = |index, begin, length| PeerMessageRequest {
+            index,
+            begin,
+            length,
+        };
+        for valid in [
+            req(0, 0, piece_length),
+            req(0, 100, 200),
+            req(1, 0, 10),
+            req(1, 9, 1),
+        ] {
+            assert!(validate_request(&valid, &metainfo).is_ok());
+        }
+        assert!(matches!(
+            validate_request(&req(2, 0, 1), &metainfo),
+            Err(PeerError::PieceOutOfRange {
+                index: 2,
+                piece_count: 2
+            })
+        ));
+        for length in [0, MAX_REQUEST_LENGTH + 1] {
+            assert!(matches!(
+                validate_request(&req(0, 0, length), &metainfo),
+                Err(PeerError::InvalidRequestLength(got)) if got == length
+            ));
+        }
+        for (begin, length) in [(1, 10), (10, 1), (u32::MAX, 2)] {
+            assert!(matches!(
+                validate_request(&req(1, begin, length), &metainfo),
+                Err(PeerError::BlockOutOfRange { index: 1, .. })
+            ));
+        }
+    }
+}
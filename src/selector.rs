@@ -0,0 +1,173 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+use crate::bitfield::Bitfield;
+
+/// Orders the pieces we still need by how many peers hold them, rarest first.
+#[derive(Debug, Clone)]
+pub struct PieceSelector {
+    /// How many of the added peers hold each piece
+    availability: Vec<u32>,
+    needed: Vec<bool>,
+    /// Breaks ties between equally rare pieces so peers do not all start on the same one
+    tiebreak: Vec<u64>,
+}
+
+impl PieceSelector {
+    /// Creates a selector that needs all `piece_count` pieces, none of them held by any peer yet.
+    pub fn new(piece_count: u32) -> Self {
+        let random_state = RandomState::new();
+        let tiebreak = (0..piece_count)
+            .map(|index| {
+                let mut hasher = random_state.build_hasher();
+                hasher.write_u32(index);
+                hasher.finish()
+            })
+            .collect();
+        Self {
+            availability: vec![0; piece_count as usize],
+            needed: vec![true; piece_count as usize],
+            tiebreak,
+        }
+    }
+
+    pub fn from_bitfields<'a>(
+        piece_count: u32,
+        bitfields: impl IntoIterator<Item = &'a Bitfield>,
+    ) -> Self {
+        let mut selector = Self::new(piece_count);
+        for bitfield in bitfields {
+            selector.add_peer(bitfield);
+        }
+        selector
+    }
+
+    /// Counts the pieces in `bitfield` towards their availability. Pieces past our own piece
+    /// count are ignored.
+    pub fn add_peer(&mut self, bitfield: &Bitfield) {
+        for index in self.held_by(bitfield) {
+            self.availability[index as usize] += 1;
+        }
+    }
+
+    /// Undoes [`Self::add_peer`] for a peer that went away.
+    pub fn remove_peer(&mut self, bitfield: &Bitfield) {
+        for index in self.held_by(bitfield) {
+            let availability = &mut self.availability[index as usize];
+            *availability = availability.saturating_sub(1);
+        }
+    }
+
+    pub fn availability(&self, index: u32) -> u32 {
+        self.availability.get(index as usize).copied().unwrap_or(0)
+    }
+
+    /// Takes the rarest piece we still need out of those in `have`, so that no other peer is
+    /// handed the same piece.
+    pub fn next_piece(&mut self, have: &Bitfield) -> Option<u32> {
+        let index = self
+            .held_by(have)
+            .filter(|&index| self.needed[index as usize])
+            .min_by_key(|&index| self.rank(index))?;
+        self.needed[index as usize] = false;
+        Some(index)
+    }
+
+    /// Takes `index` as if it had been returned by [`Self::next_piece`].
+    pub fn take(&mut self, index: u32) {
+        if let Some(needed) = self.needed.get_mut(index as usize) {
+            *needed = false;
+        }
+    }
+
+    /// Gives back a piece that was taken but not completed.
+    pub fn mark_needed(&mut self, index: u32) {
+        if let Some(needed) = self.needed.get_mut(index as usize) {
+            *needed = true;
+        }
+    }
+
+    /// Lists the pieces we still need, rarest first.
+    pub fn rarest_first(&self) -> impl Iterator<Item = u32> {
+        let mut pieces = (0..self.needed.len() as u32)
+            .filter(|&index| self.needed[index as usize])
+            .collect::<Vec<_>>();
+        pieces.sort_unstable_by_key(|&index| self.rank(index));
+        pieces.into_iter()
+    }
+
+    fn rank(&self, index: u32) -> (u32, u64) {
+        (
+            self.availability[index as usize],
+            self.tiebreak[index as usize],
+        )
+    }
+
+    fn held_by<'a>(&self, bitfield: &'a Bitfield) -> impl Iterator<Item = u32> + 'a {
+        let piece_count = self.needed.len() as u32;
+        bitfield
+            .iter_set()
+            .take_while(move |&index| index < piece_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bitfield(piece_count: u32, pieces: &[u32]) -> Bitfield {
+        let mut bitfield = Bitfield::new(piece_count);
+        for &index in pieces {
+            bitfield.set(index);
+        }
+        bitfield
+    }
+
+    #[test]
+    fn test_rarest_first() {
+        // Piece 3 is held by one peer, 1 and 4 by two, 0 by three and 2 by nobody
+        let peers = [
+            bitfield(5, &[0, 1, 3]),
+            bitfield(5, &[0, 1, 4]),
+            bitfield(5, &[0, 4]),
+        ];
+        let mut selector = PieceSelector::from_bitfields(5, &peers);
+        assert_eq!(
+            (0..5)
+                .map(|index| selector.availability(index))
+                .collect::<Vec<_>>(),
+            &[3, 2, 0, 1, 2]
+        );
+        let order = selector.rarest_first().collect::<Vec<_>>();
+        assert_eq!(order[..2], [2, 3]);
+        let mut ties = order[2..4].to_vec();
+        ties.sort_unstable();
+        assert_eq!(ties, &[1, 4]);
+        assert_eq!(order[4], 0);
+
+        // Each peer gets the rarest piece it holds that nobody has taken yet
+        assert_eq!(selector.next_piece(&peers[0]), Some(3));
+        assert_eq!(selector.next_piece(&peers[2]), Some(4));
+        assert_eq!(selector.next_piece(&peers[0]), Some(1));
+        assert_eq!(selector.next_piece(&peers[1]), Some(0));
+        assert_eq!(selector.next_piece(&peers[1]), None);
+        assert_eq!(selector.rarest_first().collect::<Vec<_>>(), &[2]);
+
+        selector.mark_needed(1);
+        selector.remove_peer(&peers[0]);
+        assert_eq!(selector.availability(1), 1);
+        assert_eq!(selector.next_piece(&peers[1]), Some(1));
+    }
+
+    #[test]
+    fn test_ignores_spare_pieces() {
+        // Bitfields read before the piece count is known may be longer than ours
+        let mut selector = PieceSelector::new(3);
+        selector.add_peer(&bitfield(8, &[2, 5, 7]));
+        assert_eq!(selector.availability(2), 1);
+        assert_eq!(selector.availability(5), 0);
+        assert_eq!(selector.next_piece(&bitfield(8, &[5, 7])), None);
+    }
+}
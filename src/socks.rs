@@ -0,0 +1,123 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN_NAME: u8 = 3;
+const IPV6: u8 = 4;
+
+/// Opens a TCP connection to `target` through the SOCKS5 proxy at `proxy` (RFC 1928), without
+/// authentication.
+pub async fn connect_via(proxy: SocketAddr, target: SocketAddr) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy).await?;
+
+    stream.write_all(&[VERSION, 1, NO_AUTHENTICATION]).await?;
+    let mut method = [0; 2];
+    stream.read_exact(&mut method).await?;
+    if method != [VERSION, NO_AUTHENTICATION] {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "SOCKS5 proxy requires authentication",
+        ));
+    }
+
+    let mut request = vec![VERSION, CONNECT, 0];
+    match target.ip() {
+        IpAddr::V4(ip) => {
+            request.push(IPV4);
+            request.extend(ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            request.push(IPV6);
+            request.extend(ip.octets());
+        }
+    }
+    request.extend(target.port().to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("SOCKS5 proxy failed to connect with reply {}", reply[1]),
+        ));
+    }
+    // Skip the address the proxy bound
+    let bound_addr_len = match reply[3] {
+        IPV4 => 4,
+        IPV6 => 16,
+        DOMAIN_NAME => stream.read_u8().await? as usize,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "SOCKS5 proxy replied with an unknown address type",
+            ))
+        }
+    };
+    let mut bound_addr = vec![0; bound_addr_len + 2];
+    stream.read_exact(&mut bound_addr).await?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Serves a single CONNECT to an IPv4 target and relays the connection.
+    pub(crate) async fn socks5_proxy(listener: TcpListener) {
+        let (mut client, _) = listener.accept().await.unwrap();
+        let mut greeting = [0; 3];
+        client.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(greeting, [VERSION, 1, NO_AUTHENTICATION]);
+        client
+            .write_all(&[VERSION, NO_AUTHENTICATION])
+            .await
+            .unwrap();
+        let mut request = [0; 10];
+        client.read_exact(&mut request).await.unwrap();
+        assert_eq!(request[..4], [VERSION, CONNECT, 0, IPV4]);
+        let ip = <[u8; 4]>::try_from(&request[4..8]).unwrap();
+        let port = u16::from_be_bytes([request[8], request[9]]);
+        let mut target = TcpStream::connect(SocketAddr::from((ip, port)))
+            .await
+            .unwrap();
+        client
+            .write_all(&[VERSION, 0, 0, IPV4, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let _ = tokio::io::copy_bidirectional(&mut client, &mut target).await;
+    }
+
+    #[tokio::test]
+    async fn test_connect_via() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = target.accept().await.unwrap();
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        tokio::spawn(socks5_proxy(proxy));
+
+        let mut stream = connect_via(proxy_addr, target_addr).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut echo = [0; 4];
+        stream.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"ping");
+    }
+}
@@ -0,0 +1,244 @@
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    io,
+    net::SocketAddr,
+    time::Duration,
+};
+
+use tokio::net::UdpSocket;
+
+use crate::{
+    parse_compact_peers, parse_compact_peers_v6, CompactPeersError, TrackerEvent, TrackerRequest,
+    TrackerResponse,
+};
+
+/// Identifies the connect request (BEP 15).
+const PROTOCOL_ID: u64 = 0x41727101980;
+const CONNECT: u32 = 0;
+const ANNOUNCE: u32 = 1;
+const ERROR: u32 = 3;
+/// How long to wait for the first response; BEP 15 doubles it after every retransmission
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_RETRANSMISSIONS: u32 = 2;
+
+#[derive(Debug, thiserror::Error)]
+pub enum UdpTrackerError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("invalid UDP tracker URL {0:?}")]
+    InvalidUrl(String),
+    #[error("tracker did not respond")]
+    Timeout,
+    #[error("tracker error: {0}")]
+    Failure(String),
+    #[error("malformed tracker response")]
+    Malformed,
+    #[error(transparent)]
+    InvalidCompactPeers(#[from] CompactPeersError),
+}
+
+/// Announces to a `udp://host:port` tracker.
+///
+/// The request is copied up front so that the returned future can be spawned.
+pub fn announce(
+    tracker: &str,
+    req: &TrackerRequest<'_>,
+) -> impl Future<Output = Result<TrackerResponse, UdpTrackerError>> + 'static {
+    let tracker = tracker.to_owned();
+    let mut body = vec![];
+    body.extend(req.info_hash.as_bytes());
+    body.extend(req.peer_id);
+    body.extend(req.downloaded.to_be_bytes());
+    body.extend(req.left.to_be_bytes());
+    body.extend(req.uploaded.to_be_bytes());
+    let event: u32 = match req.event {
+        None => 0,
+        Some(TrackerEvent::Completed) => 1,
+        Some(TrackerEvent::Started) => 2,
+        Some(TrackerEvent::Stopped) => 3,
+    };
+    body.extend(event.to_be_bytes());
+    // The tracker takes our address from the packet
+    body.extend(0_u32.to_be_bytes());
+    body.extend((random_u64() as u32).to_be_bytes());
+    // As many peers as the tracker likes
+    body.extend((-1_i32).to_be_bytes());
+    body.extend(req.port.to_be_bytes());
+    async move { announce_body(&tracker, &body).await }
+}
+
+async fn announce_body(tracker: &str, body: &[u8]) -> Result<TrackerResponse, UdpTrackerError> {
+    let addr = resolve(tracker).await?;
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0_u16; 8], 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+
+    let mut connect = PROTOCOL_ID.to_be_bytes().to_vec();
+    connect.extend(CONNECT.to_be_bytes());
+    let resp = transact(&socket, connect).await?;
+    let connection_id = resp.get(..8).ok_or(UdpTrackerError::Malformed)?;
+
+    let mut announce = connection_id.to_vec();
+    announce.extend(ANNOUNCE.to_be_bytes());
+    announce.extend(body);
+    let resp = transact(&socket, announce).await?;
+    if resp.len() < 12 {
+        return Err(UdpTrackerError::Malformed);
+    }
+    let interval = u32::from_be_bytes(resp[..4].try_into().unwrap());
+    // Leechers and seeders are skipped
+    let peers = &resp[12..];
+    let peers = match addr {
+        SocketAddr::V4(_) => parse_compact_peers(peers)?,
+        SocketAddr::V6(_) => parse_compact_peers_v6(peers)?,
+    };
+    Ok(TrackerResponse {
+        interval: interval.into(),
+        interval_provided: true,
+        peer_ids: vec![None; peers.len()],
+        peers,
+        tracker_id: None,
+    })
+}
+
+/// Sends `request` with a fresh transaction id, which is inserted after the first 12 bytes, and
+/// returns the payload of the matching response.
+async fn transact(socket: &UdpSocket, mut request: Vec<u8>) -> Result<Vec<u8>, UdpTrackerError> {
+    let action = u32::from_be_bytes(request[8..12].try_into().unwrap());
+    let transaction_id = random_u64() as u32;
+    request.splice(12..12, transaction_id.to_be_bytes());
+
+    let mut buf = vec![0; 64 * 1024];
+    for attempt in 0..=MAX_RETRANSMISSIONS {
+        socket.send(&request).await?;
+        let timeout = INITIAL_TIMEOUT * 2_u32.pow(attempt);
+        let deadline = tokio::time::Instant::now() + timeout;
+        while let Ok(read) = tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            let resp = &buf[..read?];
+            if resp.len() < 8 || resp[4..8] != transaction_id.to_be_bytes() {
+                continue;
+            }
+            let resp_action = u32::from_be_bytes(resp[..4].try_into().unwrap());
+            if resp_action == ERROR {
+                let message = String::from_utf8_lossy(&resp[8..]).into_owned();
+                return Err(UdpTrackerError::Failure(message));
+            }
+            if resp_action != action {
+                return Err(UdpTrackerError::Malformed);
+            }
+            return Ok(resp[8..].to_vec());
+        }
+    }
+    Err(UdpTrackerError::Timeout)
+}
+
+async fn resolve(tracker: &str) -> Result<SocketAddr, UdpTrackerError> {
+    let invalid = || UdpTrackerError::InvalidUrl(tracker.to_owned());
+    let authority = tracker.strip_prefix("udp://").ok_or_else(invalid)?;
+    let authority = authority.split('/').next().unwrap();
+    tokio::net::lookup_host(authority)
+        .await
+        .map_err(|_| invalid())?
+        .next()
+        .ok_or_else(invalid)
+}
+
+/// Good enough for transaction ids and keys, which only need to differ between requests.
+pub(crate) fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::InfoHash;
+
+    /// Answers a connect and an announce from the same client with `peers`.
+    pub(crate) async fn udp_tracker(peers: Vec<u8>) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            let (read, client) = socket.recv_from(&mut buf).await.unwrap();
+            assert_eq!(read, 16);
+            assert_eq!(buf[..8], PROTOCOL_ID.to_be_bytes());
+            assert_eq!(buf[8..12], CONNECT.to_be_bytes());
+            let mut resp = CONNECT.to_be_bytes().to_vec();
+            resp.extend(&buf[12..16]);
+            resp.extend(0x1234_u64.to_be_bytes());
+            socket.send_to(&resp, client).await.unwrap();
+
+            let (read, _) = socket.recv_from(&mut buf).await.unwrap();
+            assert_eq!(read, 98);
+            assert_eq!(buf[..8], 0x1234_u64.to_be_bytes());
+            assert_eq!(buf[8..12], ANNOUNCE.to_be_bytes());
+            assert_eq!(buf[36..56], *b"00112233445566778899");
+            assert_eq!(buf[96..98], 6881_u16.to_be_bytes());
+            let mut resp = ANNOUNCE.to_be_bytes().to_vec();
+            resp.extend(&buf[12..16]);
+            resp.extend(60_u32.to_be_bytes());
+            resp.extend(0_u32.to_be_bytes());
+            resp.extend(1_u32.to_be_bytes());
+            resp.extend(peers);
+            socket.send_to(&resp, client).await.unwrap();
+        });
+        url
+    }
+
+    fn request() -> TrackerRequest<'static> {
+        const INFO_HASH: InfoHash = InfoHash::new([1; 20]);
+        TrackerRequest {
+            info_hash: &INFO_HASH,
+            peer_id: b"00112233445566778899",
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 1,
+            compact: true,
+            tracker_id: None,
+            corrupt: None,
+            redundant: None,
+            event: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_announce() {
+        let tracker = udp_tracker(vec![10, 0, 0, 1, 0x1a, 0xe1]).await;
+        let resp = announce(&tracker, &request()).await.unwrap();
+        assert_eq!(resp.interval(), 60);
+        assert_eq!(
+            resp.peers(),
+            &["10.0.0.1:6881".parse::<SocketAddr>().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_error_action() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let tracker = format!("udp://{}", socket.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut buf = [0; 16];
+            let (_, client) = socket.recv_from(&mut buf).await.unwrap();
+            let mut resp = ERROR.to_be_bytes().to_vec();
+            resp.extend(&buf[12..16]);
+            resp.extend(b"unregistered torrent");
+            socket.send_to(&resp, client).await.unwrap();
+        });
+        let res = announce(&tracker, &request()).await;
+        assert!(
+            matches!(res, Err(UdpTrackerError::Failure(message)) if message == "unregistered torrent")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_url() {
+        let res = announce("http://tracker", &request()).await;
+        assert!(matches!(res, Err(UdpTrackerError::InvalidUrl(_))));
+    }
+}
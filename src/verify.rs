@@ -0,0 +1,223 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+
+use getset::{CopyGetters, Getters};
+use serde::Serialize;
+
+use crate::{bitfield::Bitfield, MetainfoInfo};
+
+/// Checks pieces against their SHA-1 hashes from the torrent.
+#[derive(Debug, Clone, Default)]
+pub struct PieceVerifier {
+    hasher: sha1::Sha1,
+}
+
+impl PieceVerifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn verify(data: &[u8], expected: &[u8; 20]) -> bool {
+        let mut verifier = Self::new();
+        verifier.update(data);
+        verifier.finalize_matches(expected)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        use sha1::Digest;
+        self.hasher.update(data);
+    }
+
+    pub fn finalize_matches(self, expected: &[u8; 20]) -> bool {
+        &self.finalize() == expected
+    }
+
+    pub fn finalize(self) -> [u8; 20] {
+        use sha1::Digest;
+        self.hasher.finalize().into()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Getters, CopyGetters)]
+pub struct VerificationReport {
+    #[getset(get_copy = "pub")]
+    total: u32,
+    #[getset(get = "pub")]
+    verified: Vec<u32>,
+    #[getset(get = "pub")]
+    failed: Vec<u32>,
+}
+
+impl VerificationReport {
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// Marks only the verified pieces.
+    pub fn to_bitfield(&self) -> Bitfield {
+        let mut bitfield = Bitfield::new(self.total);
+        for &index in &self.verified {
+            bitfield.set(index);
+        }
+        bitfield
+    }
+}
+
+/// Hashes each piece of the file at `path` against the torrent, counting pieces missing from a
+/// short file as failed.
+pub fn verify_file(info: &MetainfoInfo, path: impl AsRef<Path>) -> io::Result<VerificationReport> {
+    let mut file = File::options().read(true).open(path)?;
+    let mut verified = vec![];
+    let mut failed = vec![];
+    let mut piece = vec![0; info.piece_length() as usize];
+    for piece_info in info.pieces() {
+        let piece = &mut piece[..piece_info.length() as usize];
+        let matches = match file.read_exact(piece) {
+            // A truncated hash never matches
+            Ok(()) => <&[u8; 20]>::try_from(piece_info.hash())
+                .is_ok_and(|expected_hash| PieceVerifier::verify(piece, expected_hash)),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+            Err(e) => return Err(e),
+        };
+        if matches {
+            verified.push(piece_info.index());
+        } else {
+            failed.push(piece_info.index());
+        }
+    }
+    Ok(VerificationReport {
+        total: u32::try_from(info.piece_hashes().count()).unwrap(),
+        verified,
+        failed,
+    })
+}
+
+/// An out-of-band check of the whole downloaded content.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Checksum {
+    Size(u64),
+    Sha256([u8; 32]),
+}
+
+/// Checks the file at `path` against a checksum published separately from the torrent.
+pub fn verify_against(path: impl AsRef<Path>, expected: &Checksum) -> io::Result<bool> {
+    let mut file = File::options().read(true).open(path)?;
+    match expected {
+        Checksum::Size(size) => Ok(file.metadata()?.len() == *size),
+        Checksum::Sha256(hash) => {
+            use sha2::Digest;
+            let mut hasher = sha2::Sha256::new();
+            let mut buf = vec![0; 1 << 16];
+            loop {
+                let n = file.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buf[..n]);
+            }
+            Ok(hasher.finalize().as_slice() == hash)
+        }
+    }
+}
+
+/// Builds the bitfield to advertise when seeding from the file at `path`, which may be only
+/// partially downloaded.
+pub fn bitfield_from_file(info: &MetainfoInfo, path: impl AsRef<Path>) -> io::Result<Bitfield> {
+    Ok(verify_file(info, path)?.to_bitfield())
+}
+
+/// Finds the pieces of a partial download at `path` that are already complete, treating a
+/// missing file as empty.
+pub fn scan_existing(info: &MetainfoInfo, path: impl AsRef<Path>) -> io::Result<Bitfield> {
+    match bitfield_from_file(info, path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Bitfield::new(
+            u32::try_from(info.piece_hashes().count()).unwrap(),
+        )),
+        res => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::download::tests::metainfo_for;
+
+    #[test]
+    fn test_verify_file() {
+        let content = (0..100).collect::<Vec<u8>>();
+        let metainfo = metainfo_for(&content, 16);
+
+        let mut corrupted = content.clone();
+        corrupted[20] ^= 1;
+        // The last piece is missing
+        corrupted.truncate(90);
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&corrupted).unwrap();
+
+        let report = verify_file(metainfo.info(), file.path()).unwrap();
+        assert_eq!(report.total(), 7);
+        assert_eq!(report.verified(), &[0, 2, 3, 4]);
+        assert_eq!(report.failed(), &[1, 5, 6]);
+        assert!(!report.is_complete());
+        assert_eq!(
+            report.to_json(),
+            r#"{"total":7,"verified":[0,2,3,4],"failed":[1,5,6]}"#
+        );
+
+        let bitfield = bitfield_from_file(metainfo.info(), file.path()).unwrap();
+        assert_eq!(bitfield.as_bytes(), &[0b1011_1000]);
+    }
+
+    #[test]
+    fn test_scan_existing() {
+        let content = (0..100).collect::<Vec<u8>>();
+        let metainfo = metainfo_for(&content, 16);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("content.bin");
+
+        let bitfield = scan_existing(metainfo.info(), &path).unwrap();
+        assert_eq!(bitfield.iter_set().count(), 0);
+
+        // A preallocated file has zeroes where pieces are missing
+        let mut partial = content.clone();
+        partial[48..].fill(0);
+        std::fs::write(&path, &partial).unwrap();
+        let bitfield = scan_existing(metainfo.info(), &path).unwrap();
+        assert_eq!(bitfield.iter_set().collect::<Vec<_>>(), &[0, 1, 2]);
+    }
+
+    #[test]
+    fn test_piece_verifier() {
+        let abc_sha1 = hex::decode("a9993e364706816aba3e25717850c26c9cd0d89d").unwrap();
+        let abc_sha1 = abc_sha1.try_into().unwrap();
+        assert!(PieceVerifier::verify(b"abc", &abc_sha1));
+        assert!(!PieceVerifier::verify(b"abd", &abc_sha1));
+
+        let mut verifier = PieceVerifier::new();
+        verifier.update(b"a");
+        verifier.update(b"bc");
+        assert!(verifier.finalize_matches(&abc_sha1));
+    }
+
+    #[test]
+    fn test_verify_against() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"abc").unwrap();
+
+        assert!(verify_against(file.path(), &Checksum::Size(3)).unwrap());
+        assert!(!verify_against(file.path(), &Checksum::Size(4)).unwrap());
+        let abc_sha256 = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let hash = hex::decode(abc_sha256).unwrap().try_into().unwrap();
+        assert!(verify_against(file.path(), &Checksum::Sha256(hash)).unwrap());
+        assert!(!verify_against(file.path(), &Checksum::Sha256([0; 32])).unwrap());
+    }
+}